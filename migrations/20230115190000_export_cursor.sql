@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS export_cursor(
+  table_name text not null,
+  sink text not null,
+  last_id bigint not null default 0,
+  PRIMARY KEY (table_name, sink)
+);
+
+-- Start the cursors after the data points already exported
+INSERT INTO export_cursor(table_name, sink, last_id)
+SELECT 'data_point_generic', 'victoria', coalesce(max(id), 0) FROM data_point_generic WHERE exported = true;
+INSERT INTO export_cursor(table_name, sink, last_id)
+SELECT 'data_point_heart_rate', 'victoria', coalesce(max(id), 0) FROM data_point_heart_rate WHERE exported = true;
+INSERT INTO export_cursor(table_name, sink, last_id)
+SELECT 'data_point_sleep_analysis', 'victoria', coalesce(max(id), 0) FROM data_point_sleep_analysis WHERE exported = true;
+
+DROP INDEX IF EXISTS data_point_generic_exported_idx;
+DROP INDEX IF EXISTS data_point_heart_rate_exported_idx;
+DROP INDEX IF EXISTS data_point_sleep_analysis_exported_idx;
+
+ALTER TABLE data_point_generic DROP COLUMN exported;
+ALTER TABLE data_point_heart_rate DROP COLUMN exported;
+ALTER TABLE data_point_sleep_analysis DROP COLUMN exported;
@@ -0,0 +1,10 @@
+-- Transactions inserting data points, with the last id of every data point table when they started.
+-- Their rows only become visible when they commit, possibly after rows with a higher id,
+-- so the exporter doesn't move its cursors past these ids until the transactions are done.
+
+CREATE TABLE IF NOT EXISTS ingestion_in_progress(
+  xid bigint not null,
+  table_name text not null,
+  last_id bigint not null,
+  PRIMARY KEY (xid, table_name)
+);
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS upload(
+  id bigint primary key generated always as identity,
+  status text not null default 'pending',
+  body bytea not null,
+  created_at timestamptz not null default now(),
+  started_at timestamptz,
+  finished_at timestamptz,
+  nb_metrics bigint,
+  nb_data_points bigint,
+  error text,
+  CHECK (status IN ('pending', 'processing', 'done', 'failed'))
+);
+CREATE INDEX IF NOT EXISTS upload_pending_idx ON upload(id) WHERE status = 'pending';
@@ -0,0 +1,27 @@
+CREATE TABLE IF NOT EXISTS raw_upload(
+  id bigint primary key generated always as identity,
+  received_at timestamptz not null default now(),
+  headers jsonb not null default '{}',
+  body bytea not null,
+  body_encoding text not null default 'gzip',
+  parse_outcome text,
+  parse_error text,
+  CHECK (body_encoding IN ('identity', 'gzip')),
+  CHECK (parse_outcome IN ('ok', 'error'))
+);
+CREATE INDEX IF NOT EXISTS raw_upload_received_at_idx ON raw_upload(received_at);
+
+-- Move the bodies of the existing uploads to the archive, keeping the same ids
+INSERT INTO raw_upload(id, received_at, body, body_encoding, parse_outcome, parse_error)
+OVERRIDING SYSTEM VALUE
+SELECT
+  id, created_at, body, 'identity',
+  CASE status WHEN 'done' THEN 'ok' WHEN 'failed' THEN 'error' END,
+  error
+FROM upload;
+SELECT setval(pg_get_serial_sequence('raw_upload', 'id'), coalesce(max(id), 0) + 1, false) FROM raw_upload;
+
+ALTER TABLE upload ADD COLUMN raw_upload_id bigint REFERENCES raw_upload(id);
+UPDATE upload SET raw_upload_id = id;
+ALTER TABLE upload ALTER COLUMN raw_upload_id SET NOT NULL;
+ALTER TABLE upload DROP COLUMN body;
@@ -0,0 +1,54 @@
+-- The unique constraints included the id so they never prevented duplicates,
+-- only one data point is kept per metric and date.
+--
+-- The existing duplicates are moved to separate tables instead of being deleted,
+-- they may have been uploaded by different devices.
+
+CREATE TABLE IF NOT EXISTS data_point_generic_duplicate (LIKE data_point_generic);
+WITH moved AS (
+  DELETE FROM data_point_generic a USING data_point_generic b
+  WHERE a.metric_id = b.metric_id AND a.date = b.date AND a.id > b.id
+  RETURNING a.*
+)
+INSERT INTO data_point_generic_duplicate SELECT * FROM moved;
+ALTER TABLE data_point_generic DROP CONSTRAINT IF EXISTS data_point_generic_id_metric_id_date_key;
+ALTER TABLE data_point_generic ADD UNIQUE (metric_id, date);
+
+CREATE TABLE IF NOT EXISTS data_point_heart_rate_duplicate (LIKE data_point_heart_rate);
+WITH moved AS (
+  DELETE FROM data_point_heart_rate a USING data_point_heart_rate b
+  WHERE a.metric_id = b.metric_id AND a.date = b.date AND a.id > b.id
+  RETURNING a.*
+)
+INSERT INTO data_point_heart_rate_duplicate SELECT * FROM moved;
+ALTER TABLE data_point_heart_rate DROP CONSTRAINT IF EXISTS data_point_heart_rate_id_metric_id_date_key;
+ALTER TABLE data_point_heart_rate ADD UNIQUE (metric_id, date);
+
+CREATE TABLE IF NOT EXISTS data_point_sleep_analysis_duplicate (LIKE data_point_sleep_analysis);
+WITH moved AS (
+  DELETE FROM data_point_sleep_analysis a USING data_point_sleep_analysis b
+  WHERE a.metric_id = b.metric_id AND a.date = b.date AND a.id > b.id
+  RETURNING a.*
+)
+INSERT INTO data_point_sleep_analysis_duplicate SELECT * FROM moved;
+ALTER TABLE data_point_sleep_analysis DROP CONSTRAINT IF EXISTS data_point_sleep_analysis_id_metric_id_date_key;
+ALTER TABLE data_point_sleep_analysis ADD UNIQUE (metric_id, date);
+
+-- Data points that couldn't be parsed, kept for inspection
+
+CREATE TABLE IF NOT EXISTS rejected_data_point(
+  id bigint primary key generated always as identity,
+  upload_id bigint,
+  metric_name text not null,
+  raw jsonb not null,
+  reason text not null,
+  created_at timestamptz not null default now(),
+  FOREIGN KEY (upload_id) REFERENCES upload(id)
+);
+CREATE INDEX IF NOT EXISTS rejected_data_point_upload_id_idx ON rejected_data_point(upload_id);
+
+ALTER TABLE upload ADD COLUMN strict boolean not null default false;
+ALTER TABLE upload ADD COLUMN summary jsonb;
+
+ALTER TABLE raw_upload DROP CONSTRAINT IF EXISTS raw_upload_parse_outcome_check;
+ALTER TABLE raw_upload ADD CHECK (parse_outcome IN ('ok', 'partial', 'error'));
@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS api_token(
+  id bigint primary key generated always as identity,
+  label text not null,
+  token_hash bytea not null,
+  scopes text[] not null,
+  created_at timestamptz not null default now(),
+  last_used_at timestamptz,
+  revoked_at timestamptz,
+  UNIQUE (token_hash),
+  CHECK (scopes <@ ARRAY['ingest', 'read', 'admin'])
+);
+
+ALTER TABLE raw_upload ADD COLUMN api_token_id bigint REFERENCES api_token(id);
+
+ALTER TABLE data_point_generic ADD COLUMN api_token_id bigint REFERENCES api_token(id);
+ALTER TABLE data_point_heart_rate ADD COLUMN api_token_id bigint REFERENCES api_token(id);
+ALTER TABLE data_point_sleep_analysis ADD COLUMN api_token_id bigint REFERENCES api_token(id);
+
+CREATE INDEX IF NOT EXISTS data_point_generic_api_token_id_idx ON data_point_generic(api_token_id);
+CREATE INDEX IF NOT EXISTS data_point_heart_rate_api_token_id_idx ON data_point_heart_rate(api_token_id);
+CREATE INDEX IF NOT EXISTS data_point_sleep_analysis_api_token_id_idx ON data_point_sleep_analysis(api_token_id);
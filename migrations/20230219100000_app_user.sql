@@ -0,0 +1,48 @@
+CREATE TABLE IF NOT EXISTS app_user(
+  id bigint primary key generated always as identity,
+  name text not null,
+  created_at timestamptz not null default now(),
+  UNIQUE (name),
+  -- The name is used as a tag value by the exporter
+  CHECK (name ~ '^[A-Za-z0-9_.-]{1,64}$')
+);
+
+-- Everything that exists so far belongs to a single user
+INSERT INTO app_user(name) VALUES('default');
+
+ALTER TABLE api_token ADD COLUMN user_id bigint REFERENCES app_user(id);
+UPDATE api_token SET user_id = (SELECT id FROM app_user WHERE name = 'default');
+ALTER TABLE api_token ALTER COLUMN user_id SET NOT NULL;
+
+ALTER TABLE raw_upload ADD COLUMN user_id bigint REFERENCES app_user(id);
+UPDATE raw_upload SET user_id = (SELECT id FROM app_user WHERE name = 'default');
+ALTER TABLE raw_upload ALTER COLUMN user_id SET NOT NULL;
+
+-- Metrics are unique per user
+
+ALTER TABLE metric ADD COLUMN user_id bigint REFERENCES app_user(id);
+UPDATE metric SET user_id = (SELECT id FROM app_user WHERE name = 'default');
+ALTER TABLE metric ALTER COLUMN user_id SET NOT NULL;
+ALTER TABLE metric DROP CONSTRAINT IF EXISTS metric_name_key;
+ALTER TABLE metric ADD UNIQUE (user_id, name);
+ALTER TABLE metric ADD UNIQUE (id, user_id);
+
+-- The data points always belong to the user of their metric
+
+ALTER TABLE data_point_generic ADD COLUMN user_id bigint;
+UPDATE data_point_generic d SET user_id = m.user_id FROM metric m WHERE m.id = d.metric_id;
+ALTER TABLE data_point_generic ALTER COLUMN user_id SET NOT NULL;
+ALTER TABLE data_point_generic ADD FOREIGN KEY (metric_id, user_id) REFERENCES metric(id, user_id);
+CREATE INDEX IF NOT EXISTS data_point_generic_user_id_idx ON data_point_generic(user_id);
+
+ALTER TABLE data_point_heart_rate ADD COLUMN user_id bigint;
+UPDATE data_point_heart_rate d SET user_id = m.user_id FROM metric m WHERE m.id = d.metric_id;
+ALTER TABLE data_point_heart_rate ALTER COLUMN user_id SET NOT NULL;
+ALTER TABLE data_point_heart_rate ADD FOREIGN KEY (metric_id, user_id) REFERENCES metric(id, user_id);
+CREATE INDEX IF NOT EXISTS data_point_heart_rate_user_id_idx ON data_point_heart_rate(user_id);
+
+ALTER TABLE data_point_sleep_analysis ADD COLUMN user_id bigint;
+UPDATE data_point_sleep_analysis d SET user_id = m.user_id FROM metric m WHERE m.id = d.metric_id;
+ALTER TABLE data_point_sleep_analysis ALTER COLUMN user_id SET NOT NULL;
+ALTER TABLE data_point_sleep_analysis ADD FOREIGN KEY (metric_id, user_id) REFERENCES metric(id, user_id);
+CREATE INDEX IF NOT EXISTS data_point_sleep_analysis_user_id_idx ON data_point_sleep_analysis(user_id);
@@ -0,0 +1,14 @@
+-- The bodies of the raw uploads are stored in chunks, read back as they're parsed,
+-- so a body is never held in memory in full.
+
+CREATE TABLE IF NOT EXISTS raw_upload_chunk(
+  raw_upload_id bigint not null REFERENCES raw_upload(id) ON DELETE CASCADE,
+  chunk_index integer not null,
+  data bytea not null,
+  PRIMARY KEY (raw_upload_id, chunk_index)
+);
+
+INSERT INTO raw_upload_chunk(raw_upload_id, chunk_index, data)
+SELECT id, 0, body FROM raw_upload;
+
+ALTER TABLE raw_upload DROP COLUMN body;
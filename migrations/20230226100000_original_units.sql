@@ -0,0 +1,5 @@
+-- The data points are converted to the unit of their metric when ingested,
+-- keep the unit they were uploaded with. Unknown for the data points ingested before.
+ALTER TABLE data_point_generic ADD COLUMN original_units text;
+ALTER TABLE data_point_heart_rate ADD COLUMN original_units text;
+ALTER TABLE data_point_sleep_analysis ADD COLUMN original_units text;
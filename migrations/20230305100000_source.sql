@@ -0,0 +1,30 @@
+-- The device or app that recorded the data points.
+-- The data points without a source reference the source with an empty name.
+
+CREATE TABLE IF NOT EXISTS source(
+  id bigint primary key generated always as identity,
+  name text not null,
+  UNIQUE (name)
+);
+
+INSERT INTO source(name) VALUES('');
+
+-- A metric can have a data point per source at the same date, for example
+-- the heart rate measured by a watch and the one entered manually.
+
+ALTER TABLE data_point_generic ADD COLUMN source_id bigint REFERENCES source(id);
+UPDATE data_point_generic SET source_id = (SELECT id FROM source WHERE name = '');
+ALTER TABLE data_point_generic ALTER COLUMN source_id SET NOT NULL;
+ALTER TABLE data_point_generic DROP CONSTRAINT IF EXISTS data_point_generic_metric_id_date_key;
+ALTER TABLE data_point_generic ADD UNIQUE (metric_id, date, source_id);
+
+ALTER TABLE data_point_heart_rate ADD COLUMN source_id bigint REFERENCES source(id);
+UPDATE data_point_heart_rate SET source_id = (SELECT id FROM source WHERE name = '');
+ALTER TABLE data_point_heart_rate ALTER COLUMN source_id SET NOT NULL;
+ALTER TABLE data_point_heart_rate DROP CONSTRAINT IF EXISTS data_point_heart_rate_metric_id_date_key;
+ALTER TABLE data_point_heart_rate ADD UNIQUE (metric_id, date, source_id);
+
+-- The sleep analysis has its own source
+
+ALTER TABLE data_point_sleep_analysis DROP CONSTRAINT IF EXISTS data_point_sleep_analysis_metric_id_date_key;
+ALTER TABLE data_point_sleep_analysis ADD UNIQUE (metric_id, date, sleep_source);
@@ -0,0 +1,21 @@
+-- Priority of the sources of a metric, synced from the configuration at startup.
+-- A source matches a pattern if its name contains it, the lowest rank wins.
+
+CREATE TABLE IF NOT EXISTS source_priority(
+  metric_name text not null,
+  pattern text not null,
+  rank integer not null,
+  PRIMARY KEY (metric_name, pattern),
+  CHECK (pattern <> '')
+);
+
+-- Rank of a source for a metric, the sources matching no pattern come last.
+CREATE OR REPLACE FUNCTION source_rank(metric_name text, source_name text) RETURNS integer AS $$
+  SELECT COALESCE(
+    (
+      SELECT min(p.rank) FROM source_priority p
+      WHERE p.metric_name = $1 AND strpos($2, p.pattern) > 0
+    ),
+    2147483647
+  )
+$$ LANGUAGE sql STABLE;
@@ -0,0 +1,10 @@
+-- When the data points were inserted. The exporter holds back the recent data points
+-- of the metrics with a source priority, until the other sources had time to sync.
+
+ALTER TABLE data_point_generic ADD COLUMN IF NOT EXISTS created_at timestamptz not null default now();
+ALTER TABLE data_point_heart_rate ADD COLUMN IF NOT EXISTS created_at timestamptz not null default now();
+ALTER TABLE data_point_sleep_analysis ADD COLUMN IF NOT EXISTS created_at timestamptz not null default now();
+
+CREATE INDEX IF NOT EXISTS data_point_generic_created_at_idx ON data_point_generic(created_at);
+CREATE INDEX IF NOT EXISTS data_point_heart_rate_created_at_idx ON data_point_heart_rate(created_at);
+CREATE INDEX IF NOT EXISTS data_point_sleep_analysis_created_at_idx ON data_point_sleep_analysis(created_at);
@@ -0,0 +1,12 @@
+-- Keys sent with the uploads in the Idempotency-Key header, so that a retried upload
+-- returns the original upload instead of being ingested again.
+
+CREATE TABLE IF NOT EXISTS idempotency_key(
+  user_id bigint not null REFERENCES app_user(id),
+  key text not null,
+  body_hash bytea not null,
+  upload_id bigint not null REFERENCES upload(id),
+  created_at timestamptz not null default now(),
+  PRIMARY KEY (user_id, key)
+);
+CREATE INDEX IF NOT EXISTS idempotency_key_created_at_idx ON idempotency_key(created_at);
@@ -0,0 +1,58 @@
+-- Format of the archived bodies, the uploads are either Health Auto Export JSON or FIT files
+
+ALTER TABLE raw_upload ADD COLUMN format text not null default 'json';
+ALTER TABLE raw_upload ADD CONSTRAINT raw_upload_format_check CHECK (format IN ('json', 'fit'));
+
+-- Workouts recorded by a device, with their laps and the records sampled during the workout.
+-- A workout is identified by its start time per user and source, importing it again does nothing.
+
+CREATE TABLE IF NOT EXISTS workout(
+  id bigint primary key generated always as identity,
+  user_id bigint not null REFERENCES app_user(id),
+  api_token_id bigint REFERENCES api_token(id),
+  source_id bigint not null REFERENCES source(id),
+  sport text not null,
+  sub_sport text,
+  start_time timestamptz not null,
+  end_time timestamptz not null,
+  -- In seconds, the elapsed time includes the pauses
+  elapsed_time double precision not null,
+  timer_time double precision,
+  -- In meters
+  distance double precision,
+  -- In kcal
+  calories double precision,
+  avg_heart_rate double precision,
+  max_heart_rate double precision,
+  created_at timestamptz not null default now(),
+  UNIQUE (user_id, source_id, start_time)
+);
+
+CREATE TABLE IF NOT EXISTS workout_lap(
+  workout_id bigint not null REFERENCES workout(id) ON DELETE CASCADE,
+  lap_index integer not null,
+  start_time timestamptz not null,
+  end_time timestamptz not null,
+  elapsed_time double precision not null,
+  timer_time double precision,
+  distance double precision,
+  calories double precision,
+  avg_heart_rate double precision,
+  max_heart_rate double precision,
+  PRIMARY KEY (workout_id, lap_index)
+);
+
+-- The positions are in degrees, the altitude in meters and the speed in m/s
+CREATE TABLE IF NOT EXISTS workout_record(
+  workout_id bigint not null REFERENCES workout(id) ON DELETE CASCADE,
+  date timestamptz not null,
+  heart_rate double precision,
+  latitude double precision,
+  longitude double precision,
+  altitude double precision,
+  distance double precision,
+  speed double precision,
+  cadence double precision,
+  power double precision,
+  PRIMARY KEY (workout_id, date)
+);
@@ -0,0 +1,4 @@
+-- GPX and TCX files are archived too, the points of their tracks are stored as workout records
+
+ALTER TABLE raw_upload DROP CONSTRAINT IF EXISTS raw_upload_format_check;
+ALTER TABLE raw_upload ADD CONSTRAINT raw_upload_format_check CHECK (format IN ('json', 'fit', 'gpx', 'tcx'));
@@ -0,0 +1,1181 @@
+use crate::health_data::{
+    timestamp, GenericDataPoint, HeartRateDataPoint, MetricDataPoint, SleepAnalysisDataPoint,
+};
+use crate::units;
+use crate::workout;
+use quick_xml::events::{BytesStart, Event};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::Path;
+use time::OffsetDateTime;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error("invalid XML: {0}")]
+    Xml(#[from] quick_xml::Error),
+    #[error("invalid zip archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("no export.xml in the archive")]
+    NoExport,
+    #[error("the export is truncated")]
+    Truncated,
+    #[error("{0}")]
+    Aborted(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A `Record` element of an export, with the attributes used to make a data point.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub source_name: String,
+    pub unit: String,
+    pub start_date: String,
+    pub end_date: String,
+    pub value: String,
+}
+
+/// A `Workout` element of an export, with the attributes used to make a workout.
+///
+/// The recent exports have `WorkoutStatistics` elements instead of the total attributes,
+/// the totals are then taken from the statistics of the workout.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Workout {
+    pub workout_activity_type: String,
+    pub source_name: String,
+    pub duration: String,
+    pub duration_unit: String,
+    pub total_distance: String,
+    pub total_distance_unit: String,
+    pub total_energy_burned: String,
+    pub total_energy_burned_unit: String,
+    pub start_date: String,
+    pub end_date: String,
+}
+
+/// An `ActivitySummary` element of an export, the daily totals of the activity rings.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivitySummary {
+    pub date_components: String,
+    pub active_energy_burned: String,
+    pub active_energy_burned_unit: String,
+    pub apple_exercise_time: String,
+    pub apple_stand_hours: String,
+    /// The date of the export, the summaries have no time zone so they're in the one of the export.
+    #[serde(skip)]
+    pub export_date: String,
+}
+
+/// An element of an export that's imported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element {
+    Record(Record),
+    Workout(Workout),
+    ActivitySummary(ActivitySummary),
+}
+
+/// Counts of the elements of an export.
+#[derive(Debug, Default, PartialEq)]
+pub struct ExportStats {
+    pub nb_records: u64,
+    pub nb_workouts: u64,
+    pub nb_activity_summaries: u64,
+}
+
+/// Returns true if the file looks like an export of the Health app, by its extension.
+pub fn is_export(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(OsStr::to_str),
+        Some("zip" | "xml")
+    )
+}
+
+/// Reads the elements of an export, calling `f` with batches of at most `batch_size` elements.
+///
+/// The export is either the zip archive made by the Health app or the `export.xml` file in it.
+/// The archive is read as it's decompressed, it's never extracted.
+pub fn read_export_file<F>(path: &Path, batch_size: usize, f: F) -> Result<ExportStats>
+where
+    F: FnMut(Vec<Element>) -> std::result::Result<(), String>,
+{
+    let file = fs::File::open(path)?;
+
+    if path.extension() != Some(OsStr::new("zip")) {
+        return read_export(io::BufReader::new(file), batch_size, f);
+    }
+
+    let mut archive = zip::ZipArchive::new(file)?;
+    let name = archive
+        .file_names()
+        .find(|name| *name == "export.xml" || name.ends_with("/export.xml"))
+        .ok_or(Error::NoExport)?
+        .to_owned();
+    let entry = archive.by_name(&name)?;
+
+    read_export(io::BufReader::new(entry), batch_size, f)
+}
+
+/// Reads the elements of an `export.xml` document incrementally, like [`read_export_file`].
+pub fn read_export<R, F>(reader: R, batch_size: usize, mut f: F) -> Result<ExportStats>
+where
+    R: io::BufRead,
+    F: FnMut(Vec<Element>) -> std::result::Result<(), String>,
+{
+    let batch_size = batch_size.max(1);
+
+    let mut reader = quick_xml::Reader::from_reader(reader);
+    let mut buf = Vec::new();
+
+    let mut stats = ExportStats::default();
+    let mut elements = Vec::with_capacity(batch_size);
+    let mut export_date = String::new();
+    // A workout is only complete at its end, after its statistics
+    let mut workout: Option<Workout> = None;
+    let mut complete = false;
+
+    let mut push = |elements: &mut Vec<Element>, element: Element| -> Result<()> {
+        match element {
+            Element::Record(_) => stats.nb_records += 1,
+            Element::Workout(_) => stats.nb_workouts += 1,
+            Element::ActivitySummary(_) => stats.nb_activity_summaries += 1,
+        }
+        elements.push(element);
+
+        if elements.len() >= batch_size {
+            let batch = std::mem::replace(elements, Vec::with_capacity(batch_size));
+            f(batch).map_err(Error::Aborted)?;
+        }
+
+        Ok(())
+    };
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(element) => match element.name().as_ref() {
+                b"Record" => push(&mut elements, Element::Record(parse_record(&element)?))?,
+                b"Workout" => workout = Some(parse_workout(&element)?),
+                b"WorkoutStatistics" => {
+                    if let Some(workout) = &mut workout {
+                        add_workout_statistics(workout, &element)?;
+                    }
+                }
+                _ => {}
+            },
+            Event::Empty(element) => match element.name().as_ref() {
+                b"Record" => push(&mut elements, Element::Record(parse_record(&element)?))?,
+                b"Workout" => push(&mut elements, Element::Workout(parse_workout(&element)?))?,
+                b"WorkoutStatistics" => {
+                    if let Some(workout) = &mut workout {
+                        add_workout_statistics(workout, &element)?;
+                    }
+                }
+                b"ActivitySummary" => {
+                    let summary = parse_activity_summary(&element, &export_date)?;
+                    push(&mut elements, Element::ActivitySummary(summary))?;
+                }
+                b"ExportDate" => {
+                    for attribute in attributes(&element) {
+                        if let (b"value", value) = attribute? {
+                            export_date = value;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            Event::End(element) => match element.name().as_ref() {
+                b"Workout" => {
+                    if let Some(workout) = workout.take() {
+                        push(&mut elements, Element::Workout(workout))?;
+                    }
+                }
+                b"HealthData" => complete = true,
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    // Nothing is imported from a partial export, which is most likely an interrupted copy
+    if !complete {
+        return Err(Error::Truncated);
+    }
+
+    if !elements.is_empty() {
+        f(elements).map_err(Error::Aborted)?;
+    }
+
+    Ok(stats)
+}
+
+/// Returns the attributes of an element with their unescaped values.
+fn attributes<'a>(
+    element: &'a BytesStart,
+) -> impl Iterator<Item = Result<(&'a [u8], String)>> + 'a {
+    element.attributes().map(|attribute| {
+        let attribute = attribute.map_err(quick_xml::Error::from)?;
+        let value = attribute.unescape_value()?.into_owned();
+
+        Ok((attribute.key.into_inner(), value))
+    })
+}
+
+fn parse_record(element: &BytesStart) -> Result<Record> {
+    let mut record = Record::default();
+
+    for attribute in attributes(element) {
+        let (key, value) = attribute?;
+        let field = match key {
+            b"type" => &mut record.type_,
+            b"sourceName" => &mut record.source_name,
+            b"unit" => &mut record.unit,
+            b"startDate" => &mut record.start_date,
+            b"endDate" => &mut record.end_date,
+            b"value" => &mut record.value,
+            _ => continue,
+        };
+        *field = value;
+    }
+
+    Ok(record)
+}
+
+fn parse_workout(element: &BytesStart) -> Result<Workout> {
+    let mut workout = Workout::default();
+
+    for attribute in attributes(element) {
+        let (key, value) = attribute?;
+        let field = match key {
+            b"workoutActivityType" => &mut workout.workout_activity_type,
+            b"sourceName" => &mut workout.source_name,
+            b"duration" => &mut workout.duration,
+            b"durationUnit" => &mut workout.duration_unit,
+            b"totalDistance" => &mut workout.total_distance,
+            b"totalDistanceUnit" => &mut workout.total_distance_unit,
+            b"totalEnergyBurned" => &mut workout.total_energy_burned,
+            b"totalEnergyBurnedUnit" => &mut workout.total_energy_burned_unit,
+            b"startDate" => &mut workout.start_date,
+            b"endDate" => &mut workout.end_date,
+            _ => continue,
+        };
+        *field = value;
+    }
+
+    Ok(workout)
+}
+
+/// Takes the total distance or energy of a workout from a `WorkoutStatistics` element,
+/// unless the workout already has it.
+fn add_workout_statistics(workout: &mut Workout, element: &BytesStart) -> Result<()> {
+    let (mut type_, mut sum, mut unit) = (String::new(), String::new(), String::new());
+    for attribute in attributes(element) {
+        match attribute? {
+            (b"type", value) => type_ = value,
+            (b"sum", value) => sum = value,
+            (b"unit", value) => unit = value,
+            _ => {}
+        }
+    }
+
+    let (total, total_unit) = if type_.starts_with("HKQuantityTypeIdentifierDistance") {
+        (
+            &mut workout.total_distance,
+            &mut workout.total_distance_unit,
+        )
+    } else if type_ == "HKQuantityTypeIdentifierActiveEnergyBurned" {
+        (
+            &mut workout.total_energy_burned,
+            &mut workout.total_energy_burned_unit,
+        )
+    } else {
+        return Ok(());
+    };
+    if total.is_empty() {
+        *total = sum;
+        *total_unit = unit;
+    }
+
+    Ok(())
+}
+
+fn parse_activity_summary(element: &BytesStart, export_date: &str) -> Result<ActivitySummary> {
+    let mut summary = ActivitySummary {
+        export_date: export_date.to_owned(),
+        ..Default::default()
+    };
+
+    for attribute in attributes(element) {
+        let (key, value) = attribute?;
+        let field = match key {
+            b"dateComponents" => &mut summary.date_components,
+            b"activeEnergyBurned" => &mut summary.active_energy_burned,
+            b"activeEnergyBurnedUnit" => &mut summary.active_energy_burned_unit,
+            b"appleExerciseTime" => &mut summary.apple_exercise_time,
+            b"appleStandHours" => &mut summary.apple_stand_hours,
+            _ => continue,
+        };
+        *field = value;
+    }
+
+    Ok(summary)
+}
+
+/// How the records of a HealthKit type become data points.
+#[derive(Debug, Clone, Copy)]
+enum Mapping {
+    /// A generic data point of the metric with the value of the record.
+    Quantity(&'static str),
+    /// A heart rate data point, the record is a single measure.
+    HeartRate,
+    /// A generic data point of the metric with the duration of the record in minutes.
+    Duration(&'static str),
+    /// Aggregated by night in a sleep analysis data point.
+    SleepAnalysis,
+}
+
+/// HealthKit type identifiers and the metric they're imported as, named like Health Auto Export names them.
+const TYPE_MAPPINGS: &[(&str, Mapping)] = &[
+    ("HKQuantityTypeIdentifierHeartRate", Mapping::HeartRate),
+    (
+        "HKCategoryTypeIdentifierSleepAnalysis",
+        Mapping::SleepAnalysis,
+    ),
+    (
+        "HKCategoryTypeIdentifierMindfulSession",
+        Mapping::Duration("mindful_minutes"),
+    ),
+    (
+        "HKQuantityTypeIdentifierActiveEnergyBurned",
+        Mapping::Quantity("active_energy"),
+    ),
+    (
+        "HKQuantityTypeIdentifierAppleExerciseTime",
+        Mapping::Quantity("apple_exercise_time"),
+    ),
+    (
+        "HKQuantityTypeIdentifierAppleStandTime",
+        Mapping::Quantity("apple_stand_time"),
+    ),
+    (
+        "HKQuantityTypeIdentifierBasalEnergyBurned",
+        Mapping::Quantity("basal_energy_burned"),
+    ),
+    (
+        "HKQuantityTypeIdentifierOxygenSaturation",
+        Mapping::Quantity("blood_oxygen_saturation"),
+    ),
+    (
+        "HKQuantityTypeIdentifierBodyFatPercentage",
+        Mapping::Quantity("body_fat_percentage"),
+    ),
+    (
+        "HKQuantityTypeIdentifierBodyMassIndex",
+        Mapping::Quantity("body_mass_index"),
+    ),
+    (
+        "HKQuantityTypeIdentifierDistanceCycling",
+        Mapping::Quantity("cycling_distance"),
+    ),
+    (
+        "HKQuantityTypeIdentifierDietaryWater",
+        Mapping::Quantity("dietary_water"),
+    ),
+    (
+        "HKQuantityTypeIdentifierEnvironmentalAudioExposure",
+        Mapping::Quantity("environmental_audio_exposure"),
+    ),
+    (
+        "HKQuantityTypeIdentifierFlightsClimbed",
+        Mapping::Quantity("flights_climbed"),
+    ),
+    (
+        "HKQuantityTypeIdentifierHeadphoneAudioExposure",
+        Mapping::Quantity("headphone_audio_exposure"),
+    ),
+    (
+        "HKQuantityTypeIdentifierHeartRateVariabilitySDNN",
+        Mapping::Quantity("heart_rate_variability"),
+    ),
+    (
+        "HKQuantityTypeIdentifierLeanBodyMass",
+        Mapping::Quantity("lean_body_mass"),
+    ),
+    (
+        "HKQuantityTypeIdentifierPhysicalEffort",
+        Mapping::Quantity("physical_effort"),
+    ),
+    (
+        "HKQuantityTypeIdentifierRespiratoryRate",
+        Mapping::Quantity("respiratory_rate"),
+    ),
+    (
+        "HKQuantityTypeIdentifierRestingHeartRate",
+        Mapping::Quantity("resting_heart_rate"),
+    ),
+    (
+        "HKQuantityTypeIdentifierSixMinuteWalkTestDistance",
+        Mapping::Quantity("six_minute_walking_test_distance"),
+    ),
+    (
+        "HKQuantityTypeIdentifierStairDescentSpeed",
+        Mapping::Quantity("stair_speed_down"),
+    ),
+    (
+        "HKQuantityTypeIdentifierStairAscentSpeed",
+        Mapping::Quantity("stair_speed_up"),
+    ),
+    (
+        "HKQuantityTypeIdentifierStepCount",
+        Mapping::Quantity("step_count"),
+    ),
+    (
+        "HKQuantityTypeIdentifierDistanceSwimming",
+        Mapping::Quantity("swimming_distance"),
+    ),
+    (
+        "HKQuantityTypeIdentifierTimeInDaylight",
+        Mapping::Quantity("time_in_daylight"),
+    ),
+    (
+        "HKQuantityTypeIdentifierVO2Max",
+        Mapping::Quantity("vo2_max"),
+    ),
+    (
+        "HKQuantityTypeIdentifierWalkingAsymmetryPercentage",
+        Mapping::Quantity("walking_asymmetry_percentage"),
+    ),
+    (
+        "HKQuantityTypeIdentifierWalkingDoubleSupportPercentage",
+        Mapping::Quantity("walking_double_support_percentage"),
+    ),
+    (
+        "HKQuantityTypeIdentifierWalkingHeartRateAverage",
+        Mapping::Quantity("walking_heart_rate_average"),
+    ),
+    (
+        "HKQuantityTypeIdentifierDistanceWalkingRunning",
+        Mapping::Quantity("walking_running_distance"),
+    ),
+    (
+        "HKQuantityTypeIdentifierWalkingSpeed",
+        Mapping::Quantity("walking_speed"),
+    ),
+    (
+        "HKQuantityTypeIdentifierWalkingStepLength",
+        Mapping::Quantity("walking_step_length"),
+    ),
+    (
+        "HKQuantityTypeIdentifierBodyMass",
+        Mapping::Quantity("weight_body_mass"),
+    ),
+];
+
+fn lookup_type(type_: &str) -> Option<Mapping> {
+    TYPE_MAPPINGS
+        .iter()
+        .find(|(name, _)| *name == type_)
+        .map(|(_, mapping)| *mapping)
+}
+
+/// A data point made from the records of an export.
+#[derive(Debug, PartialEq)]
+pub struct DataPoint {
+    pub metric: &'static str,
+    pub units: String,
+    /// The data point, or why it couldn't be made.
+    pub data_point: std::result::Result<MetricDataPoint, String>,
+    pub raw: Raw,
+}
+
+/// What a data point was made from.
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(untagged)]
+pub enum Raw {
+    Record(Record),
+    Night(SleepNight),
+    ActivitySummary(ActivitySummary),
+}
+
+impl Raw {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "null".to_owned())
+    }
+}
+
+/// Maps the records of an export to data points.
+#[derive(Debug, Default)]
+pub struct Mapper {
+    nights: BTreeMap<time::Date, SleepNight>,
+    unmapped_types: BTreeMap<String, u64>,
+}
+
+impl Mapper {
+    /// Returns the data point made from a record, if any.
+    ///
+    /// The records of an unknown type are skipped. The sleep records are kept to be aggregated,
+    /// their data points are returned by [`Mapper::sleep_analysis`].
+    pub fn map(&mut self, record: Record) -> Option<DataPoint> {
+        let mapping = match lookup_type(&record.type_) {
+            Some(mapping) => mapping,
+            None => {
+                *self.unmapped_types.entry(record.type_).or_default() += 1;
+                return None;
+            }
+        };
+
+        let (metric, units, data_point) = match mapping {
+            Mapping::Quantity(metric) => {
+                let (units, data_point) = match (parse_date(&record), parse_value(&record)) {
+                    (Ok(date), Ok(quantity)) => {
+                        let (units, quantity) = normalize_units(&record.unit, quantity);
+                        let data_point = MetricDataPoint::Generic(GenericDataPoint {
+                            date,
+                            quantity,
+                            source: Some(record.source_name.clone()),
+                        });
+                        (units, Ok(data_point))
+                    }
+                    (Err(err), _) | (_, Err(err)) => (record.unit.clone(), Err(err)),
+                };
+                (metric, units, data_point)
+            }
+            Mapping::HeartRate => {
+                let data_point = parse_date(&record).and_then(|date| {
+                    let value = parse_value(&record)?;
+                    Ok(MetricDataPoint::HeartRate(HeartRateDataPoint {
+                        date,
+                        min: value,
+                        max: value,
+                        avg: value,
+                        source: Some(record.source_name.clone()),
+                    }))
+                });
+                ("heart_rate", record.unit.clone(), data_point)
+            }
+            Mapping::Duration(metric) => {
+                let data_point = parse_window(&record).map(|(start, end)| {
+                    MetricDataPoint::Generic(GenericDataPoint {
+                        date: start,
+                        quantity: (end - start).as_seconds_f64() / 60.0,
+                        source: Some(record.source_name.clone()),
+                    })
+                });
+                (metric, "min".to_owned(), data_point)
+            }
+            Mapping::SleepAnalysis => match self.add_sleep_record(&record) {
+                Ok(()) => return None,
+                Err(err) => ("sleep_analysis", "hr".to_owned(), Err(err)),
+            },
+        };
+
+        Some(DataPoint {
+            metric,
+            units,
+            data_point,
+            raw: Raw::Record(record),
+        })
+    }
+
+    fn add_sleep_record(&mut self, record: &Record) -> std::result::Result<(), String> {
+        let (start, end) = parse_window(record)?;
+
+        let night = self.nights.entry(end.date()).or_default();
+        let segments = match record.value.as_str() {
+            "HKCategoryValueSleepAnalysisInBed" => &mut night.in_bed,
+            "HKCategoryValueSleepAnalysisAsleep"
+            | "HKCategoryValueSleepAnalysisAsleepUnspecified"
+            | "HKCategoryValueSleepAnalysisAsleepCore"
+            | "HKCategoryValueSleepAnalysisAsleepDeep"
+            | "HKCategoryValueSleepAnalysisAsleepREM" => &mut night.asleep,
+            "HKCategoryValueSleepAnalysisAwake" => return Ok(()),
+            value => return Err(format!("unknown sleep analysis value {:?}", value)),
+        };
+        segments.add(start, end, &record.source_name);
+
+        Ok(())
+    }
+
+    /// Returns the sleep analysis data points, one per night, and forgets the sleep records mapped so far.
+    ///
+    /// A night is identified by the day it ends. The times asleep and in bed are in hours,
+    /// the overlapping records of multiple sources are only counted once.
+    pub fn sleep_analysis(&mut self) -> Vec<DataPoint> {
+        std::mem::take(&mut self.nights)
+            .into_values()
+            .filter_map(|night| {
+                let data_point = night.to_data_point()?;
+                Some(DataPoint {
+                    metric: "sleep_analysis",
+                    units: "hr".to_owned(),
+                    data_point: Ok(MetricDataPoint::SleepAnalysis(data_point)),
+                    raw: Raw::Night(night),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the data points of the daily totals of an activity summary, dated at midnight.
+    ///
+    /// The totals are distinct metrics from the ones of the records they're made of,
+    /// so that they aren't counted twice. The totals missing from the summary are skipped.
+    pub fn map_activity_summary(&self, summary: ActivitySummary) -> Vec<DataPoint> {
+        let energy_units = match summary.active_energy_burned_unit.as_str() {
+            "" => "kcal",
+            units => units,
+        };
+        let totals = [
+            (
+                "activity_active_energy",
+                energy_units,
+                &summary.active_energy_burned,
+            ),
+            (
+                "activity_exercise_time",
+                "min",
+                &summary.apple_exercise_time,
+            ),
+            ("activity_stand_hours", "count", &summary.apple_stand_hours),
+        ];
+
+        let date = parse_summary_date(&summary);
+        totals
+            .into_iter()
+            .filter(|(_, _, value)| !value.is_empty())
+            .map(|(metric, units, value)| {
+                let data_point = date.clone().and_then(|date| {
+                    let quantity = value
+                        .parse()
+                        .map_err(|_| format!("invalid {} {:?}", metric, value))?;
+                    Ok(MetricDataPoint::Generic(GenericDataPoint {
+                        date,
+                        quantity,
+                        source: None,
+                    }))
+                });
+                DataPoint {
+                    metric,
+                    units: units.to_owned(),
+                    data_point,
+                    raw: Raw::ActivitySummary(summary.clone()),
+                }
+            })
+            .collect()
+    }
+
+    /// Number of records skipped by HealthKit type identifier.
+    pub fn unmapped_types(&self) -> &BTreeMap<String, u64> {
+        &self.unmapped_types
+    }
+}
+
+/// The sleep records of a night.
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepNight {
+    asleep: SleepSegments,
+    in_bed: SleepSegments,
+}
+
+impl SleepNight {
+    /// Without in bed records the in bed window is the sleep window and vice versa, with a duration of 0.
+    fn to_data_point(&self) -> Option<SleepAnalysisDataPoint> {
+        let (sleep_start, sleep_end) = self.asleep.window().or_else(|| self.in_bed.window())?;
+        let (in_bed_start, in_bed_end) = self.in_bed.window().unwrap_or((sleep_start, sleep_end));
+
+        let date = sleep_end
+            .date()
+            .midnight()
+            .assume_offset(sleep_end.offset());
+
+        Some(SleepAnalysisDataPoint {
+            asleep: self.asleep.hours(),
+            date,
+            sleep_source: self.asleep.sources.join("|"),
+            sleep_start,
+            sleep_end,
+            in_bed: self.in_bed.hours(),
+            in_bed_source: self.in_bed.sources.join("|"),
+            in_bed_start,
+            in_bed_end,
+        })
+    }
+}
+
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+struct SleepSegments {
+    #[serde(skip)]
+    intervals: Vec<(OffsetDateTime, OffsetDateTime)>,
+    sources: Vec<String>,
+}
+
+impl SleepSegments {
+    fn add(&mut self, start: OffsetDateTime, end: OffsetDateTime, source: &str) {
+        self.intervals.push((start, end));
+        if !self.sources.iter().any(|known| known == source) {
+            self.sources.push(source.to_owned());
+        }
+    }
+
+    fn window(&self) -> Option<(OffsetDateTime, OffsetDateTime)> {
+        let start = self.intervals.iter().map(|(start, _)| *start).min()?;
+        let end = self.intervals.iter().map(|(_, end)| *end).max()?;
+        Some((start, end))
+    }
+
+    /// Total duration of the union of the intervals, in hours.
+    fn hours(&self) -> f64 {
+        let mut intervals = self.intervals.clone();
+        intervals.sort();
+
+        let mut total = time::Duration::ZERO;
+        let mut current: Option<(OffsetDateTime, OffsetDateTime)> = None;
+        for (start, end) in intervals {
+            current = match current {
+                Some((current_start, current_end)) if start <= current_end => {
+                    Some((current_start, current_end.max(end)))
+                }
+                Some((current_start, current_end)) => {
+                    total += current_end - current_start;
+                    Some((start, end))
+                }
+                None => Some((start, end)),
+            };
+        }
+        if let Some((start, end)) = current {
+            total += end - start;
+        }
+
+        total.as_seconds_f64() / 3600.0
+    }
+}
+
+impl Workout {
+    /// Returns the workout recorded by the source of the element, or why it can't be made.
+    ///
+    /// The sport is the activity type in snake case, `HKWorkoutActivityTypeTraditionalStrengthTraining`
+    /// is `traditional_strength_training`. The totals are converted to the units of the workouts.
+    pub fn to_workout(&self) -> std::result::Result<workout::Workout, String> {
+        let start_time = timestamp::parse(&self.start_date)
+            .ok_or_else(|| format!("invalid startDate {:?}", self.start_date))?;
+        let end_time = timestamp::parse(&self.end_date)
+            .ok_or_else(|| format!("invalid endDate {:?}", self.end_date))?;
+
+        let type_ = &self.workout_activity_type;
+        let sport = type_.strip_prefix("HKWorkoutActivityType").unwrap_or(type_);
+        if sport.is_empty() {
+            return Err(format!("invalid workoutActivityType {:?}", type_));
+        }
+
+        Ok(workout::Workout {
+            sport: snake_case(sport),
+            sub_sport: None,
+            start_time,
+            end_time,
+            elapsed_time: (end_time - start_time).as_seconds_f64(),
+            timer_time: parse_total("duration", &self.duration, &self.duration_unit, "s")?,
+            distance: parse_total(
+                "totalDistance",
+                &self.total_distance,
+                &self.total_distance_unit,
+                "m",
+            )?,
+            calories: parse_total(
+                "totalEnergyBurned",
+                &self.total_energy_burned,
+                &self.total_energy_burned_unit,
+                "kcal",
+            )?,
+            avg_heart_rate: None,
+            max_heart_rate: None,
+            laps: Vec::new(),
+            records: Vec::new(),
+        })
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake_case = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake_case.push('_');
+        }
+        snake_case.extend(c.to_lowercase());
+    }
+
+    snake_case
+}
+
+/// Parses a total of a workout converted to `to`, `None` if the workout doesn't have it.
+fn parse_total(
+    name: &str,
+    value: &str,
+    units: &str,
+    to: &str,
+) -> std::result::Result<Option<f64>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid {} {:?}", name, value))?;
+    let conversion = units::Conversion::new(units, to)
+        .ok_or_else(|| format!("unknown {} unit {:?}", name, units))?;
+
+    Ok(Some(conversion.apply(value)))
+}
+
+/// Midnight of the day of a summary, in the time zone of the export or UTC without export date.
+fn parse_summary_date(summary: &ActivitySummary) -> std::result::Result<OffsetDateTime, String> {
+    let format = time::macros::format_description!("[year]-[month]-[day]");
+    let date = time::Date::parse(&summary.date_components, &format)
+        .map_err(|_| format!("invalid dateComponents {:?}", summary.date_components))?;
+    let offset = timestamp::parse(&summary.export_date)
+        .map(|export_date| export_date.offset())
+        .unwrap_or(time::UtcOffset::UTC);
+
+    Ok(date.midnight().assume_offset(offset))
+}
+
+/// HealthKit stores the percentages as fractions, Health Auto Export as percentages.
+fn normalize_units(units: &str, value: f64) -> (String, f64) {
+    match units {
+        "%" => (units.to_owned(), value * 100.0),
+        _ => (units.to_owned(), value),
+    }
+}
+
+fn parse_date(record: &Record) -> std::result::Result<OffsetDateTime, String> {
+    timestamp::parse(&record.start_date)
+        .ok_or_else(|| format!("invalid startDate {:?}", record.start_date))
+}
+
+fn parse_window(record: &Record) -> std::result::Result<(OffsetDateTime, OffsetDateTime), String> {
+    let start = parse_date(record)?;
+    let end = timestamp::parse(&record.end_date)
+        .ok_or_else(|| format!("invalid endDate {:?}", record.end_date))?;
+
+    Ok((start, end))
+}
+
+fn parse_value(record: &Record) -> std::result::Result<f64, String> {
+    record
+        .value
+        .parse()
+        .map_err(|_| format!("invalid value {:?}", record.value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use time::macros::datetime;
+
+    const EXPORT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE HealthData [
+<!ELEMENT HealthData (ExportDate,Me,(Record|Workout|ActivitySummary)*)>
+]>
+<HealthData locale="fr_FR">
+ <ExportDate value="2023-04-02 10:00:00 +0200"/>
+ <Me HKCharacteristicTypeIdentifierBiologicalSex="HKBiologicalSexMale"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="iPhone de Test" unit="count" creationDate="2023-04-01 10:05:00 +0200" startDate="2023-04-01 10:00:00 +0200" endDate="2023-04-01 10:05:00 +0200" value="120"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Apple Watch de Test" unit="count/min" startDate="2023-04-01 10:01:00 +0200" endDate="2023-04-01 10:01:00 +0200" value="72">
+  <MetadataEntry key="HKMetadataKeyHeartRateMotionContext" value="0"/>
+ </Record>
+ <Record type="HKQuantityTypeIdentifierOxygenSaturation" sourceName="Apple Watch de Test" unit="%" startDate="2023-04-01 10:02:00 +0200" endDate="2023-04-01 10:02:00 +0200" value="0.97"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" sourceName="Balance &amp; Co" unit="kg" startDate="2023-04-01 08:00:00 +0200" endDate="2023-04-01 08:00:00 +0200" value="lots"/>
+ <Record type="HKQuantityTypeIdentifierNumberOfTimesFallen" sourceName="Apple Watch de Test" unit="count" startDate="2023-04-01 12:00:00 +0200" endDate="2023-04-01 12:00:00 +0200" value="1"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="iPhone de Test" startDate="2023-03-31 23:00:00 +0200" endDate="2023-04-01 07:00:00 +0200" value="HKCategoryValueSleepAnalysisInBed"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Apple Watch de Test" startDate="2023-03-31 23:30:00 +0200" endDate="2023-04-01 03:00:00 +0200" value="HKCategoryValueSleepAnalysisAsleepCore"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Apple Watch de Test" startDate="2023-04-01 03:00:00 +0200" endDate="2023-04-01 03:15:00 +0200" value="HKCategoryValueSleepAnalysisAwake"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Apple Watch de Test" startDate="2023-04-01 03:15:00 +0200" endDate="2023-04-01 06:30:00 +0200" value="HKCategoryValueSleepAnalysisAsleepREM"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="AutoSleep" startDate="2023-04-01 06:00:00 +0200" endDate="2023-04-01 06:45:00 +0200" value="HKCategoryValueSleepAnalysisAsleep"/>
+ <Workout workoutActivityType="HKWorkoutActivityTypeTraditionalStrengthTraining" duration="45" durationUnit="min" totalEnergyBurned="180" totalEnergyBurnedUnit="Cal" sourceName="Apple Watch de Test" startDate="2023-04-01 08:00:00 +0200" endDate="2023-04-01 08:46:00 +0200"/>
+ <Workout workoutActivityType="HKWorkoutActivityTypeRunning" duration="0.5" durationUnit="hr" sourceName="Apple Watch de Test" startDate="2023-04-01 18:00:00 +0200" endDate="2023-04-01 18:32:00 +0200">
+  <MetadataEntry key="HKIndoorWorkout" value="0"/>
+  <WorkoutStatistics type="HKQuantityTypeIdentifierDistanceWalkingRunning" startDate="2023-04-01 18:00:00 +0200" endDate="2023-04-01 18:32:00 +0200" sum="5.2" unit="km"/>
+  <WorkoutStatistics type="HKQuantityTypeIdentifierActiveEnergyBurned" startDate="2023-04-01 18:00:00 +0200" endDate="2023-04-01 18:32:00 +0200" sum="350" unit="kcal"/>
+  <WorkoutStatistics type="HKQuantityTypeIdentifierHeartRate" startDate="2023-04-01 18:00:00 +0200" endDate="2023-04-01 18:32:00 +0200" average="150" unit="count/min"/>
+ </Workout>
+ <ActivitySummary dateComponents="2023-04-01" activeEnergyBurned="500" activeEnergyBurnedGoal="600" activeEnergyBurnedUnit="kcal" appleExerciseTime="35" appleExerciseTimeGoal="30" appleStandHours="11" appleStandHoursGoal="12"/>
+</HealthData>
+"#;
+
+    fn read_elements(batch_size: usize) -> (Vec<Vec<Element>>, ExportStats) {
+        let mut batches = Vec::new();
+        let stats = read_export(EXPORT.as_bytes(), batch_size, |batch| {
+            batches.push(batch);
+            Ok(())
+        })
+        .unwrap();
+
+        (batches, stats)
+    }
+
+    fn read_records() -> Vec<Record> {
+        let (batches, _) = read_elements(100);
+
+        batches
+            .into_iter()
+            .flatten()
+            .filter_map(|element| match element {
+                Element::Record(record) => Some(record),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn read_export_in_batches() {
+        let (batches, stats) = read_elements(4);
+
+        assert_eq!(
+            ExportStats {
+                nb_records: 10,
+                nb_workouts: 2,
+                nb_activity_summaries: 1,
+            },
+            stats
+        );
+        assert_eq!(
+            vec![4, 4, 4, 1],
+            batches.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Element::Record(Record {
+                type_: "HKQuantityTypeIdentifierBodyMass".to_owned(),
+                source_name: "Balance & Co".to_owned(),
+                unit: "kg".to_owned(),
+                start_date: "2023-04-01 08:00:00 +0200".to_owned(),
+                end_date: "2023-04-01 08:00:00 +0200".to_owned(),
+                value: "lots".to_owned(),
+            }),
+            batches[0][3]
+        );
+
+        // The totals of the second workout are taken from its statistics
+        assert!(matches!(
+            &batches[2][3],
+            Element::Workout(workout) if workout.total_distance == "5.2" && workout.total_energy_burned_unit == "kcal"
+        ));
+        assert!(matches!(
+            &batches[3][0],
+            Element::ActivitySummary(summary) if summary.export_date == "2023-04-02 10:00:00 +0200"
+        ));
+    }
+
+    #[test]
+    fn read_export_stops_on_error() {
+        let result = read_export(EXPORT.as_bytes(), 1, |_| Err("stop".to_owned()));
+        assert!(matches!(result, Err(Error::Aborted(reason)) if reason == "stop"));
+
+        let truncated = &EXPORT[..EXPORT.find("<Workout").unwrap()];
+        let result = read_export(truncated.as_bytes(), 100, |_| Ok(()));
+        assert!(matches!(result, Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn read_export_from_zip() {
+        let path = std::env::temp_dir().join("hdas-apple-health-test.zip");
+
+        let mut writer = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        writer
+            .start_file("apple_health_export/export_cda.xml", Default::default())
+            .unwrap();
+        writer
+            .start_file("apple_health_export/export.xml", Default::default())
+            .unwrap();
+        writer.write_all(EXPORT.as_bytes()).unwrap();
+        writer.finish().unwrap();
+
+        let mut nb_elements = 0;
+        let stats = read_export_file(&path, 100, |batch| {
+            nb_elements += batch.len();
+            Ok(())
+        })
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(10, stats.nb_records);
+        assert_eq!(13, nb_elements);
+    }
+
+    #[test]
+    fn map_records() {
+        let mut mapper = Mapper::default();
+        let data_points: Vec<_> = read_records()
+            .into_iter()
+            .filter_map(|record| mapper.map(record))
+            .collect();
+
+        let summary: Vec<_> = data_points
+            .iter()
+            .map(|data_point| (data_point.metric, data_point.units.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("step_count", "count"),
+                ("heart_rate", "count/min"),
+                ("blood_oxygen_saturation", "%"),
+                ("weight_body_mass", "kg"),
+            ],
+            summary
+        );
+
+        assert_eq!(
+            Ok(MetricDataPoint::HeartRate(HeartRateDataPoint {
+                date: datetime!(2023-04-01 10:01 +2),
+                min: 72.0,
+                max: 72.0,
+                avg: 72.0,
+                source: Some("Apple Watch de Test".to_owned()),
+            })),
+            data_points[1].data_point
+        );
+        assert!(matches!(
+            &data_points[2].data_point,
+            Ok(MetricDataPoint::Generic(data_point)) if (data_point.quantity - 97.0).abs() < 1e-9
+        ));
+        assert_eq!(
+            Err("invalid value \"lots\"".to_owned()),
+            data_points[3].data_point
+        );
+        assert!(data_points[3]
+            .raw
+            .to_json()
+            .contains(r#""sourceName":"Balance & Co""#));
+
+        let mut exp_unmapped = BTreeMap::new();
+        exp_unmapped.insert("HKQuantityTypeIdentifierNumberOfTimesFallen".to_owned(), 1);
+        assert_eq!(&exp_unmapped, mapper.unmapped_types());
+    }
+
+    #[test]
+    fn map_sleep_records_by_night() {
+        let mut mapper = Mapper::default();
+        for record in read_records() {
+            mapper.map(record);
+        }
+
+        let data_points = mapper.sleep_analysis();
+        assert_eq!(1, data_points.len());
+        assert!(mapper.sleep_analysis().is_empty());
+
+        // Asleep from 23:30 to 3:00 then from 3:15 to 6:45, the overlapping records counted once
+        let exp = SleepAnalysisDataPoint {
+            asleep: 7.0,
+            date: datetime!(2023-04-01 00:00 +2),
+            sleep_source: "Apple Watch de Test|AutoSleep".to_owned(),
+            sleep_start: datetime!(2023-03-31 23:30 +2),
+            sleep_end: datetime!(2023-04-01 06:45 +2),
+            in_bed: 8.0,
+            in_bed_source: "iPhone de Test".to_owned(),
+            in_bed_start: datetime!(2023-03-31 23:00 +2),
+            in_bed_end: datetime!(2023-04-01 07:00 +2),
+        };
+        assert_eq!(
+            Ok(MetricDataPoint::SleepAnalysis(exp)),
+            data_points[0].data_point
+        );
+        assert_eq!("sleep_analysis", data_points[0].metric);
+        assert_eq!("hr", data_points[0].units);
+    }
+
+    #[test]
+    fn map_workouts() {
+        let (batches, _) = read_elements(100);
+        let workouts: Vec<_> = batches
+            .into_iter()
+            .flatten()
+            .filter_map(|element| match element {
+                Element::Workout(workout) => Some(workout.to_workout().unwrap()),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(2, workouts.len());
+        assert_eq!("traditional_strength_training", workouts[0].sport);
+        assert_eq!(datetime!(2023-04-01 08:00 +2), workouts[0].start_time);
+        assert_eq!(2760.0, workouts[0].elapsed_time);
+        assert_eq!(Some(2700.0), workouts[0].timer_time);
+        assert_eq!(None, workouts[0].distance);
+        assert_eq!(Some(180.0), workouts[0].calories);
+
+        assert_eq!("running", workouts[1].sport);
+        assert_eq!(Some(1800.0), workouts[1].timer_time);
+        assert_eq!(Some(5200.0), workouts[1].distance);
+        assert_eq!(Some(350.0), workouts[1].calories);
+
+        let workout = Workout {
+            total_distance: "5".to_owned(),
+            total_distance_unit: "furlong".to_owned(),
+            start_date: "2023-04-01 18:00:00 +0200".to_owned(),
+            end_date: "2023-04-01 18:30:00 +0200".to_owned(),
+            workout_activity_type: "HKWorkoutActivityTypeRunning".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Err("unknown totalDistance unit \"furlong\"".to_owned()),
+            workout.to_workout()
+        );
+    }
+
+    #[test]
+    fn map_activity_summaries() {
+        let mapper = Mapper::default();
+        let summary = ActivitySummary {
+            date_components: "2023-04-01".to_owned(),
+            active_energy_burned: "500".to_owned(),
+            active_energy_burned_unit: "kcal".to_owned(),
+            apple_exercise_time: "35".to_owned(),
+            apple_stand_hours: "11".to_owned(),
+            export_date: "2023-04-02 10:00:00 +0200".to_owned(),
+        };
+
+        let data_points = mapper.map_activity_summary(summary.clone());
+        let summary_data_points: Vec<_> = data_points
+            .iter()
+            .map(|data_point| (data_point.metric, data_point.units.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("activity_active_energy", "kcal"),
+                ("activity_exercise_time", "min"),
+                ("activity_stand_hours", "count"),
+            ],
+            summary_data_points
+        );
+        assert_eq!(
+            Ok(MetricDataPoint::Generic(GenericDataPoint {
+                date: datetime!(2023-04-01 00:00 +2),
+                quantity: 11.0,
+                source: None,
+            })),
+            data_points[2].data_point
+        );
+
+        // The missing totals are skipped, the invalid ones rejected
+        let summary = ActivitySummary {
+            active_energy_burned: "lots".to_owned(),
+            apple_stand_hours: String::new(),
+            ..summary
+        };
+        let data_points = mapper.map_activity_summary(summary);
+        assert_eq!(2, data_points.len());
+        assert_eq!(
+            Err("invalid activity_active_energy \"lots\"".to_owned()),
+            data_points[0].data_point
+        );
+    }
+}
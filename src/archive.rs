@@ -0,0 +1,556 @@
+use crate::db;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures_util::StreamExt;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    SQLx(#[from] sqlx::Error),
+    #[error("raw upload {0} not found")]
+    NotFound(i64),
+    #[error("unknown body encoding {0:?}")]
+    UnknownEncoding(String),
+    #[error("unknown body format {0:?}")]
+    UnknownFormat(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Size from which the compressed data of a body is written as a chunk.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Headers never archived since they can contain credentials.
+const REDACTED_HEADERS: [http::header::HeaderName; 2] =
+    [http::header::AUTHORIZATION, http::header::COOKIE];
+
+/// Format of an upload body, tells the ingester how to parse it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A Health Auto Export JSON payload.
+    Json,
+    /// A FIT file recorded by a Garmin device.
+    Fit,
+    /// A GPX file with the tracks of workouts.
+    Gpx,
+    /// A Garmin Training Center file with the activities of workouts.
+    Tcx,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Fit => "fit",
+            Self::Gpx => "gpx",
+            Self::Tcx => "tcx",
+        }
+    }
+}
+
+/// Archives the headers and body of an upload request, returns the ID of the raw upload.
+///
+/// The body is stored compressed. The data points ingested from it belong to `user_id`
+/// and are attributed to `api_token_id`.
+pub async fn store<B>(
+    tx: &mut db::Transaction,
+    user_id: i64,
+    api_token_id: Option<i64>,
+    headers: &http::HeaderMap,
+    format: Format,
+    body: B,
+) -> Result<i64>
+where
+    B: AsRef<[u8]> + Send + 'static,
+{
+    let body = run_blocking(move || compress(body.as_ref())).await?;
+
+    let raw_upload_id = insert_raw_upload(tx, user_id, api_token_id, headers, format).await?;
+    for (chunk_index, chunk) in (0..).zip(body.chunks(CHUNK_SIZE)) {
+        write_chunk(tx, raw_upload_id, chunk_index, chunk.to_vec()).await?;
+    }
+
+    Ok(raw_upload_id)
+}
+
+/// Compresses a body as it's received into a temporary file.
+///
+/// The body is only archived once fully received, so that the transaction archiving it
+/// doesn't last as long as the upload.
+pub struct BodyWriter {
+    encoder: GzEncoder<fs::File>,
+}
+
+impl BodyWriter {
+    pub async fn new() -> Result<Self> {
+        let file = run_blocking(spool_file).await?;
+
+        Ok(Self {
+            encoder: GzEncoder::new(file, flate2::Compression::default()),
+        })
+    }
+
+    /// Compresses a part of the body.
+    pub async fn write<B>(self, data: B) -> Result<Self>
+    where
+        B: AsRef<[u8]> + Send + 'static,
+    {
+        let mut encoder = self.encoder;
+        let encoder = run_blocking(move || {
+            encoder.write_all(data.as_ref())?;
+            Ok(encoder)
+        })
+        .await?;
+
+        Ok(Self { encoder })
+    }
+
+    /// Archives the headers of an upload request and the body written so far, like [`store`].
+    ///
+    /// The compressed body is read back from the temporary file in chunks of [`CHUNK_SIZE`] bytes.
+    pub async fn store(
+        self,
+        tx: &mut db::Transaction,
+        user_id: i64,
+        api_token_id: Option<i64>,
+        headers: &http::HeaderMap,
+        format: Format,
+    ) -> Result<i64> {
+        let encoder = self.encoder;
+        let mut file = run_blocking(move || {
+            let mut file = encoder.finish()?;
+            file.seek(io::SeekFrom::Start(0))?;
+            Ok(file)
+        })
+        .await?;
+
+        let raw_upload_id = insert_raw_upload(tx, user_id, api_token_id, headers, format).await?;
+        for chunk_index in 0.. {
+            let (next_file, chunk) = run_blocking(move || {
+                let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+                (&mut file)
+                    .take(CHUNK_SIZE as u64)
+                    .read_to_end(&mut chunk)?;
+                Ok((file, chunk))
+            })
+            .await?;
+            file = next_file;
+
+            if chunk.is_empty() {
+                break;
+            }
+            write_chunk(tx, raw_upload_id, chunk_index, chunk).await?;
+        }
+
+        Ok(raw_upload_id)
+    }
+}
+
+/// Creates a temporary file, removed right away so that it disappears once closed.
+fn spool_file() -> io::Result<fs::File> {
+    let path = std::env::temp_dir().join(format!("hdas-upload-{:016x}", rand::random::<u64>()));
+
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+
+    Ok(file)
+}
+
+async fn insert_raw_upload(
+    tx: &mut db::Transaction,
+    user_id: i64,
+    api_token_id: Option<i64>,
+    headers: &http::HeaderMap,
+    format: Format,
+) -> Result<i64> {
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO raw_upload(user_id, api_token_id, headers, body_encoding, format)
+        VALUES($1, $2, $3, 'gzip', $4)
+        RETURNING id"#,
+        user_id,
+        api_token_id,
+        headers_to_json(headers),
+        format.as_str(),
+    )
+    .fetch_one(tx)
+    .await?;
+
+    Ok(record.id)
+}
+
+async fn write_chunk(
+    tx: &mut db::Transaction,
+    raw_upload_id: i64,
+    chunk_index: i32,
+    data: Vec<u8>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO raw_upload_chunk(raw_upload_id, chunk_index, data)
+        VALUES($1, $2, $3)"#,
+        raw_upload_id,
+        chunk_index,
+        data,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// The body of a raw upload as stored in the archive.
+pub struct Body {
+    encoding: BodyEncoding,
+    format: Format,
+    data: BodyData,
+}
+
+enum BodyData {
+    Memory(Vec<u8>),
+    /// The chunks of a raw upload, fetched as the body is read.
+    Archive {
+        pool: sqlx::PgPool,
+        raw_upload_id: i64,
+        runtime: tokio::runtime::Handle,
+    },
+}
+
+enum BodyEncoding {
+    Identity,
+    Gzip,
+}
+
+impl Body {
+    /// Returns an uncompressed body.
+    pub fn new(format: Format, data: Vec<u8>) -> Self {
+        Self {
+            encoding: BodyEncoding::Identity,
+            format,
+            data: BodyData::Memory(data),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns a reader decompressing the body on the fly.
+    ///
+    /// The body of a raw upload is read from the archive chunk by chunk, so the reader
+    /// blocks and must be used outside of the runtime.
+    pub fn reader(&self) -> Box<dyn Read + Send + '_> {
+        let data: Box<dyn Read + Send + '_> = match &self.data {
+            BodyData::Memory(data) => Box::new(data.as_slice()),
+            BodyData::Archive {
+                pool,
+                raw_upload_id,
+                runtime,
+            } => Box::new(read_chunks(pool.clone(), *raw_upload_id, runtime)),
+        };
+
+        match self.encoding {
+            BodyEncoding::Identity => data,
+            BodyEncoding::Gzip => Box::new(GzDecoder::new(data)),
+        }
+    }
+}
+
+/// Returns the body of a raw upload.
+///
+/// The body isn't loaded, [`Body::reader`] fetches and decompresses it as it's read.
+pub async fn load_body(pool: &sqlx::PgPool, id: i64) -> Result<Body> {
+    let record = sqlx::query!(
+        r#"SELECT body_encoding, format FROM raw_upload WHERE id = $1"#,
+        id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(Error::NotFound(id))?;
+
+    let encoding = match record.body_encoding.as_str() {
+        "identity" => BodyEncoding::Identity,
+        "gzip" => BodyEncoding::Gzip,
+        encoding => return Err(Error::UnknownEncoding(encoding.to_owned())),
+    };
+    let format = match record.format.as_str() {
+        "json" => Format::Json,
+        "fit" => Format::Fit,
+        "gpx" => Format::Gpx,
+        "tcx" => Format::Tcx,
+        format => return Err(Error::UnknownFormat(format.to_owned())),
+    };
+
+    Ok(Body {
+        encoding,
+        format,
+        data: BodyData::Archive {
+            pool: pool.clone(),
+            raw_upload_id: id,
+            runtime: tokio::runtime::Handle::current(),
+        },
+    })
+}
+
+/// Returns a reader of the chunks of a raw upload, a task of the runtime fetches them as they're read.
+fn read_chunks(
+    pool: sqlx::PgPool,
+    raw_upload_id: i64,
+    runtime: &tokio::runtime::Handle,
+) -> ChannelReader {
+    let (sender, receiver) = mpsc::channel(2);
+
+    runtime.spawn(async move {
+        let mut chunks = sqlx::query!(
+            r#"
+            SELECT data FROM raw_upload_chunk
+            WHERE raw_upload_id = $1
+            ORDER BY chunk_index"#,
+            raw_upload_id,
+        )
+        .fetch(&pool);
+
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk
+                .map(|record| Bytes::from(record.data))
+                .map_err(io::Error::other);
+
+            // The reader was dropped
+            if sender.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    ChannelReader::new(receiver)
+}
+
+/// Reads the parts of a body sent through a channel, blocks until they're received.
+///
+/// Must be used outside of the runtime, in a blocking task.
+pub struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Bytes>>,
+    part: Bytes,
+}
+
+impl ChannelReader {
+    pub fn new(receiver: mpsc::Receiver<io::Result<Bytes>>) -> Self {
+        Self {
+            receiver,
+            part: Bytes::new(),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.part.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(part) => self.part = part?,
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.part.len());
+        buf[..n].copy_from_slice(&self.part[..n]);
+        self.part = self.part.slice(n..);
+
+        Ok(n)
+    }
+}
+
+/// Outcome of the parsing of a raw upload.
+pub enum ParseOutcome {
+    Ok,
+    /// Parsed but some data points were invalid.
+    Partial(String),
+    Error(String),
+}
+
+/// Records whether the body of a raw upload could be parsed, with the parse error if not.
+pub async fn set_parse_outcome<'e, E>(executor: E, id: i64, outcome: ParseOutcome) -> Result<()>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let (parse_outcome, parse_error) = match outcome {
+        ParseOutcome::Ok => ("ok", None),
+        ParseOutcome::Partial(err) => ("partial", Some(err)),
+        ParseOutcome::Error(err) => ("error", Some(err)),
+    };
+
+    sqlx::query!(
+        r#"
+        UPDATE raw_upload
+        SET parse_outcome = $2, parse_error = $3
+        WHERE id = $1"#,
+        id,
+        parse_outcome,
+        parse_error,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the IDs of the raw uploads received in the range `[from, to)`, oldest first.
+pub async fn find_in_range<'e, E>(
+    executor: E,
+    from: OffsetDateTime,
+    to: OffsetDateTime,
+) -> Result<Vec<i64>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let records = sqlx::query!(
+        r#"
+        SELECT id FROM raw_upload
+        WHERE received_at >= $1 AND received_at < $2
+        ORDER BY id"#,
+        from,
+        to,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(records.into_iter().map(|record| record.id).collect())
+}
+
+fn headers_to_json(headers: &http::HeaderMap) -> serde_json::Value {
+    let mut result = serde_json::Map::new();
+
+    for name in headers.keys() {
+        if REDACTED_HEADERS.contains(name) {
+            continue;
+        }
+
+        let values: Vec<_> = headers
+            .get_all(name)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()))
+            .collect();
+
+        result.insert(name.to_string(), values.join(", ").into());
+    }
+
+    serde_json::Value::Object(result)
+}
+
+fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Runs a CPU heavy or blocking function outside of the runtime.
+async fn run_blocking<F, T>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::configuration;
+    use crate::user;
+    use rand::RngCore;
+    use secrecy::ExposeSecret;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_store_in_chunks() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let user_id = user::get_or_create_user(&mut tx, "archive-test-user")
+            .await
+            .unwrap();
+
+        // Random data doesn't compress, the body spans several chunks
+        let mut data = vec![0u8; 3 * CHUNK_SIZE];
+        rand::thread_rng().fill_bytes(&mut data);
+
+        let mut writer = BodyWriter::new().await.unwrap();
+        for part in data.chunks(64 * 1024) {
+            writer = writer.write(part.to_vec()).await.unwrap();
+        }
+        let raw_upload_id = writer
+            .store(&mut tx, user_id, None, &http::HeaderMap::new(), Format::Fit)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let nb_chunks = sqlx::query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM raw_upload_chunk WHERE raw_upload_id = $1"#,
+            raw_upload_id,
+        )
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert!(nb_chunks > 1);
+
+        let body = load_body(&db.pool, raw_upload_id).await.unwrap();
+        assert_eq!(Format::Fit, body.format());
+
+        let read = tokio::task::spawn_blocking(move || {
+            let mut read = Vec::new();
+            body.reader().read_to_end(&mut read).map(|_| read)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(read == data);
+
+        sqlx::query!("DELETE FROM raw_upload WHERE id = $1", raw_upload_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn headers_to_json_redacts_credentials() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::USER_AGENT,
+            "Health Auto Export".parse().unwrap(),
+        );
+        headers.insert(
+            http::header::AUTHORIZATION,
+            "Bearer foobar".parse().unwrap(),
+        );
+        headers.append("x-foo", "a".parse().unwrap());
+        headers.append("x-foo", "b".parse().unwrap());
+
+        let exp = serde_json::json!({
+            "user-agent": "Health Auto Export",
+            "x-foo": "a, b",
+        });
+
+        assert_eq!(exp, headers_to_json(&headers));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::db;
+use crate::problem::Problem;
+use crate::rate_limit;
+use crate::user;
+use crate::web;
+use axum::response::IntoResponse;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    SQLx(#[from] sqlx::Error),
+    #[error(transparent)]
+    User(#[from] user::Error),
+    #[error("invalid scope {0:?}")]
+    InvalidScope(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Prefix of the generated tokens, makes them easy to spot in a secret scanner.
+const TOKEN_PREFIX: &str = "hdas_";
+
+/// What an API token is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Upload health data.
+    Ingest,
+    /// Read the uploads and their outcome.
+    Read,
+    /// Manage the tokens and replay uploads, implies all other scopes.
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ingest => "ingest",
+            Self::Read => "read",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ingest" => Ok(Self::Ingest),
+            "read" => Ok(Self::Read),
+            "admin" => Ok(Self::Admin),
+            _ => Err(Error::InvalidScope(s.to_owned())),
+        }
+    }
+}
+
+/// An API token, without the token itself which is only known by its owner.
+///
+/// The data uploaded with a token belongs to its user.
+#[derive(Debug, serde::Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub label: String,
+    pub scopes: Vec<Scope>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used_at: Option<time::OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub revoked_at: Option<time::OffsetDateTime>,
+}
+
+impl ApiToken {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    /// Fails with a 403 if the token doesn't have the scope.
+    pub fn require(&self, scope: Scope) -> std::result::Result<(), Problem> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(Problem::new(http::StatusCode::FORBIDDEN)
+                .with_detail(format!("token is missing the {} scope", scope)))
+        }
+    }
+}
+
+struct ApiTokenRecord {
+    id: i64,
+    user_id: i64,
+    label: String,
+    scopes: Vec<String>,
+    created_at: time::OffsetDateTime,
+    last_used_at: Option<time::OffsetDateTime>,
+    revoked_at: Option<time::OffsetDateTime>,
+}
+
+impl TryFrom<ApiTokenRecord> for ApiToken {
+    type Error = Error;
+
+    fn try_from(record: ApiTokenRecord) -> Result<Self> {
+        Ok(Self {
+            id: record.id,
+            user_id: record.user_id,
+            label: record.label,
+            scopes: record
+                .scopes
+                .iter()
+                .map(|scope| scope.parse())
+                .collect::<Result<_>>()?,
+            created_at: record.created_at,
+            last_used_at: record.last_used_at,
+            revoked_at: record.revoked_at,
+        })
+    }
+}
+
+/// Creates a new token, returns its ID and the token itself.
+///
+/// Only the hash of the token is stored so this is the only time it's available.
+pub async fn create_token<'e, E>(
+    executor: E,
+    user_id: i64,
+    label: &str,
+    scopes: &[Scope],
+) -> Result<(i64, String)>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let token = generate_token();
+    let scopes: Vec<_> = scopes.iter().map(|scope| scope.as_str()).collect();
+
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO api_token(user_id, label, token_hash, scopes)
+        VALUES($1, $2, $3, $4)
+        RETURNING id"#,
+        user_id,
+        label,
+        hash_token(&token),
+        &scopes as _,
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok((record.id, token))
+}
+
+/// Returns the token matching `token` if it exists and isn't revoked.
+pub async fn authenticate<'e, E>(executor: E, token: &str) -> Result<Option<ApiToken>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let record = sqlx::query_as!(
+        ApiTokenRecord,
+        r#"
+        SELECT id, user_id, label, scopes, created_at, last_used_at, revoked_at
+        FROM api_token
+        WHERE token_hash = $1 AND revoked_at IS NULL"#,
+        hash_token(token),
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    record.map(ApiToken::try_from).transpose()
+}
+
+/// Records the use of a token, returns false if its last use was recorded less than a minute ago.
+///
+/// Tokens are used for every request, the row is only updated once a minute at most.
+pub async fn record_use<'e, E>(executor: E, id: i64) -> Result<bool>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let result = sqlx::query!(
+        r#"
+        UPDATE api_token
+        SET last_used_at = now()
+        WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < now() - interval '1 minute')"#,
+        id,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn list_tokens<'e, E>(executor: E) -> Result<Vec<ApiToken>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let records = sqlx::query_as!(
+        ApiTokenRecord,
+        r#"
+        SELECT id, user_id, label, scopes, created_at, last_used_at, revoked_at
+        FROM api_token
+        ORDER BY id"#,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    records.into_iter().map(ApiToken::try_from).collect()
+}
+
+/// Revokes a token, returns false if it doesn't exist or was already revoked.
+///
+/// The data points ingested with the token are kept, they can be found with their `api_token_id`.
+pub async fn revoke_token<'e, E>(executor: E, id: i64) -> Result<bool>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let result = sqlx::query!(
+        r#"
+        UPDATE api_token
+        SET revoked_at = now()
+        WHERE id = $1 AND revoked_at IS NULL"#,
+        id,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Creates an admin token if there's no usable one, returns the token if it was created.
+///
+/// Without it there would be no way to create the first tokens through the API.
+pub async fn ensure_admin_token(db: &db::Db) -> Result<Option<String>> {
+    let mut tx = db.pool.begin().await?;
+
+    // Serialize the check between concurrent starts
+    sqlx::query!(r#"LOCK TABLE api_token IN SHARE ROW EXCLUSIVE MODE"#)
+        .execute(&mut tx)
+        .await?;
+
+    let record = sqlx::query!(
+        r#"
+        SELECT EXISTS(
+          SELECT 1 FROM api_token
+          WHERE 'admin' = ANY(scopes) AND revoked_at IS NULL
+        ) AS "exists!""#
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    if record.exists {
+        return Ok(None);
+    }
+
+    let user_id = user::get_or_create_user(&mut tx, user::DEFAULT_USER).await?;
+    let (_, token) = create_token(&mut tx, user_id, "bootstrap", &[Scope::Admin]).await?;
+
+    tx.commit().await?;
+
+    Ok(Some(token))
+}
+
+fn generate_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+
+    let mut result = String::with_capacity(TOKEN_PREFIX.len() + bytes.len() * 2);
+    result.push_str(TOKEN_PREFIX);
+    for byte in bytes {
+        result.push_str(&format!("{:02x}", byte));
+    }
+
+    result
+}
+
+/// Hashes a token for storage.
+///
+/// The tokens are random so a fast unsalted hash is enough.
+pub fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Extracts the API token from the `Authorization: Bearer` header.
+///
+/// Rejects the request with a 401 if the header is missing or the token is unknown or revoked,
+/// and with a 429 if the token exceeds its rate limit.
+#[axum::async_trait]
+impl axum::extract::FromRequestParts<web::State> for ApiToken {
+    type Rejection = axum::response::Response;
+
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &web::State,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or_else(|| unauthorized("missing bearer token").into_response())?;
+
+        let api_token = match authenticate(&state.db().pool, token).await {
+            Ok(Some(api_token)) => api_token,
+            Ok(None) => return Err(unauthorized("invalid or revoked token").into_response()),
+            Err(err) => return Err(Problem::internal(err).into_response()),
+        };
+        record_use(&state.db().pool, api_token.id)
+            .await
+            .map_err(|err| Problem::internal(err).into_response())?;
+
+        state
+            .rate_limiter()
+            .limit_token(api_token.id)
+            .map_err(rate_limit::rate_limited)?;
+
+        Ok(api_token)
+    }
+}
+
+fn unauthorized(detail: &str) -> Problem {
+    Problem::new(http::StatusCode::UNAUTHORIZED).with_detail(detail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use secrecy::ExposeSecret;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn generate_token_is_random() {
+        let token1 = generate_token();
+        let token2 = generate_token();
+
+        assert!(token1.starts_with(TOKEN_PREFIX));
+        assert_eq!(TOKEN_PREFIX.len() + 64, token1.len());
+        assert_ne!(token1, token2);
+        assert_ne!(hash_token(&token1), hash_token(&token2));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let user_id = user::get_or_create_user(&mut tx, "test-user")
+            .await
+            .unwrap();
+
+        let (id, token) = create_token(&mut tx, user_id, "iPhone", &[Scope::Ingest])
+            .await
+            .unwrap();
+
+        let api_token = authenticate(&mut tx, &token).await.unwrap().unwrap();
+        assert_eq!(id, api_token.id);
+        assert_eq!(user_id, api_token.user_id);
+        assert_eq!("iPhone", api_token.label);
+        assert!(api_token.last_used_at.is_none());
+        assert!(api_token.has_scope(Scope::Ingest));
+        assert!(!api_token.has_scope(Scope::Read));
+
+        assert!(record_use(&mut tx, id).await.unwrap());
+        assert!(!record_use(&mut tx, id).await.unwrap());
+        let api_token = authenticate(&mut tx, &token).await.unwrap().unwrap();
+        assert!(api_token.last_used_at.is_some());
+
+        assert!(authenticate(&mut tx, "hdas_foobar")
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(revoke_token(&mut tx, id).await.unwrap());
+        assert!(!revoke_token(&mut tx, id).await.unwrap());
+        assert!(authenticate(&mut tx, &token).await.unwrap().is_none());
+    }
+}
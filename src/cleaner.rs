@@ -1,4 +1,6 @@
 use crate::db;
+use crate::exporter;
+use crate::idempotency;
 use crate::shutdown::Shutdown;
 use std::fmt;
 use std::io;
@@ -14,17 +16,25 @@ pub enum Error {
     SQLx(#[from] sqlx::Error),
     #[error(transparent)]
     Fmt(#[from] fmt::Error),
+    #[error(transparent)]
+    Db(#[from] db::Error),
+    #[error(transparent)]
+    Idempotency(#[from] idempotency::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct Cleaner {
     db: Arc<db::Db>,
+    idempotency_key_ttl: time::Duration,
 }
 
 impl Cleaner {
-    pub fn new(db: Arc<db::Db>) -> Self {
-        Self { db }
+    pub fn new(db: Arc<db::Db>, idempotency_key_ttl: time::Duration) -> Self {
+        Self {
+            db,
+            idempotency_key_ttl,
+        }
     }
 
     pub async fn run(mut self, mut shutdown: Shutdown) -> Result<()> {
@@ -50,27 +60,160 @@ impl Cleaner {
 
     async fn do_clean(&mut self) -> Result<()> {
         let mut tx = self.db.pool.begin().await?;
+        let nb_cleaned = delete_exported_data_points(&mut tx).await?;
+        tx.commit().await?;
 
-        let result1 = sqlx::query!(r#"DELETE FROM data_point_heart_rate WHERE exported = true"#)
-            .execute(&mut tx)
-            .await?;
+        info!(nb_cleaned, "cleaned");
 
-        let result2 = sqlx::query!(r#"DELETE FROM data_point_generic WHERE exported = true"#)
-            .execute(&mut tx)
-            .await?;
+        db::delete_finished_ingestions(&self.db.pool).await?;
 
-        let result3 =
-            sqlx::query!(r#"DELETE FROM data_point_sleep_analysis WHERE exported = true"#)
-                .execute(&mut tx)
-                .await?;
+        let nb_expired_keys =
+            idempotency::delete_expired(&self.db.pool, self.idempotency_key_ttl).await?;
+        if nb_expired_keys > 0 {
+            info!(nb_expired_keys, "deleted expired idempotency keys");
+        }
 
-        tx.commit().await?;
+        Ok(())
+    }
+}
 
-        let nb_cleaned =
-            result1.rows_affected() + result2.rows_affected() + result3.rows_affected();
+/// Deletes the data points exported to every sink, returns the number of deleted data points.
+///
+/// The cursors move past the data points of the metrics which aren't exported,
+/// so these are only deleted if their metric is exported. The data points of the metrics
+/// with a source priority are kept, the exporter compares the new data points with them.
+async fn delete_exported_data_points(tx: &mut db::Transaction) -> Result<u64> {
+    let heart_rate = sqlx::query!(
+        r#"
+        DELETE FROM data_point_heart_rate d
+        USING metric m
+        WHERE m.id = d.metric_id AND m.name = ANY($1)
+        AND NOT EXISTS (SELECT 1 FROM source_priority p WHERE p.metric_name = m.name)
+        AND d.id <= (
+          SELECT min(last_id) FROM export_cursor
+          WHERE table_name = 'data_point_heart_rate'
+        )"#,
+        &exporter::exported_metrics("data_point_heart_rate"),
+    )
+    .execute(&mut *tx)
+    .await?;
 
-        info!(nb_cleaned, "cleaned");
+    let generic = sqlx::query!(
+        r#"
+        DELETE FROM data_point_generic d
+        USING metric m
+        WHERE m.id = d.metric_id AND m.name = ANY($1)
+        AND NOT EXISTS (SELECT 1 FROM source_priority p WHERE p.metric_name = m.name)
+        AND d.id <= (
+          SELECT min(last_id) FROM export_cursor
+          WHERE table_name = 'data_point_generic'
+        )"#,
+        &exporter::exported_metrics("data_point_generic"),
+    )
+    .execute(&mut *tx)
+    .await?;
 
-        Ok(())
+    let sleep_analysis = sqlx::query!(
+        r#"
+        DELETE FROM data_point_sleep_analysis d
+        USING metric m
+        WHERE m.id = d.metric_id AND m.name = ANY($1)
+        AND d.id <= (
+          SELECT min(last_id) FROM export_cursor
+          WHERE table_name = 'data_point_sleep_analysis'
+        )"#,
+        &exporter::exported_metrics("data_point_sleep_analysis"),
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(heart_rate.rows_affected() + generic.rows_affected() + sleep_analysis.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use crate::source;
+    use crate::user;
+    use ::time::macros::datetime;
+    use secrecy::ExposeSecret;
+    use std::collections::BTreeMap;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unexported_and_prioritized_metrics_are_kept() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let mut priorities = BTreeMap::new();
+        priorities.insert("walking_speed".to_owned(), vec!["Watch".to_owned()]);
+        source::sync_priorities(&mut tx, &priorities).await.unwrap();
+
+        let user_id = user::get_or_create_user(&mut tx, "test-user")
+            .await
+            .unwrap();
+
+        // The weight and the walking speed are exported, the step count isn't.
+        // The walking speed has a source priority.
+        for name in ["weight_body_mass", "step_count", "walking_speed"] {
+            sqlx::query!(
+                r#"
+                WITH m AS (
+                  INSERT INTO metric(user_id, name, units) VALUES($1, $2, 'count')
+                  ON CONFLICT (user_id, name) DO UPDATE SET name = excluded.name
+                  RETURNING id
+                )
+                INSERT INTO data_point_generic(metric_id, user_id, date, quantity, source_id)
+                SELECT m.id, $1, $3, 1, (SELECT id FROM source WHERE name = '') FROM m"#,
+                user_id,
+                name,
+                datetime!(2022-08-01 10:00 UTC),
+            )
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        }
+
+        // Every sink is past both data points
+        sqlx::query!(
+            r#"
+            INSERT INTO export_cursor(table_name, sink, last_id)
+            SELECT 'data_point_generic', 'victoria', max(id) FROM data_point_generic
+            ON CONFLICT (table_name, sink) DO UPDATE SET last_id = excluded.last_id"#
+        )
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        delete_exported_data_points(&mut tx).await.unwrap();
+
+        let names: Vec<String> = sqlx::query!(
+            r#"
+            SELECT m.name FROM data_point_generic d
+            INNER JOIN metric m ON m.id = d.metric_id
+            WHERE d.user_id = $1
+            ORDER BY m.name"#,
+            user_id,
+        )
+        .fetch_all(&mut tx)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|record| record.name)
+        .collect();
+        assert_eq!(
+            vec!["step_count".to_owned(), "walking_speed".to_owned()],
+            names
+        );
+
+        tx.rollback().await.unwrap();
     }
 }
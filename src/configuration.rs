@@ -1,20 +1,299 @@
 use secrecy::{ExposeSecret, Secret};
+use std::collections::BTreeMap;
+use std::time;
 
-#[derive(Clone, serde::Deserialize)]
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct Config {
     pub database: DatabaseConfig,
     pub application: ApplicationSetttings,
+    #[serde(default)]
+    pub exporter: ExporterConfig,
+    #[serde(default)]
+    pub ingester: IngesterConfig,
+    /// Canonical unit of the metrics, by metric name.
+    ///
+    /// The data points of these metrics are converted to their canonical unit when ingested.
+    /// The other metrics keep the unit of their first upload.
+    #[serde(default)]
+    pub units: BTreeMap<String, String>,
+    /// Priority of the sources of the metrics, by metric name, highest priority first.
+    ///
+    /// A source matches if its name contains the pattern. When multiple sources have
+    /// overlapping data points only the one with the highest priority is exported and read.
+    #[serde(default)]
+    pub source_priority: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub priority_resolution: PriorityResolutionConfig,
+    #[serde(default)]
+    pub validation: ValidationConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
 }
 
-#[derive(Clone, serde::Deserialize)]
+#[derive(Debug, thiserror::Error)]
+#[error("invalid configuration: {0} must be greater than zero")]
+pub struct ZeroInterval(&'static str);
+
+impl Config {
+    /// Checks what the deserialization can't, the intervals can't be zero.
+    pub fn validate(&self) -> Result<(), ZeroInterval> {
+        let mut intervals = vec![
+            (
+                "exporter.poll_interval_secs",
+                self.exporter.poll_interval_secs,
+            ),
+            (
+                "ingester.poll_interval_secs",
+                self.ingester.poll_interval_secs,
+            ),
+        ];
+        if let Some(tls) = &self.application.tls {
+            intervals.push((
+                "application.tls.reload_interval_secs",
+                tls.reload_interval_secs,
+            ));
+        }
+
+        match intervals.into_iter().find(|(_, value)| *value == 0) {
+            Some((name, _)) => Err(ZeroInterval(name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Renders the configuration as TOML, with the secrets redacted.
+    pub fn to_redacted_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct ApplicationSetttings {
     pub listen_addr: String,
     pub victoria_addr: String,
+    /// Maximum size of a request body in bytes, larger requests are rejected with a 413.
+    ///
+    /// This is the size once decompressed for the compressed uploads.
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    /// How long the idempotency keys of the uploads are kept, in seconds.
+    ///
+    /// An upload retried with the same key within this delay isn't ingested again.
+    #[serde(default = "default_idempotency_key_ttl_secs")]
+    pub idempotency_key_ttl_secs: u64,
+    /// Serve HTTPS instead of plain HTTP if set.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+impl ApplicationSetttings {
+    pub fn idempotency_key_ttl(&self) -> time::Duration {
+        time::Duration::from_secs(self.idempotency_key_ttl_secs)
+    }
+}
+
+fn default_max_body_size() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_idempotency_key_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct TlsConfig {
+    /// Path of the PEM certificate chain.
+    pub cert_path: String,
+    /// Path of the PEM private key.
+    pub key_path: String,
+    /// Path of the PEM certificates of the CA signing the client certificates.
+    ///
+    /// If set the clients must present a certificate signed by this CA, otherwise
+    /// the clients aren't authenticated at the TLS level.
+    pub client_ca_path: Option<String>,
+    /// Interval at which the files are checked for changes, in seconds.
+    ///
+    /// The certificates are reloaded without a restart when a file changes.
+    #[serde(default = "default_tls_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+impl TlsConfig {
+    pub fn reload_interval(&self) -> time::Duration {
+        time::Duration::from_secs(self.reload_interval_secs)
+    }
+}
+
+fn default_tls_reload_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ExporterConfig {
+    /// Interval of the fallback poll, in seconds.
+    ///
+    /// The exporter is normally woken up by a notification when new data is committed,
+    /// this poll only exists to catch up on notifications missed while disconnected.
+    pub poll_interval_secs: u64,
+    /// How long to wait after a notification before exporting, in milliseconds.
+    ///
+    /// This batches the notifications sent by a single upload in a single export.
+    pub debounce_interval_ms: u64,
+    /// Maximum number of data points read from the database and sent at once.
+    pub chunk_size: i64,
+}
+
+impl ExporterConfig {
+    pub fn poll_interval(&self) -> time::Duration {
+        time::Duration::from_secs(self.poll_interval_secs)
+    }
+
+    pub fn debounce_interval(&self) -> time::Duration {
+        time::Duration::from_millis(self.debounce_interval_ms)
+    }
+}
+
+impl Default for ExporterConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 60,
+            debounce_interval_ms: 500,
+            chunk_size: 5000,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct IngesterConfig {
+    /// Number of uploads processed concurrently.
+    pub workers: usize,
+    /// Interval of the fallback poll, in seconds.
+    ///
+    /// Like the exporter, the ingesters are woken up by a notification when an upload is queued.
+    pub poll_interval_secs: u64,
+    /// Default ingestion mode of the uploads, can be overridden per upload.
+    ///
+    /// In strict mode a single invalid data point fails the whole upload,
+    /// otherwise invalid data points are skipped and the rest is ingested.
+    pub strict: bool,
+    /// Number of data points parsed before being inserted at once.
+    pub batch_size: usize,
+}
+
+impl IngesterConfig {
+    pub fn poll_interval(&self) -> time::Duration {
+        time::Duration::from_secs(self.poll_interval_secs)
+    }
 }
 
-#[derive(Clone, serde::Deserialize)]
+impl Default for IngesterConfig {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            poll_interval_secs: 60,
+            strict: false,
+            batch_size: 5000,
+        }
+    }
+}
+
+/// How the data points of sources with different priorities are compared.
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct PriorityResolutionConfig {
+    /// Two data points overlap if their dates are at most this far apart, in seconds.
+    ///
+    /// The sources don't record their data points at the same instants, a watch and a phone
+    /// counting the same steps can be a few seconds apart.
+    pub window_secs: u64,
+    /// How long the data points of the metrics with a source priority wait before being exported, in seconds.
+    ///
+    /// The sources don't sync at the same time, a data point of a source with a higher priority
+    /// arriving within this delay still hides the overlapping ones. Past it, a data point
+    /// already exported stays exported.
+    pub settle_delay_secs: u64,
+}
+
+impl PriorityResolutionConfig {
+    pub fn window(&self) -> time::Duration {
+        time::Duration::from_secs(self.window_secs)
+    }
+
+    pub fn settle_delay(&self) -> time::Duration {
+        time::Duration::from_secs(self.settle_delay_secs)
+    }
+}
+
+impl Default for PriorityResolutionConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            settle_delay_secs: 15 * 60,
+        }
+    }
+}
+
+/// Rules checked on every ingested data point, the data points breaking them are rejected.
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    /// How far in the future a data point can be, in seconds.
+    ///
+    /// Allows for some clock skew between the phone and the server.
+    pub max_future_skew_secs: u64,
+    /// How old a data point can be, in days. Unlimited if not set.
+    pub max_age_days: Option<u64>,
+    /// Allowed range of the values of the metrics, by metric name.
+    ///
+    /// The range is in the units of the metric, after the conversion to the canonical units.
+    pub ranges: BTreeMap<String, RangeConfig>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            max_future_skew_secs: 24 * 60 * 60,
+            max_age_days: None,
+            ranges: BTreeMap::new(),
+        }
+    }
+}
+
+/// Limits protecting the server from clients sending too many requests.
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Number of requests a client can make per minute on average.
+    ///
+    /// The limit applies to every IP address, and to every API token once authenticated.
+    pub requests_per_minute: u32,
+    /// Number of requests a client can make at once before being limited.
+    pub burst: u32,
+    /// Number of uploads handled concurrently, over all clients.
+    pub max_concurrent_uploads: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 60,
+            burst: 20,
+            max_concurrent_uploads: 4,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct RangeConfig {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct DatabaseConfig {
     pub username: String,
+    #[serde(serialize_with = "serialize_redacted")]
     pub password: Secret<String>,
     pub port: u16,
     pub host: String,
@@ -34,15 +313,33 @@ impl DatabaseConfig {
     }
 }
 
-pub fn get_configuration() -> Result<Config, config::ConfigError> {
-    let settings = config::Config::builder()
-        .add_source(
-            config::File::new("configuration.toml", config::FileFormat::Toml).required(false),
-        )
-        .add_source(
-            config::File::new("/etc/hdas/configuration.toml", config::FileFormat::Toml)
-                .required(false),
-        )
+/// Serializes a secret without revealing it.
+fn serialize_redacted<S>(_secret: &Secret<String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str("[REDACTED]")
+}
+
+/// Reads the configuration from `path`, overridden by the environment.
+///
+/// Without a path the configuration is read from `configuration.toml` and `/etc/hdas/configuration.toml`
+/// if they exist.
+pub fn get_configuration(path: Option<&str>) -> Result<Config, config::ConfigError> {
+    let mut builder = config::Config::builder();
+    builder = match path {
+        Some(path) => builder.add_source(config::File::new(path, config::FileFormat::Toml)),
+        None => builder
+            .add_source(
+                config::File::new("configuration.toml", config::FileFormat::Toml).required(false),
+            )
+            .add_source(
+                config::File::new("/etc/hdas/configuration.toml", config::FileFormat::Toml)
+                    .required(false),
+            ),
+    };
+
+    let settings = builder
         .add_source(
             config::Environment::default()
                 .try_parsing(true)
@@ -52,3 +349,55 @@ pub fn get_configuration() -> Result<Config, config::ConfigError> {
 
     settings.try_deserialize::<Config>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_intervals() {
+        let mut config = get_configuration(None).unwrap();
+        config.validate().unwrap();
+
+        config.exporter.poll_interval_secs = 0;
+        assert_eq!(
+            "invalid configuration: exporter.poll_interval_secs must be greater than zero",
+            config.validate().unwrap_err().to_string()
+        );
+
+        config.exporter.poll_interval_secs = 60;
+        config.ingester.poll_interval_secs = 0;
+        assert_eq!(
+            "invalid configuration: ingester.poll_interval_secs must be greater than zero",
+            config.validate().unwrap_err().to_string()
+        );
+
+        config.ingester.poll_interval_secs = 60;
+        config.application.tls = Some(TlsConfig {
+            cert_path: "cert.pem".to_owned(),
+            key_path: "key.pem".to_owned(),
+            client_ca_path: None,
+            reload_interval_secs: 0,
+        });
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn redacted_toml() {
+        let config = get_configuration(None).unwrap();
+        let rendered = config.to_redacted_toml().unwrap();
+
+        assert!(rendered.contains("password = \"[REDACTED]\""));
+        assert!(!rendered.contains(&format!(
+            "password = \"{}\"",
+            config.database.password.expose_secret()
+        )));
+
+        // The rendered configuration is valid
+        let parsed: Config = toml::from_str(&rendered).unwrap();
+        assert_eq!(
+            config.application.listen_addr,
+            parsed.application.listen_addr
+        );
+    }
+}
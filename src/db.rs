@@ -1,7 +1,11 @@
+use sqlx::migrate::{Migrate, Migrator};
 use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
 use sqlx::ConnectOptions;
+use std::collections::HashSet;
 use std::str::FromStr;
 
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
 pub struct Db {
     pub pool: PgPool,
 }
@@ -13,15 +17,136 @@ impl Db {
 
         let pool = PgPoolOptions::new().connect_with(options).await?;
 
-        sqlx::migrate!("./migrations")
-            .run(&pool)
+        Ok(Self { pool })
+    }
+
+    /// Applies the pending migrations.
+    pub async fn migrate(&self) -> Result<()> {
+        MIGRATOR.run(&self.pool).await.map_err(Error::Migration)
+    }
+
+    /// Returns all the migrations known to this binary, oldest first.
+    pub async fn migrations(&self) -> Result<Vec<MigrationStatus>> {
+        let mut conn = self.pool.acquire().await?;
+
+        conn.ensure_migrations_table()
             .await
             .map_err(Error::Migration)?;
+        let applied: HashSet<i64> = conn
+            .list_applied_migrations()
+            .await
+            .map_err(Error::Migration)?
+            .into_iter()
+            .map(|migration| migration.version)
+            .collect();
 
-        Ok(Self { pool })
+        let migrations = MIGRATOR
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+            .map(|migration| MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                applied: applied.contains(&migration.version),
+            })
+            .collect();
+
+        Ok(migrations)
     }
 }
 
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+}
+
+/// Channel notified when new data points are committed.
+pub const NEW_DATA_CHANNEL: &str = "hdas_new_data";
+
+/// Notifies the listeners of [`NEW_DATA_CHANNEL`] that new data points are available.
+///
+/// The notification is only delivered once the transaction commits.
+pub async fn notify_new_data(tx: &mut Transaction) -> Result<()> {
+    sqlx::query!(r#"SELECT pg_notify($1, '')"#, NEW_DATA_CHANNEL)
+        .execute(tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Channel notified when a new upload is queued.
+pub const UPLOAD_CHANNEL: &str = "hdas_upload";
+
+/// Notifies the listeners of [`UPLOAD_CHANNEL`] that a new upload is queued.
+///
+/// The notification is only delivered once the transaction commits.
+pub async fn notify_new_upload(tx: &mut Transaction) -> Result<()> {
+    sqlx::query!(r#"SELECT pg_notify($1, '')"#, UPLOAD_CHANNEL)
+        .execute(tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Tables of the data points, exported in order of their id.
+pub const DATA_POINT_TABLES: [&str; 3] = [
+    "data_point_heart_rate",
+    "data_point_generic",
+    "data_point_sleep_analysis",
+];
+
+/// Records that `tx` is about to insert data points, returns the ID of the transaction.
+///
+/// Ids are allocated on insert but the rows are only visible once committed, so without this
+/// the exporter could move its cursor past the rows of a transaction still in progress.
+/// Must be called before the transaction inserts anything.
+pub async fn register_ingestion(pool: &PgPool, tx: &mut Transaction) -> Result<i64> {
+    let xid = sqlx::query!(r#"SELECT pg_current_xact_id()::text::bigint AS "xid!""#)
+        .fetch_one(&mut *tx)
+        .await?
+        .xid;
+
+    // Committed right away to be visible to the exporter
+    sqlx::query!(
+        r#"
+        INSERT INTO ingestion_in_progress(xid, table_name, last_id)
+        SELECT $1, t, COALESCE(pg_sequence_last_value(pg_get_serial_sequence(t, 'id')), 0)
+        FROM UNNEST($2::text[]) t"#,
+        xid,
+        &DATA_POINT_TABLES.map(ToOwned::to_owned) as _,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(xid)
+}
+
+/// Removes the record of an ingestion once its transaction is committed or rolled back.
+pub async fn unregister_ingestion(pool: &PgPool, xid: i64) -> Result<()> {
+    sqlx::query!("DELETE FROM ingestion_in_progress WHERE xid = $1", xid)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Removes the records of the ingestions interrupted before being unregistered.
+pub async fn delete_finished_ingestions<'e, E>(executor: E) -> Result<u64>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM ingestion_in_progress
+        WHERE pg_xact_status(xid::text::xid8) IS DISTINCT FROM 'in progress'"#
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
 pub type Transaction = sqlx::Transaction<'static, sqlx::Postgres>;
 
 #[derive(Debug, thiserror::Error)]
@@ -33,3 +158,90 @@ pub enum Error {
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use secrecy::ExposeSecret;
+    use sqlx::postgres::PgListener;
+
+    async fn get_db() -> Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_migrations() {
+        let db = get_db().await;
+        db.migrate().await.unwrap();
+
+        let migrations = db.migrations().await.unwrap();
+        assert!(!migrations.is_empty());
+        assert!(migrations.iter().all(|migration| migration.applied));
+        assert!(migrations
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+    }
+
+    #[tokio::test]
+    async fn test_notify_new_data() {
+        let db = get_db().await;
+
+        let mut listener = PgListener::connect_with(&db.pool).await.unwrap();
+        listener.listen(NEW_DATA_CHANNEL).await.unwrap();
+
+        let mut tx = db.pool.begin().await.unwrap();
+        notify_new_data(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let notification = listener.recv().await.unwrap();
+        assert_eq!(NEW_DATA_CHANNEL, notification.channel());
+    }
+
+    #[tokio::test]
+    async fn test_register_ingestion() {
+        let db = get_db().await;
+
+        let mut tx = db.pool.begin().await.unwrap();
+        let xid = register_ingestion(&db.pool, &mut tx).await.unwrap();
+
+        let nb_tables = sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM ingestion_in_progress WHERE xid = $1"#,
+            xid
+        )
+        .fetch_one(&db.pool)
+        .await
+        .unwrap()
+        .count;
+        assert_eq!(DATA_POINT_TABLES.len() as i64, nb_tables);
+
+        // Still in progress
+        delete_finished_ingestions(&db.pool).await.unwrap();
+        let nb_tables = sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM ingestion_in_progress WHERE xid = $1"#,
+            xid
+        )
+        .fetch_one(&db.pool)
+        .await
+        .unwrap()
+        .count;
+        assert_eq!(DATA_POINT_TABLES.len() as i64, nb_tables);
+
+        // Interrupted without being unregistered
+        tx.rollback().await.unwrap();
+        delete_finished_ingestions(&db.pool).await.unwrap();
+        let nb_tables = sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM ingestion_in_progress WHERE xid = $1"#,
+            xid
+        )
+        .fetch_one(&db.pool)
+        .await
+        .unwrap()
+        .count;
+        assert_eq!(0, nb_tables);
+    }
+}
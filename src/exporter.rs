@@ -1,5 +1,7 @@
+use crate::configuration::{ExporterConfig, PriorityResolutionConfig};
 use crate::db;
 use crate::shutdown::Shutdown;
+use sqlx::postgres::PgListener;
 use std::fmt;
 use std::fmt::Write;
 use std::io;
@@ -8,7 +10,8 @@ use std::sync::Arc;
 use std::time;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tracing::{debug, error, info};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -26,19 +29,42 @@ pub struct Exporter {
     db: Arc<db::Db>,
     addr: net::SocketAddr,
     stream: Option<TcpStream>,
+    poll_interval: time::Duration,
+    debounce_interval: time::Duration,
+    chunk_size: i64,
+    priority_window: time::Duration,
+    settle_delay: time::Duration,
 }
 
 impl Exporter {
-    pub fn new(db: Arc<db::Db>, addr: net::SocketAddr) -> Self {
+    pub fn new(
+        db: Arc<db::Db>,
+        addr: net::SocketAddr,
+        config: &ExporterConfig,
+        priority_resolution: &PriorityResolutionConfig,
+    ) -> Self {
         Self {
             db,
             addr,
             stream: None,
+            poll_interval: config.poll_interval(),
+            debounce_interval: config.debounce_interval(),
+            chunk_size: config.chunk_size,
+            priority_window: priority_resolution.window(),
+            settle_delay: priority_resolution.settle_delay(),
         }
     }
 
     pub async fn run(mut self, mut shutdown: Shutdown) -> Result<()> {
-        let mut interval = tokio::time::interval(time::Duration::from_secs(1));
+        let mut listener = PgListener::connect_with(&self.db.pool).await?;
+        listener.listen(db::NEW_DATA_CHANNEL).await?;
+
+        let mut interval = tokio::time::interval(self.poll_interval);
+
+        // Set when new data is available, the export is done once the deadline is reached.
+        let mut export_deadline: Option<Instant> = None;
+        // Set when the listener failed, it's not polled again until the deadline is reached.
+        let mut listen_retry_deadline: Option<Instant> = None;
 
         'outer_loop: loop {
             tokio::select! {
@@ -46,11 +72,33 @@ impl Exporter {
                     info!("exporter shutting down");
                     break 'outer_loop;
                 },
-                _ = interval.tick() => {
-                    match self.do_export().await {
-                        Ok(_) => {},
-                        Err(err) => error!(%err, "unable to export data"),
+                notification = listener.try_recv(), if listen_retry_deadline.is_none() => {
+                    match notification {
+                        Ok(Some(_)) => {},
+                        Ok(None) => {
+                            // The connection was lost and will be reestablished on the next call,
+                            // notifications may have been missed in the meantime so export anyway.
+                            warn!("lost connection to the database, reconnecting listener");
+                        },
+                        Err(err) => {
+                            error!(%err, "unable to receive notification");
+                            listen_retry_deadline = Some(Instant::now() + time::Duration::from_secs(1));
+                        },
                     }
+
+                    if export_deadline.is_none() {
+                        export_deadline = Some(Instant::now() + self.debounce_interval);
+                    }
+                },
+                _ = tokio::time::sleep_until(listen_retry_deadline.unwrap_or_else(Instant::now)), if listen_retry_deadline.is_some() => {
+                    listen_retry_deadline = None;
+                },
+                _ = tokio::time::sleep_until(export_deadline.unwrap_or_else(Instant::now)), if export_deadline.is_some() => {
+                    export_deadline = None;
+                    self.export().await;
+                },
+                _ = interval.tick() => {
+                    self.export().await;
                 },
             }
         }
@@ -62,6 +110,13 @@ impl Exporter {
         Ok(())
     }
 
+    async fn export(&mut self) {
+        match self.do_export().await {
+            Ok(_) => {}
+            Err(err) => error!(%err, "unable to export data"),
+        }
+    }
+
     async fn connect(&mut self) -> Result<&mut TcpStream> {
         match self.stream {
             Some(ref mut s) => Ok(s),
@@ -77,24 +132,24 @@ impl Exporter {
         }
     }
 
-    async fn do_export(&mut self) -> Result<()> {
-        let mut exported: usize = 0;
-        let mut commands_buffer = String::new();
-
-        // Generate all the export commands
+    async fn send(&mut self, commands_buffer: &str) -> Result<()> {
+        let stream = self.connect().await?;
 
-        self.gen_export_heart_rate_commands(&mut commands_buffer, &mut exported)
-            .await?;
-        for metric_type in ALL_GENERIC_METRIC_TYPES {
-            self.gen_export_generic_commands(&mut commands_buffer, metric_type, &mut exported)
-                .await?;
+        if let Err(err) = stream.write_all(commands_buffer.as_bytes()).await {
+            // Reconnect on the next export
+            self.stream = None;
+            return Err(err.into());
         }
-        self.gen_export_sleep_analysis_commands(&mut commands_buffer, &mut exported)
-            .await?;
 
-        // Send them to Victoria
-        let stream = self.connect().await?;
-        stream.write_all(commands_buffer.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn do_export(&mut self) -> Result<()> {
+        let mut exported: usize = 0;
+
+        exported += self.export_heart_rate().await?;
+        exported += self.export_generic().await?;
+        exported += self.export_sleep_analysis().await?;
 
         if exported > 0 {
             info!(exported = exported, "exported data points");
@@ -103,172 +158,377 @@ impl Exporter {
         Ok(())
     }
 
-    async fn gen_export_heart_rate_commands(
-        &mut self,
-        commands_buffer: &mut String,
-        exported: &mut usize,
-    ) -> Result<()> {
-        // Get the data and write the export commands to the buffer
+    async fn export_heart_rate(&mut self) -> Result<usize> {
+        const TABLE: &str = "data_point_heart_rate";
+
+        let metric_names = exported_metrics(TABLE);
+        let upper_bound = get_upper_bound(&self.db.pool, TABLE, self.settle_delay).await?;
+        let mut cursor = get_export_cursor(&self.db.pool, TABLE).await?;
+        let mut exported = 0;
+
+        while cursor < upper_bound {
+            let rows = fetch_heart_rate(
+                &self.db.pool,
+                &metric_names,
+                cursor,
+                upper_bound,
+                self.chunk_size,
+                self.priority_window,
+            )
+            .await?;
+
+            let mut commands_buffer = String::new();
+            for row in &rows {
+                writeln!(
+                    commands_buffer,
+                    "put health_data_heart_rate {} {} user={} source={}",
+                    row.date.unix_timestamp_nanos() / 1_000_000,
+                    row.max,
+                    row.user_name,
+                    source_tag_value(&row.source_name),
+                )?;
+            }
+            self.send(&commands_buffer).await?;
 
-        let rows = sqlx::query!(
-            r#"
-            SELECT d.id, d.max, d.date
-            FROM data_point_heart_rate d
-            INNER JOIN metric m ON d.metric_id = m.id
-            WHERE m.name = 'heart_rate'
-            AND d.exported = false"#,
-        )
-        .fetch_all(&self.db.pool)
-        .await?;
-
-        let mut ids = Vec::<i64>::new();
-        for row in rows {
-            writeln!(
-                commands_buffer,
-                "put health_data_heart_rate {} {} ",
-                row.date.unix_timestamp_nanos() / 1_000_000,
-                row.max
-            )?;
-
-            ids.push(row.id);
+            exported += rows.len();
+            cursor = match rows.last() {
+                Some(row) if rows.len() as i64 == self.chunk_size => row.id,
+                // Nothing left to export below the upper bound
+                _ => upper_bound,
+            };
+
+            set_export_cursor(&self.db.pool, TABLE, cursor).await?;
         }
 
-        // Mark all data points as exported
+        Ok(exported)
+    }
+
+    async fn export_generic(&mut self) -> Result<usize> {
+        const TABLE: &str = "data_point_generic";
 
-        *exported += ids.len();
+        let metric_names = exported_metrics(TABLE);
 
-        let mut tx = self.db.pool.begin().await?;
-        for id in ids {
-            sqlx::query!(
-                r#"
-                UPDATE data_point_heart_rate
-                SET exported = true
-                WHERE id = $1"#,
-                id,
+        let upper_bound = get_upper_bound(&self.db.pool, TABLE, self.settle_delay).await?;
+        let mut cursor = get_export_cursor(&self.db.pool, TABLE).await?;
+        let mut exported = 0;
+
+        while cursor < upper_bound {
+            let rows = fetch_generic(
+                &self.db.pool,
+                &metric_names,
+                cursor,
+                upper_bound,
+                self.chunk_size,
+                self.priority_window,
             )
-            .execute(&mut tx)
             .await?;
-        }
-        tx.commit().await?;
 
-        Ok(())
-    }
-
-    async fn gen_export_generic_commands(
-        &mut self,
-        commands_buffer: &mut String,
-        metric_type: GenericMetricType,
-        exported: &mut usize,
-    ) -> Result<()> {
-        let metric_name = metric_type.to_string();
+            let mut commands_buffer = String::new();
+            for row in &rows {
+                writeln!(
+                    commands_buffer,
+                    "put health_data_{} {} {} user={} source={}",
+                    row.name,
+                    row.date.unix_timestamp_nanos() / 1_000_000,
+                    row.quantity,
+                    row.user_name,
+                    source_tag_value(&row.source_name),
+                )?;
+            }
+            self.send(&commands_buffer).await?;
 
-        // Get the data and write the export commands to the buffer
+            exported += rows.len();
+            cursor = match rows.last() {
+                Some(row) if rows.len() as i64 == self.chunk_size => row.id,
+                // Nothing left to export below the upper bound
+                _ => upper_bound,
+            };
 
-        let rows = sqlx::query!(
-            r#"
-            SELECT d.id, d.quantity, d.date
-            FROM data_point_generic d
-            INNER JOIN metric m ON d.metric_id = m.id
-            WHERE m.name = $1
-            AND d.exported = false
-            "#,
-            metric_name,
-        )
-        .fetch_all(&self.db.pool)
-        .await?;
-
-        let mut ids = Vec::<i64>::new();
-        for row in rows {
-            writeln!(
-                commands_buffer,
-                "put health_data_{} {} {} ",
-                metric_name,
-                row.date.unix_timestamp_nanos() / 1_000_000,
-                row.quantity
-            )?;
-
-            ids.push(row.id);
+            set_export_cursor(&self.db.pool, TABLE, cursor).await?;
         }
 
-        // Mark all data points as exported
+        Ok(exported)
+    }
 
-        *exported += ids.len();
+    async fn export_sleep_analysis(&mut self) -> Result<usize> {
+        const TABLE: &str = "data_point_sleep_analysis";
 
-        let mut tx = self.db.pool.begin().await?;
-        for id in ids {
-            sqlx::query!(
+        let metric_names = exported_metrics(TABLE);
+        let upper_bound = get_upper_bound(&self.db.pool, TABLE, self.settle_delay).await?;
+        let mut cursor = get_export_cursor(&self.db.pool, TABLE).await?;
+        let mut exported = 0;
+
+        while cursor < upper_bound {
+            let rows = sqlx::query!(
                 r#"
-                UPDATE data_point_generic
-                SET exported = true
-                WHERE id = $1"#,
-                id,
+                SELECT d.id, d.in_bed, d.asleep, d.date, u.name AS user_name
+                FROM data_point_sleep_analysis d
+                INNER JOIN metric m ON d.metric_id = m.id
+                INNER JOIN app_user u ON d.user_id = u.id
+                WHERE m.name = ANY($1)
+                AND d.id > $2 AND d.id <= $3
+                ORDER BY d.id
+                LIMIT $4"#,
+                &metric_names,
+                cursor,
+                upper_bound,
+                self.chunk_size,
             )
-            .execute(&mut tx)
+            .fetch_all(&self.db.pool)
             .await?;
+
+            let mut commands_buffer = String::new();
+            for row in &rows {
+                writeln!(
+                    commands_buffer,
+                    "put health_data_sleep_analysis {} {} type=in_bed user={}",
+                    row.date.unix_timestamp_nanos() / 1_000_000,
+                    row.in_bed,
+                    row.user_name,
+                )?;
+                writeln!(
+                    commands_buffer,
+                    "put health_data_sleep_analysis {} {} type=asleep user={}",
+                    row.date.unix_timestamp_nanos() / 1_000_000,
+                    row.asleep,
+                    row.user_name,
+                )?;
+            }
+            self.send(&commands_buffer).await?;
+
+            exported += rows.len();
+            cursor = match rows.last() {
+                Some(row) if rows.len() as i64 == self.chunk_size => row.id,
+                // Nothing left to export below the upper bound
+                _ => upper_bound,
+            };
+
+            set_export_cursor(&self.db.pool, TABLE, cursor).await?;
         }
-        tx.commit().await?;
 
-        Ok(())
+        Ok(exported)
     }
+}
 
-    async fn gen_export_sleep_analysis_commands(
-        &mut self,
-        commands_buffer: &mut String,
-        exported: &mut usize,
-    ) -> Result<()> {
-        // Get the data and write the export commands to the buffer
+struct HeartRateRow {
+    id: i64,
+    max: f64,
+    date: ::time::OffsetDateTime,
+    user_name: String,
+    source_name: String,
+}
 
-        let rows = sqlx::query!(
-            r#"
-            SELECT d.id, d.in_bed, d.asleep, d.date
-            FROM data_point_sleep_analysis d
-            INNER JOIN metric m ON d.metric_id = m.id
-            WHERE m.name = 'sleep_analysis'
-            AND d.exported = false"#,
+/// Returns at most `limit` heart rate data points with an id in the range `(cursor, upper_bound]`.
+///
+/// Data points overlapping a data point of a source with a higher priority are skipped.
+/// The data points are only exported once settled, see [`get_upper_bound`], so a data point
+/// arriving later than the settle delay doesn't hide the ones already exported.
+async fn fetch_heart_rate<'e, E>(
+    executor: E,
+    metric_names: &[String],
+    cursor: i64,
+    upper_bound: i64,
+    limit: i64,
+    priority_window: time::Duration,
+) -> Result<Vec<HeartRateRow>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let rows = sqlx::query_as!(
+        HeartRateRow,
+        r#"
+        SELECT d.id, d.max, d.date, u.name AS user_name, s.name AS source_name
+        FROM data_point_heart_rate d
+        INNER JOIN metric m ON d.metric_id = m.id
+        INNER JOIN app_user u ON d.user_id = u.id
+        INNER JOIN source s ON d.source_id = s.id
+        WHERE m.name = ANY($1)
+        AND d.id > $2 AND d.id <= $3
+        AND NOT EXISTS (
+          SELECT 1 FROM data_point_heart_rate o
+          INNER JOIN source os ON o.source_id = os.id
+          WHERE o.metric_id = d.metric_id
+          AND o.date BETWEEN d.date - make_interval(secs => $5) AND d.date + make_interval(secs => $5)
+          AND source_rank(m.name, os.name) < source_rank(m.name, s.name)
         )
-        .fetch_all(&self.db.pool)
-        .await?;
-
-        let mut ids = Vec::<i64>::new();
-        for row in rows {
-            writeln!(
-                commands_buffer,
-                "put health_data_sleep_analysis {} {} type=in_bed",
-                row.date.unix_timestamp_nanos() / 1_000_000,
-                row.in_bed,
-            )?;
-            writeln!(
-                commands_buffer,
-                "put health_data_sleep_analysis {} {} type=asleep",
-                row.date.unix_timestamp_nanos() / 1_000_000,
-                row.asleep,
-            )?;
-
-            ids.push(row.id);
-        }
+        ORDER BY d.id
+        LIMIT $4"#,
+        metric_names,
+        cursor,
+        upper_bound,
+        limit,
+        priority_window.as_secs_f64(),
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows)
+}
 
-        // Mark all data points as exported
+struct GenericRow {
+    id: i64,
+    name: String,
+    quantity: f64,
+    date: ::time::OffsetDateTime,
+    user_name: String,
+    source_name: String,
+}
 
-        *exported += ids.len();
+/// Returns at most `limit` generic data points with an id in the range `(cursor, upper_bound]`.
+///
+/// Same as for the heart rate, only the sources with the highest priority are exported.
+async fn fetch_generic<'e, E>(
+    executor: E,
+    metric_names: &[String],
+    cursor: i64,
+    upper_bound: i64,
+    limit: i64,
+    priority_window: time::Duration,
+) -> Result<Vec<GenericRow>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let rows = sqlx::query_as!(
+        GenericRow,
+        r#"
+        SELECT d.id, m.name, d.quantity, d.date, u.name AS user_name, s.name AS source_name
+        FROM data_point_generic d
+        INNER JOIN metric m ON d.metric_id = m.id
+        INNER JOIN app_user u ON d.user_id = u.id
+        INNER JOIN source s ON d.source_id = s.id
+        WHERE m.name = ANY($1)
+        AND d.id > $2 AND d.id <= $3
+        AND NOT EXISTS (
+          SELECT 1 FROM data_point_generic o
+          INNER JOIN source os ON o.source_id = os.id
+          WHERE o.metric_id = d.metric_id
+          AND o.date BETWEEN d.date - make_interval(secs => $5) AND d.date + make_interval(secs => $5)
+          AND source_rank(m.name, os.name) < source_rank(m.name, s.name)
+        )
+        ORDER BY d.id
+        LIMIT $4"#,
+        metric_names,
+        cursor,
+        upper_bound,
+        limit,
+        priority_window.as_secs_f64(),
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows)
+}
 
-        let mut tx = self.db.pool.begin().await?;
-        for id in ids {
-            sqlx::query!(
-                r#"
-                UPDATE data_point_sleep_analysis
-                SET exported = true
-                WHERE id = $1"#,
-                id,
-            )
-            .execute(&mut tx)
-            .await?;
-        }
-        tx.commit().await?;
+/// Returns the name of a source usable as a tag value.
+///
+/// Tag values can't contain spaces and only some punctuation, the other characters are replaced.
+fn source_tag_value(name: &str) -> String {
+    if name.is_empty() {
+        return "unknown".to_owned();
+    }
 
-        Ok(())
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '/') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Name of the sink in the export cursors.
+const SINK: &str = "victoria";
+
+/// Returns the id up to which the rows of `table` can safely be exported.
+///
+/// Identity values are allocated when a row is inserted, not when its transaction commits,
+/// so a row with a lower id can become visible after a row with a higher id.
+/// The bound stays below the ids allocated by the ingestions still in progress, see [`db::register_ingestion`].
+///
+/// It also stays below the data points of the metrics with a source priority inserted less than
+/// `settle_delay` ago, a source with a higher priority can still sync an overlapping data point.
+async fn get_upper_bound<'e, E>(
+    executor: E,
+    table: &'static str,
+    settle_delay: time::Duration,
+) -> Result<i64>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let (upper_bound,): (Option<i64>,) = sqlx::query_as(&format!(
+        r#"
+        SELECT LEAST(
+          (SELECT max(id) FROM {table}),
+          (
+            SELECT min(last_id) FROM ingestion_in_progress
+            WHERE table_name = $1 AND pg_xact_status(xid::text::xid8) = 'in progress'
+          ),
+          (
+            SELECT min(d.id) - 1 FROM {table} d
+            INNER JOIN metric m ON d.metric_id = m.id
+            WHERE d.created_at > now() - make_interval(secs => $2)
+            AND EXISTS (SELECT 1 FROM source_priority p WHERE p.metric_name = m.name)
+          )
+        )"#,
+    ))
+    .bind(table)
+    .bind(settle_delay.as_secs_f64())
+    .fetch_one(executor)
+    .await?;
+
+    Ok(upper_bound.unwrap_or(0))
+}
+
+/// Returns the names of the metrics exported from a data point table.
+///
+/// The data points of the other metrics are never exported, so they're never cleaned either.
+pub fn exported_metrics(table: &str) -> Vec<String> {
+    match table {
+        "data_point_heart_rate" => vec!["heart_rate".to_owned()],
+        "data_point_generic" => ALL_GENERIC_METRIC_TYPES
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        "data_point_sleep_analysis" => vec!["sleep_analysis".to_owned()],
+        _ => Vec::new(),
     }
 }
 
+async fn get_export_cursor<'e, E>(executor: E, table: &str) -> Result<i64>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let record = sqlx::query!(
+        r#"SELECT last_id FROM export_cursor WHERE table_name = $1 AND sink = $2"#,
+        table,
+        SINK,
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(record.map(|record| record.last_id).unwrap_or(0))
+}
+
+async fn set_export_cursor<'e, E>(executor: E, table: &str, last_id: i64) -> Result<()>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    sqlx::query!(
+        r#"
+        INSERT INTO export_cursor(table_name, sink, last_id) VALUES($1, $2, $3)
+        ON CONFLICT (table_name, sink) DO UPDATE SET last_id = excluded.last_id"#,
+        table,
+        SINK,
+        last_id,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
 const ALL_GENERIC_METRIC_TYPES: [GenericMetricType; 5] = [
     GenericMetricType::WeightBodyMass,
     GenericMetricType::WalkingHeartRateAverage,
@@ -296,3 +556,181 @@ impl fmt::Display for GenericMetricType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use crate::source;
+    use crate::user;
+    use ::time::macros::datetime;
+    use secrecy::ExposeSecret;
+    use std::collections::BTreeMap;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn source_tag_values() {
+        assert_eq!("unknown", source_tag_value(""));
+        assert_eq!(
+            "Apple_Watch_de_José",
+            source_tag_value("Apple Watch de José")
+        );
+        assert_eq!("Withings_Body_", source_tag_value("Withings Body+"));
+        assert_eq!("Health.app", source_tag_value("Health.app"));
+    }
+
+    #[tokio::test]
+    async fn test_export_cursor() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let cursor = get_export_cursor(&mut tx, "foobar").await.unwrap();
+        assert_eq!(0, cursor);
+
+        set_export_cursor(&mut tx, "foobar", 200).await.unwrap();
+        set_export_cursor(&mut tx, "foobar", 300).await.unwrap();
+
+        let cursor = get_export_cursor(&mut tx, "foobar").await.unwrap();
+        assert_eq!(300, cursor);
+    }
+
+    #[tokio::test]
+    async fn test_upper_bound_below_ingestions_in_progress() {
+        let db = get_db().await;
+
+        let mut ingestion_tx = db.pool.begin().await.unwrap();
+        let xid = db::register_ingestion(&db.pool, &mut ingestion_tx)
+            .await
+            .unwrap();
+        let last_id = sqlx::query!(
+            r#"
+            SELECT last_id FROM ingestion_in_progress
+            WHERE xid = $1 AND table_name = 'data_point_generic'"#,
+            xid
+        )
+        .fetch_one(&db.pool)
+        .await
+        .unwrap()
+        .last_id;
+
+        let upper_bound = get_upper_bound(&db.pool, "data_point_generic", time::Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(upper_bound <= last_id, "{} > {}", upper_bound, last_id);
+
+        ingestion_tx.rollback().await.unwrap();
+        db::unregister_ingestion(&db.pool, xid).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_source_priority_settles() {
+        const SETTLE_DELAY: time::Duration = time::Duration::from_secs(600);
+        const WINDOW: time::Duration = time::Duration::from_secs(60);
+
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let mut priorities = BTreeMap::new();
+        priorities.insert(
+            "weight_body_mass".to_owned(),
+            vec!["Watch".to_owned(), "iPhone".to_owned()],
+        );
+        source::sync_priorities(&mut tx, &priorities).await.unwrap();
+
+        let user_id = user::get_or_create_user(&mut tx, "test-user")
+            .await
+            .unwrap();
+        let metric = sqlx::query!(
+            r#"INSERT INTO metric(user_id, name, units) VALUES($1, 'weight_body_mass', 'kg') RETURNING id"#,
+            user_id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+
+        async fn insert(
+            tx: &mut db::Transaction,
+            metric_id: i64,
+            user_id: i64,
+            date: ::time::OffsetDateTime,
+            source_name: &str,
+        ) -> i64 {
+            sqlx::query!(
+                r#"
+                WITH s AS (
+                  INSERT INTO source(name) VALUES($4)
+                  ON CONFLICT (name) DO UPDATE SET name = excluded.name
+                  RETURNING id
+                )
+                INSERT INTO data_point_generic(metric_id, user_id, date, quantity, source_id)
+                SELECT $1, $2, $3, 70, s.id FROM s
+                RETURNING id"#,
+                metric_id,
+                user_id,
+                date,
+                source_name,
+            )
+            .fetch_one(tx)
+            .await
+            .unwrap()
+            .id
+        }
+
+        // The phone syncs first, its data point isn't exported until it settles
+        let iphone_id = insert(
+            &mut tx,
+            metric.id,
+            user_id,
+            datetime!(2022-08-01 10:00 UTC),
+            "iPhone de Test",
+        )
+        .await;
+
+        let upper_bound = get_upper_bound(&mut tx, "data_point_generic", SETTLE_DELAY)
+            .await
+            .unwrap();
+        assert!(upper_bound < iphone_id, "{} >= {}", upper_bound, iphone_id);
+
+        // The watch syncs a bit later, with a data point a few seconds apart
+        let watch_id = insert(
+            &mut tx,
+            metric.id,
+            user_id,
+            datetime!(2022-08-01 10:00:20 UTC),
+            "Apple Watch de Test",
+        )
+        .await;
+
+        sqlx::query!(
+            r#"UPDATE data_point_generic SET created_at = now() - interval '1 hour' WHERE metric_id = $1"#,
+            metric.id,
+        )
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let rows = fetch_generic(
+            &mut tx,
+            &exported_metrics("data_point_generic"),
+            iphone_id - 1,
+            watch_id,
+            100,
+            WINDOW,
+        )
+        .await
+        .unwrap();
+        let ids: Vec<_> = rows
+            .iter()
+            .filter(|row| row.user_name == "test-user")
+            .map(|row| row.id)
+            .collect();
+        assert_eq!(vec![watch_id], ids);
+    }
+}
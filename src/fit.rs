@@ -0,0 +1,603 @@
+use crate::health_data::{GenericDataPoint, HeartRateDataPoint, MetricDataPoint};
+use crate::workout::{Lap, Record, Workout};
+use fitparser::profile::MesgNum;
+use fitparser::{FitDataRecord, Value};
+use std::collections::HashMap;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::warn;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid FIT file: {0}")]
+    Parse(#[from] fitparser::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Offset of the FIT epoch, 1989-12-31 00:00:00 UTC, from the Unix epoch.
+const FIT_EPOCH: i64 = 631_065_600;
+
+/// Returns true if the data starts like a FIT file.
+///
+/// Only the header is checked, the file can still fail to be decoded.
+pub fn is_fit_file(data: &[u8]) -> bool {
+    data.len() >= 12 && matches!(data[0], 12 | 14) && &data[8..12] == b".FIT"
+}
+
+/// A data point decoded from a monitoring file.
+#[derive(Debug, PartialEq)]
+pub struct DataPoint {
+    pub metric: &'static str,
+    pub units: &'static str,
+    pub data_point: MetricDataPoint,
+}
+
+impl DataPoint {
+    /// Returns the data point like Health Auto Export sends it, to be kept if it's rejected.
+    pub fn to_json(&self) -> String {
+        let format_date = |date: OffsetDateTime| date.format(&Rfc3339).unwrap_or_default();
+
+        let value = match &self.data_point {
+            MetricDataPoint::HeartRate(data_point) => serde_json::json!({
+                "date": format_date(data_point.date),
+                "Min": data_point.min,
+                "Max": data_point.max,
+                "Avg": data_point.avg,
+                "source": data_point.source,
+            }),
+            MetricDataPoint::Generic(data_point) => serde_json::json!({
+                "date": format_date(data_point.date),
+                "qty": data_point.quantity,
+                "source": data_point.source,
+            }),
+            MetricDataPoint::SleepAnalysis(_) => serde_json::Value::Null,
+        };
+
+        value.to_string()
+    }
+}
+
+/// What's imported from a FIT file.
+///
+/// The monitoring files have data points, the activity files have workouts.
+#[derive(Debug, Default, PartialEq)]
+pub struct FitFile {
+    /// The device that recorded the file.
+    pub source: String,
+    pub data_points: Vec<DataPoint>,
+    pub workouts: Vec<Workout>,
+}
+
+/// Decodes a FIT file.
+///
+/// The monitoring messages give the heart rate, the steps and the stress level data points.
+/// Every session of an activity is a workout, with the laps and records made during the session.
+pub fn decode(data: &[u8]) -> Result<FitFile> {
+    let messages = fitparser::from_bytes(data)?;
+
+    let mut decoder = Decoder::default();
+    for message in &messages {
+        decoder.decode(message);
+    }
+
+    Ok(decoder.finish())
+}
+
+#[derive(Default)]
+struct Decoder {
+    source: Option<String>,
+    /// The last full timestamp, the monitoring messages only have its lower 16 bits.
+    last_timestamp: Option<OffsetDateTime>,
+    /// The steps are counted per activity type since the start of the file.
+    cumulative_steps: HashMap<String, f64>,
+    data_points: Vec<DataPoint>,
+    sessions: Vec<Workout>,
+    laps: Vec<Lap>,
+    records: Vec<Record>,
+}
+
+impl Decoder {
+    fn decode(&mut self, message: &FitDataRecord) {
+        let timestamp = timestamp_field(message, "timestamp");
+        if timestamp.is_some() {
+            self.last_timestamp = timestamp;
+        }
+
+        match message.kind() {
+            MesgNum::FileId => self.decode_file_id(message),
+            MesgNum::Monitoring => self.decode_monitoring(message, timestamp),
+            MesgNum::StressLevel => self.decode_stress_level(message),
+            MesgNum::Session => match decode_session(message) {
+                Some(session) => self.sessions.push(session),
+                None => warn!("skipped session without start time or duration"),
+            },
+            MesgNum::Lap => self.laps.extend(decode_lap(message)),
+            MesgNum::Record => {
+                if let Some(date) = timestamp {
+                    self.records.push(decode_record(message, date));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn decode_file_id(&mut self, message: &FitDataRecord) {
+        let manufacturer = string_field(message, "manufacturer");
+        let product = string_field(message, "garmin_product")
+            .map(str::to_owned)
+            .or_else(|| number_field(message, "product").map(|product| product.to_string()));
+
+        self.source = match (manufacturer, product) {
+            (Some(manufacturer), Some(product)) => Some(format!("{} {}", manufacturer, product)),
+            (Some(manufacturer), None) => Some(manufacturer.to_owned()),
+            (None, product) => product,
+        };
+    }
+
+    fn decode_monitoring(&mut self, message: &FitDataRecord, timestamp: Option<OffsetDateTime>) {
+        // The messages with a duration summarize a period, their values are already in the other messages
+        if field(message, "duration_min").is_some() {
+            return;
+        }
+
+        let date = match timestamp.or_else(|| self.resolve_timestamp_16(message)) {
+            Some(date) => date,
+            None => return,
+        };
+        self.last_timestamp = Some(date);
+
+        if let Some(heart_rate) = number_field(message, "heart_rate").filter(|value| *value > 0.0) {
+            self.data_points.push(DataPoint {
+                metric: "heart_rate",
+                units: "count/min",
+                data_point: MetricDataPoint::HeartRate(HeartRateDataPoint {
+                    date,
+                    min: heart_rate,
+                    max: heart_rate,
+                    avg: heart_rate,
+                    source: None,
+                }),
+            });
+        }
+
+        if let Some(steps) = self.decode_steps(message) {
+            self.data_points.push(DataPoint {
+                metric: "step_count",
+                units: "count",
+                data_point: MetricDataPoint::Generic(GenericDataPoint {
+                    date,
+                    quantity: steps,
+                    source: None,
+                }),
+            });
+        }
+    }
+
+    /// Returns the steps made since the previous message of the same activity type.
+    ///
+    /// The steps of a monitoring message are a running total of the activity type, which is reset
+    /// every day.
+    fn decode_steps(&mut self, message: &FitDataRecord) -> Option<f64> {
+        let activity_type = string_field(message, "activity_type")?;
+
+        // The cycles of walking and running are strides, a step is half a cycle
+        let total = match number_field(message, "steps") {
+            Some(steps) => steps,
+            None if matches!(activity_type, "walking" | "running") => {
+                number_field(message, "cycles")? * 2.0
+            }
+            None => return None,
+        };
+
+        let previous = self
+            .cumulative_steps
+            .insert(activity_type.to_owned(), total)
+            .unwrap_or_default();
+        let steps = if total >= previous {
+            total - previous
+        } else {
+            total
+        };
+
+        Some(steps).filter(|steps| *steps > 0.0)
+    }
+
+    fn decode_stress_level(&mut self, message: &FitDataRecord) {
+        let date = timestamp_field(message, "stress_level_time");
+        let value = number_field(message, "stress_level_value");
+
+        // Negative values mean that the stress couldn't be measured, for example during an activity
+        if let (Some(date), Some(value)) = (date, value.filter(|value| *value >= 0.0)) {
+            self.data_points.push(DataPoint {
+                metric: "stress_level",
+                units: "count",
+                data_point: MetricDataPoint::Generic(GenericDataPoint {
+                    date,
+                    quantity: value,
+                    source: None,
+                }),
+            });
+        }
+    }
+
+    /// Returns the date of a message with a `timestamp_16` field, relative to the last full timestamp.
+    fn resolve_timestamp_16(&self, message: &FitDataRecord) -> Option<OffsetDateTime> {
+        let timestamp_16 = number_field(message, "timestamp_16")? as i64;
+        let last = self.last_timestamp?.unix_timestamp() - FIT_EPOCH;
+
+        let resolved = last + ((timestamp_16 - last) & 0xFFFF);
+        OffsetDateTime::from_unix_timestamp(resolved + FIT_EPOCH).ok()
+    }
+
+    fn finish(mut self) -> FitFile {
+        let source = self.source.unwrap_or_else(|| "fit".to_owned());
+
+        for data_point in &mut self.data_points {
+            match &mut data_point.data_point {
+                MetricDataPoint::HeartRate(data_point) => data_point.source = Some(source.clone()),
+                MetricDataPoint::Generic(data_point) => data_point.source = Some(source.clone()),
+                MetricDataPoint::SleepAnalysis(_) => {}
+            }
+        }
+
+        // The laps and records come before their session in the file, they're matched by time
+        let mut workouts = self.sessions;
+        for lap in self.laps {
+            if let Some(workout) = find_workout(&mut workouts, lap.start_time) {
+                workout.laps.push(lap);
+            }
+        }
+        for record in self.records {
+            if let Some(workout) = find_workout(&mut workouts, record.date) {
+                workout.records.push(record);
+            }
+        }
+
+        FitFile {
+            source,
+            data_points: self.data_points,
+            workouts,
+        }
+    }
+}
+
+fn find_workout(workouts: &mut [Workout], date: OffsetDateTime) -> Option<&mut Workout> {
+    workouts
+        .iter_mut()
+        .find(|workout| workout.start_time <= date && date <= workout.end_time)
+}
+
+fn decode_session(message: &FitDataRecord) -> Option<Workout> {
+    let start_time = timestamp_field(message, "start_time")?;
+    let elapsed_time = number_field(message, "total_elapsed_time")?;
+
+    Some(Workout {
+        sport: string_field(message, "sport")
+            .unwrap_or("generic")
+            .to_owned(),
+        sub_sport: string_field(message, "sub_sport").map(str::to_owned),
+        start_time,
+        end_time: start_time + time::Duration::seconds_f64(elapsed_time),
+        elapsed_time,
+        timer_time: number_field(message, "total_timer_time"),
+        distance: number_field(message, "total_distance"),
+        calories: number_field(message, "total_calories"),
+        avg_heart_rate: number_field(message, "avg_heart_rate"),
+        max_heart_rate: number_field(message, "max_heart_rate"),
+        laps: Vec::new(),
+        records: Vec::new(),
+    })
+}
+
+fn decode_lap(message: &FitDataRecord) -> Option<Lap> {
+    let start_time = timestamp_field(message, "start_time")?;
+    let elapsed_time = number_field(message, "total_elapsed_time")?;
+
+    Some(Lap {
+        start_time,
+        end_time: start_time + time::Duration::seconds_f64(elapsed_time),
+        elapsed_time,
+        timer_time: number_field(message, "total_timer_time"),
+        distance: number_field(message, "total_distance"),
+        calories: number_field(message, "total_calories"),
+        avg_heart_rate: number_field(message, "avg_heart_rate"),
+        max_heart_rate: number_field(message, "max_heart_rate"),
+    })
+}
+
+fn decode_record(message: &FitDataRecord, date: OffsetDateTime) -> Record {
+    Record {
+        date,
+        heart_rate: number_field(message, "heart_rate"),
+        latitude: number_field(message, "position_lat").map(semicircles_to_degrees),
+        longitude: number_field(message, "position_long").map(semicircles_to_degrees),
+        altitude: number_field(message, "enhanced_altitude")
+            .or_else(|| number_field(message, "altitude")),
+        distance: number_field(message, "distance"),
+        speed: number_field(message, "enhanced_speed").or_else(|| number_field(message, "speed")),
+        cadence: number_field(message, "cadence"),
+        power: number_field(message, "power"),
+    }
+}
+
+fn semicircles_to_degrees(semicircles: f64) -> f64 {
+    semicircles * 180.0 / 2f64.powi(31)
+}
+
+fn field<'a>(message: &'a FitDataRecord, name: &str) -> Option<&'a Value> {
+    message
+        .fields()
+        .iter()
+        .find(|field| field.name() == name)
+        .map(|field| field.value())
+}
+
+fn number_field(message: &FitDataRecord, name: &str) -> Option<f64> {
+    match field(message, name)? {
+        Value::Byte(value) | Value::Enum(value) | Value::UInt8(value) | Value::UInt8z(value) => {
+            Some(f64::from(*value))
+        }
+        Value::SInt8(value) => Some(f64::from(*value)),
+        Value::SInt16(value) => Some(f64::from(*value)),
+        Value::UInt16(value) | Value::UInt16z(value) => Some(f64::from(*value)),
+        Value::SInt32(value) => Some(f64::from(*value)),
+        Value::UInt32(value) | Value::UInt32z(value) => Some(f64::from(*value)),
+        Value::SInt64(value) => Some(*value as f64),
+        Value::UInt64(value) | Value::UInt64z(value) => Some(*value as f64),
+        Value::Float32(value) => Some(f64::from(*value)),
+        Value::Float64(value) => Some(*value),
+        Value::Timestamp(_) | Value::String(_) | Value::Array(_) => None,
+    }
+    .filter(|value| value.is_finite())
+}
+
+fn string_field<'a>(message: &'a FitDataRecord, name: &str) -> Option<&'a str> {
+    match field(message, name)? {
+        Value::String(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn timestamp_field(message: &FitDataRecord, name: &str) -> Option<OffsetDateTime> {
+    match field(message, name)? {
+        Value::Timestamp(value) => OffsetDateTime::from_unix_timestamp(value.timestamp()).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fitparser::FitDataField;
+    use time::macros::datetime;
+
+    fn message(kind: MesgNum, fields: Vec<(&str, Value)>) -> FitDataRecord {
+        let mut message = FitDataRecord::new(kind);
+        for (name, value) in fields {
+            message.push(FitDataField::new(name.to_owned(), 0, value, String::new()));
+        }
+        message
+    }
+
+    fn timestamp(date: OffsetDateTime) -> Value {
+        use chrono::TimeZone;
+        Value::Timestamp(
+            chrono::Local
+                .timestamp_opt(date.unix_timestamp(), 0)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn decode_monitoring() {
+        let start = datetime!(2023-03-20 00:00 UTC);
+        let fit_start = (start.unix_timestamp() - FIT_EPOCH) as u16;
+
+        let mut decoder = Decoder::default();
+        let messages = vec![
+            message(
+                MesgNum::FileId,
+                vec![
+                    ("manufacturer", Value::String("garmin".to_owned())),
+                    ("garmin_product", Value::String("fenix7".to_owned())),
+                ],
+            ),
+            message(
+                MesgNum::MonitoringInfo,
+                vec![("timestamp", timestamp(start))],
+            ),
+            message(
+                MesgNum::Monitoring,
+                vec![
+                    ("timestamp_16", Value::UInt16(fit_start.wrapping_add(60))),
+                    ("activity_type", Value::String("walking".to_owned())),
+                    ("cycles", Value::Float64(50.0)),
+                    ("heart_rate", Value::UInt8(62)),
+                ],
+            ),
+            message(
+                MesgNum::Monitoring,
+                vec![
+                    ("timestamp_16", Value::UInt16(fit_start.wrapping_add(120))),
+                    ("activity_type", Value::String("walking".to_owned())),
+                    ("cycles", Value::Float64(80.0)),
+                ],
+            ),
+            message(
+                MesgNum::Monitoring,
+                vec![
+                    ("timestamp", timestamp(start + time::Duration::days(1))),
+                    ("activity_type", Value::String("walking".to_owned())),
+                    ("steps", Value::UInt32(3000)),
+                    ("duration_min", Value::UInt16(1440)),
+                ],
+            ),
+            message(
+                MesgNum::StressLevel,
+                vec![
+                    ("stress_level_value", Value::SInt16(-1)),
+                    ("stress_level_time", timestamp(start)),
+                ],
+            ),
+            message(
+                MesgNum::StressLevel,
+                vec![
+                    ("stress_level_value", Value::SInt16(25)),
+                    (
+                        "stress_level_time",
+                        timestamp(start + time::Duration::minutes(3)),
+                    ),
+                ],
+            ),
+        ];
+        for message in &messages {
+            decoder.decode(message);
+        }
+        let fit_file = decoder.finish();
+
+        let source = Some("garmin fenix7".to_owned());
+        let exp = vec![
+            DataPoint {
+                metric: "heart_rate",
+                units: "count/min",
+                data_point: MetricDataPoint::HeartRate(HeartRateDataPoint {
+                    date: datetime!(2023-03-20 00:01 UTC),
+                    min: 62.0,
+                    max: 62.0,
+                    avg: 62.0,
+                    source: source.clone(),
+                }),
+            },
+            DataPoint {
+                metric: "step_count",
+                units: "count",
+                data_point: MetricDataPoint::Generic(GenericDataPoint {
+                    date: datetime!(2023-03-20 00:01 UTC),
+                    quantity: 100.0,
+                    source: source.clone(),
+                }),
+            },
+            DataPoint {
+                metric: "step_count",
+                units: "count",
+                data_point: MetricDataPoint::Generic(GenericDataPoint {
+                    date: datetime!(2023-03-20 00:02 UTC),
+                    quantity: 60.0,
+                    source: source.clone(),
+                }),
+            },
+            DataPoint {
+                metric: "stress_level",
+                units: "count",
+                data_point: MetricDataPoint::Generic(GenericDataPoint {
+                    date: datetime!(2023-03-20 00:03 UTC),
+                    quantity: 25.0,
+                    source: source.clone(),
+                }),
+            },
+        ];
+        assert_eq!(exp, fit_file.data_points);
+        assert_eq!("garmin fenix7", fit_file.source);
+        assert!(fit_file.workouts.is_empty());
+    }
+
+    #[test]
+    fn decode_activity() {
+        let start = datetime!(2023-03-25 09:00 UTC);
+        let record = |seconds: i64, lat: i32| {
+            message(
+                MesgNum::Record,
+                vec![
+                    (
+                        "timestamp",
+                        timestamp(start + time::Duration::seconds(seconds)),
+                    ),
+                    ("heart_rate", Value::UInt8(140)),
+                    ("position_lat", Value::SInt32(lat)),
+                    ("enhanced_speed", Value::Float64(2.5)),
+                    ("speed", Value::Float64(9.0)),
+                ],
+            )
+        };
+        let lap = |seconds: i64, elapsed_time: f64| {
+            message(
+                MesgNum::Lap,
+                vec![
+                    (
+                        "start_time",
+                        timestamp(start + time::Duration::seconds(seconds)),
+                    ),
+                    ("total_elapsed_time", Value::Float64(elapsed_time)),
+                    ("total_distance", Value::Float64(1000.0)),
+                ],
+            )
+        };
+
+        // The laps and records come before their session, a record outside of any session is dropped
+        let messages = vec![
+            record(0, 1 << 30),
+            record(1, -(1 << 30)),
+            lap(0, 300.0),
+            lap(300, 300.0),
+            record(3600, 0),
+            message(
+                MesgNum::Session,
+                vec![
+                    ("start_time", timestamp(start)),
+                    ("total_elapsed_time", Value::Float64(600.0)),
+                    ("total_timer_time", Value::Float64(590.0)),
+                    ("sport", Value::String("running".to_owned())),
+                    ("sub_sport", Value::String("trail".to_owned())),
+                    ("avg_heart_rate", Value::UInt8(140)),
+                ],
+            ),
+            message(
+                MesgNum::Session,
+                vec![("sport", Value::String("cycling".to_owned()))],
+            ),
+        ];
+
+        let mut decoder = Decoder::default();
+        for message in &messages {
+            decoder.decode(message);
+        }
+        let fit_file = decoder.finish();
+
+        assert_eq!("fit", fit_file.source);
+        assert!(fit_file.data_points.is_empty());
+        assert_eq!(1, fit_file.workouts.len());
+
+        let workout = &fit_file.workouts[0];
+        assert_eq!("running", workout.sport);
+        assert_eq!(Some("trail".to_owned()), workout.sub_sport);
+        assert_eq!(datetime!(2023-03-25 09:10 UTC), workout.end_time);
+        assert_eq!(Some(590.0), workout.timer_time);
+        assert_eq!(Some(140.0), workout.avg_heart_rate);
+        assert_eq!(None, workout.distance);
+        assert_eq!(2, workout.laps.len());
+        assert_eq!(datetime!(2023-03-25 09:10 UTC), workout.laps[1].end_time);
+
+        assert_eq!(2, workout.records.len());
+        assert_eq!(Some(90.0), workout.records[0].latitude);
+        assert_eq!(Some(-90.0), workout.records[1].latitude);
+        assert_eq!(Some(2.5), workout.records[1].speed);
+        assert_eq!(Some(140.0), workout.records[1].heart_rate);
+    }
+
+    #[test]
+    fn check_fit_file_header() {
+        let mut header = vec![14, 0x20, 0, 0, 0, 0, 0, 0];
+        header.extend_from_slice(b".FIT");
+        header.extend_from_slice(&[0, 0]);
+        assert!(is_fit_file(&header));
+
+        assert!(!is_fit_file(b"{\"data\":{}}"));
+        assert!(!is_fit_file(&header[..10]));
+
+        // The data ends in the middle of a definition message
+        header[4] = 100;
+        header.extend_from_slice(&[0x40, 0, 0, 0, 0, 1]);
+        assert!(matches!(decode(&header), Err(Error::Parse(_))));
+    }
+}
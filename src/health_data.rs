@@ -1,23 +1,133 @@
+use serde::de;
+use serde::de::{DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
 use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::fmt;
+use std::io;
 use time::OffsetDateTime;
 
-time::serde::format_description!(
-    custom_format,
-    OffsetDateTime,
-    "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour][offset_minute]"
-);
+/// Deserializes the timestamps of the data points.
+///
+/// The accepted formats are:
+/// * the Health Auto Export format `2022-07-23 08:13:00 +0200`, optionally with fractional seconds
+/// * RFC 3339, for example `2022-07-23T08:13:00.250+02:00`
+/// * a Unix epoch in seconds or milliseconds, as a number or a string, with an optional fraction
+pub mod timestamp {
+    use serde::de;
+    use std::fmt;
+    use time::format_description::well_known::Rfc3339;
+    use time::macros::format_description;
+    use time::OffsetDateTime;
+
+    const HEALTH_AUTO_EXPORT_FORMAT: &[time::format_description::FormatItem] = format_description!(
+        "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour][offset_minute]"
+    );
+    const HEALTH_AUTO_EXPORT_FRACTIONAL_FORMAT: &[time::format_description::FormatItem] = format_description!(
+        "[year]-[month]-[day] [hour]:[minute]:[second].[subsecond] [offset_hour][offset_minute]"
+    );
+
+    /// Epochs with an absolute value above this are in milliseconds.
+    ///
+    /// In seconds it would be a date in the year 5138.
+    const MILLISECONDS_THRESHOLD: f64 = 100_000_000_000.0;
+
+    const EXPECTED: &str = "a timestamp formatted as \"YYYY-MM-DD HH:MM:SS +ZZZZ\", RFC 3339 or a Unix epoch in seconds or milliseconds";
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<OffsetDateTime, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+
+    /// Parses a timestamp from a string in any of the accepted formats.
+    pub fn parse(value: &str) -> Option<OffsetDateTime> {
+        if let Ok(epoch) = value.parse::<f64>() {
+            return from_epoch(epoch);
+        }
+
+        OffsetDateTime::parse(value, HEALTH_AUTO_EXPORT_FORMAT)
+            .or_else(|_| OffsetDateTime::parse(value, HEALTH_AUTO_EXPORT_FRACTIONAL_FORMAT))
+            .or_else(|_| OffsetDateTime::parse(value, &Rfc3339))
+            .ok()
+    }
+
+    fn from_epoch(epoch: f64) -> Option<OffsetDateTime> {
+        if !epoch.is_finite() {
+            return None;
+        }
+
+        let unit_nanos: i128 = if epoch.abs() >= MILLISECONDS_THRESHOLD {
+            1_000_000
+        } else {
+            1_000_000_000
+        };
+
+        // The whole and fractional parts are converted separately to not lose precision
+        let whole = epoch.trunc();
+        let fraction = ((epoch - whole) * unit_nanos as f64).round() as i128;
+        let nanos = (whole as i128)
+            .checked_mul(unit_nanos)?
+            .checked_add(fraction)?;
+
+        OffsetDateTime::from_unix_timestamp_nanos(nanos).ok()
+    }
+
+    struct TimestampVisitor;
+
+    impl<'de> de::Visitor<'de> for TimestampVisitor {
+        type Value = OffsetDateTime;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str(EXPECTED)
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            parse(value).ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+        }
+
+        fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            from_epoch(value as f64)
+                .ok_or_else(|| E::invalid_value(de::Unexpected::Signed(value), &self))
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            from_epoch(value as f64)
+                .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+        }
+
+        fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            from_epoch(value).ok_or_else(|| E::invalid_value(de::Unexpected::Float(value), &self))
+        }
+    }
+}
 
 #[derive(Clone, Deserialize, Debug, PartialEq)]
 pub struct GenericDataPoint {
-    #[serde(with = "custom_format")]
+    #[serde(deserialize_with = "timestamp::deserialize")]
     pub date: OffsetDateTime,
     #[serde(rename(deserialize = "qty"))]
     pub quantity: f64,
+    /// The device or app that recorded the data point.
+    #[serde(default)]
+    pub source: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq)]
 pub struct HeartRateDataPoint {
-    #[serde(with = "custom_format")]
+    #[serde(deserialize_with = "timestamp::deserialize")]
     pub date: OffsetDateTime,
     #[serde(rename(deserialize = "Min"))]
     pub min: f64,
@@ -25,52 +135,574 @@ pub struct HeartRateDataPoint {
     pub max: f64,
     #[serde(rename(deserialize = "Avg"))]
     pub avg: f64,
+    /// The device or app that recorded the data point.
+    #[serde(default)]
+    pub source: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq)]
 pub struct SleepAnalysisDataPoint {
     pub asleep: f64,
-    #[serde(with = "custom_format")]
+    #[serde(deserialize_with = "timestamp::deserialize")]
     pub date: OffsetDateTime,
     #[serde(rename(deserialize = "sleepSource"))]
     pub sleep_source: String,
-    #[serde(with = "custom_format", rename(deserialize = "sleepStart"))]
+    #[serde(
+        deserialize_with = "timestamp::deserialize",
+        rename(deserialize = "sleepStart")
+    )]
     pub sleep_start: OffsetDateTime,
-    #[serde(with = "custom_format", rename(deserialize = "sleepEnd"))]
+    #[serde(
+        deserialize_with = "timestamp::deserialize",
+        rename(deserialize = "sleepEnd")
+    )]
     pub sleep_end: OffsetDateTime,
     #[serde(rename(deserialize = "inBed"))]
     pub in_bed: f64,
     #[serde(rename(deserialize = "inBedSource"))]
     pub in_bed_source: String,
-    #[serde(with = "custom_format", rename(deserialize = "inBedStart"))]
+    #[serde(
+        deserialize_with = "timestamp::deserialize",
+        rename(deserialize = "inBedStart")
+    )]
     pub in_bed_start: OffsetDateTime,
-    #[serde(with = "custom_format", rename(deserialize = "inBedEnd"))]
+    #[serde(
+        deserialize_with = "timestamp::deserialize",
+        rename(deserialize = "inBedEnd")
+    )]
     pub in_bed_end: OffsetDateTime,
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
-#[serde(untagged)]
+#[derive(Debug, PartialEq)]
 pub enum MetricDataPoint {
     HeartRate(HeartRateDataPoint),
     SleepAnalysis(SleepAnalysisDataPoint),
     Generic(GenericDataPoint),
 }
 
+impl MetricDataPoint {
+    /// Applies `f` to every value of the data point expressed in the units of its metric.
+    pub fn map_values<F>(&mut self, f: F)
+    where
+        F: Fn(f64) -> f64,
+    {
+        match self {
+            Self::HeartRate(data_point) => {
+                data_point.min = f(data_point.min);
+                data_point.max = f(data_point.max);
+                data_point.avg = f(data_point.avg);
+            }
+            Self::SleepAnalysis(data_point) => {
+                data_point.asleep = f(data_point.asleep);
+                data_point.in_bed = f(data_point.in_bed);
+            }
+            Self::Generic(data_point) => data_point.quantity = f(data_point.quantity),
+        }
+    }
+}
+
+/// Type of the data points of a metric.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataPointKind {
+    HeartRate,
+    SleepAnalysis,
+    Generic,
+}
+
+/// The known metrics and the type of their data points.
+const METRIC_REGISTRY: &[(&str, DataPointKind)] = &[
+    ("heart_rate", DataPointKind::HeartRate),
+    ("sleep_analysis", DataPointKind::SleepAnalysis),
+    ("active_energy", DataPointKind::Generic),
+    ("apple_exercise_time", DataPointKind::Generic),
+    ("apple_stand_hour", DataPointKind::Generic),
+    ("apple_stand_time", DataPointKind::Generic),
+    ("basal_energy_burned", DataPointKind::Generic),
+    ("blood_oxygen_saturation", DataPointKind::Generic),
+    ("body_fat_percentage", DataPointKind::Generic),
+    ("body_mass_index", DataPointKind::Generic),
+    ("cycling_distance", DataPointKind::Generic),
+    ("dietary_water", DataPointKind::Generic),
+    ("environmental_audio_exposure", DataPointKind::Generic),
+    ("flights_climbed", DataPointKind::Generic),
+    ("headphone_audio_exposure", DataPointKind::Generic),
+    ("heart_rate_variability", DataPointKind::Generic),
+    ("lean_body_mass", DataPointKind::Generic),
+    ("mindful_minutes", DataPointKind::Generic),
+    ("physical_effort", DataPointKind::Generic),
+    ("respiratory_rate", DataPointKind::Generic),
+    ("resting_heart_rate", DataPointKind::Generic),
+    ("six_minute_walking_test_distance", DataPointKind::Generic),
+    ("stair_speed_down", DataPointKind::Generic),
+    ("stair_speed_up", DataPointKind::Generic),
+    ("step_count", DataPointKind::Generic),
+    ("stress_level", DataPointKind::Generic),
+    ("swimming_distance", DataPointKind::Generic),
+    ("time_in_daylight", DataPointKind::Generic),
+    ("vo2_max", DataPointKind::Generic),
+    ("walking_asymmetry_percentage", DataPointKind::Generic),
+    ("walking_double_support_percentage", DataPointKind::Generic),
+    ("walking_heart_rate_average", DataPointKind::Generic),
+    ("walking_running_distance", DataPointKind::Generic),
+    ("walking_speed", DataPointKind::Generic),
+    ("walking_step_length", DataPointKind::Generic),
+    ("weight_body_mass", DataPointKind::Generic),
+];
+
+/// Returns the type of the data points of a metric, `None` if the metric is unknown.
+///
+/// The data points of unknown metrics are expected to be generic.
+pub fn lookup_metric(name: &str) -> Option<DataPointKind> {
+    METRIC_REGISTRY
+        .iter()
+        .find(|(metric_name, _)| *metric_name == name)
+        .map(|(_, kind)| *kind)
+}
+
+impl MetricDataPoint {
+    /// Parses a data point of the given type.
+    ///
+    /// The error names the invalid field, if any.
+    fn parse(raw: &str, kind: DataPointKind) -> Result<Self, String> {
+        fn parse_as<'a, T>(raw: &'a str) -> Result<T, String>
+        where
+            T: Deserialize<'a>,
+        {
+            let deserializer = &mut serde_json::Deserializer::from_str(raw);
+
+            serde_path_to_error::deserialize(deserializer).map_err(|err| {
+                let path = err.path().to_string();
+                match path.as_str() {
+                    "." => err.into_inner().to_string(),
+                    _ => format!("{}: {}", path, err.into_inner()),
+                }
+            })
+        }
+
+        match kind {
+            DataPointKind::HeartRate => parse_as(raw).map(Self::HeartRate),
+            DataPointKind::SleepAnalysis => parse_as(raw).map(Self::SleepAnalysis),
+            DataPointKind::Generic => parse_as(raw).map(Self::Generic),
+        }
+    }
+}
+
+/// A data point that failed to parse, kept as is with the reason of the failure.
+#[derive(Debug, PartialEq)]
+pub struct InvalidDataPoint {
+    pub raw: String,
+    pub reason: String,
+}
+
+/// A data point parsed leniently.
+///
+/// An invalid data point doesn't fail the parsing of the whole payload,
+/// it's up to the caller to decide what to do with it.
+#[derive(Debug, PartialEq)]
+pub enum ParsedDataPoint {
+    Valid(MetricDataPoint),
+    /// Doesn't match the type of its metric.
+    Invalid(InvalidDataPoint),
+    /// Matches the type of its metric but was refused by the `prepare` function
+    /// of [`parse_data_points`].
+    Rejected(InvalidDataPoint),
+}
+
+/// Function preparing a valid data point, called with the index of its metric.
+type Prepare<'a> = dyn FnMut(usize, &mut MetricDataPoint) -> Result<(), String> + 'a;
+
+/// Deserializes a data point of the given type leniently.
+struct ParsedDataPointSeed<'a, 'b> {
+    kind: DataPointKind,
+    metric_index: usize,
+    prepare: &'a mut Prepare<'b>,
+}
+
+impl<'de, 'a, 'b> DeserializeSeed<'de> for ParsedDataPointSeed<'a, 'b> {
+    type Value = ParsedDataPoint;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let raw = Box::<RawValue>::deserialize(deserializer)?;
+
+        let mut data_point = match MetricDataPoint::parse(raw.get(), self.kind) {
+            Ok(data_point) => data_point,
+            Err(reason) => {
+                return Ok(ParsedDataPoint::Invalid(InvalidDataPoint {
+                    raw: raw.get().to_owned(),
+                    reason,
+                }))
+            }
+        };
+
+        Ok(match (self.prepare)(self.metric_index, &mut data_point) {
+            Ok(()) => ParsedDataPoint::Valid(data_point),
+            Err(reason) => ParsedDataPoint::Rejected(InvalidDataPoint {
+                raw: raw.get().to_owned(),
+                reason,
+            }),
+        })
+    }
+}
+
+/// Outline of a payload: its metrics without their data points.
+///
+/// Parsing it validates the structure of a payload without keeping the data points in memory.
 #[derive(Deserialize, Debug, PartialEq)]
-pub struct Metric {
-    pub name: String,
-    pub units: String,
-    pub data: Vec<MetricDataPoint>,
+pub struct PayloadOutline {
+    pub data: HealthDataOutline,
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
-pub struct HealthData {
-    pub metrics: Vec<Metric>,
+pub struct HealthDataOutline {
+    pub metrics: Vec<MetricOutline>,
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
-pub struct HealthDataPayload {
-    pub data: HealthData,
+pub struct MetricOutline {
+    pub name: String,
+    pub units: String,
+    #[serde(rename = "data", deserialize_with = "count_seq")]
+    pub nb_data_points: usize,
+}
+
+fn count_seq<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct CountVisitor;
+
+    impl<'de> Visitor<'de> for CountVisitor {
+        type Value = usize;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a sequence")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut count = 0;
+            while seq.next_element::<IgnoredAny>()?.is_some() {
+                count += 1;
+            }
+            Ok(count)
+        }
+    }
+
+    deserializer.deserialize_seq(CountVisitor)
+}
+
+/// A batch of data points of the metric at `metric_index` in the payload.
+#[derive(Debug, PartialEq)]
+pub struct DataPointBatch {
+    pub metric_index: usize,
+    pub data_points: Vec<ParsedDataPoint>,
+}
+
+/// Parses the data points of a payload incrementally, calling `f` with batches of at most
+/// `batch_size` data points.
+///
+/// Only a single batch is in memory at once. The metric of a batch is identified by its index,
+/// its name and units are in the [`PayloadOutline`] since they can appear after the data points.
+/// The data points of the metric at index `i` are parsed as `kinds[i]`, generic if missing.
+///
+/// Every valid data point goes through `prepare` before being batched, which can modify it
+/// or reject it with a reason. An error returned by `f` stops the parsing.
+pub fn parse_data_points<R, P, F>(
+    reader: R,
+    kinds: &[DataPointKind],
+    batch_size: usize,
+    mut prepare: P,
+    mut f: F,
+) -> serde_json::Result<()>
+where
+    R: io::Read,
+    P: FnMut(usize, &mut MetricDataPoint) -> Result<(), String>,
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    let mut batcher = Batcher {
+        kinds,
+        batch_size: batch_size.max(1),
+        prepare: &mut prepare,
+        f: &mut f,
+    };
+
+    let mut deserializer = serde_json::Deserializer::from_reader(io::BufReader::new(reader));
+    PayloadSeed(&mut batcher).deserialize(&mut deserializer)?;
+    deserializer.end()
+}
+
+struct Batcher<'a, F> {
+    kinds: &'a [DataPointKind],
+    batch_size: usize,
+    prepare: &'a mut Prepare<'a>,
+    f: &'a mut F,
+}
+
+impl<'a, F> Batcher<'a, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    fn flush<E>(
+        &mut self,
+        metric_index: usize,
+        data_points: &mut Vec<ParsedDataPoint>,
+    ) -> Result<(), E>
+    where
+        E: de::Error,
+    {
+        if data_points.is_empty() {
+            return Ok(());
+        }
+
+        let batch = DataPointBatch {
+            metric_index,
+            data_points: std::mem::replace(data_points, Vec::with_capacity(self.batch_size)),
+        };
+
+        (self.f)(batch).map_err(E::custom)
+    }
+}
+
+struct PayloadSeed<'a, 'b, F>(&'a mut Batcher<'b, F>);
+
+impl<'de, 'a, 'b, F> DeserializeSeed<'de> for PayloadSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, 'b, F> Visitor<'de> for PayloadSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a health data payload")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "data" {
+                map.next_value_seed(HealthDataSeed(&mut *self.0))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct HealthDataSeed<'a, 'b, F>(&'a mut Batcher<'b, F>);
+
+impl<'de, 'a, 'b, F> DeserializeSeed<'de> for HealthDataSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, 'b, F> Visitor<'de> for HealthDataSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("health data")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "metrics" {
+                map.next_value_seed(MetricsSeed(&mut *self.0))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct MetricsSeed<'a, 'b, F>(&'a mut Batcher<'b, F>);
+
+impl<'de, 'a, 'b, F> DeserializeSeed<'de> for MetricsSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, 'b, F> Visitor<'de> for MetricsSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a sequence of metrics")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut metric_index = 0;
+        while seq
+            .next_element_seed(MetricSeed {
+                batcher: self.0,
+                metric_index,
+            })?
+            .is_some()
+        {
+            metric_index += 1;
+        }
+        Ok(())
+    }
+}
+
+struct MetricSeed<'a, 'b, F> {
+    batcher: &'a mut Batcher<'b, F>,
+    metric_index: usize,
+}
+
+impl<'de, 'a, 'b, F> DeserializeSeed<'de> for MetricSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, 'b, F> Visitor<'de> for MetricSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a metric")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "data" {
+                map.next_value_seed(DataPointsSeed {
+                    batcher: &mut *self.batcher,
+                    metric_index: self.metric_index,
+                })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct DataPointsSeed<'a, 'b, F> {
+    batcher: &'a mut Batcher<'b, F>,
+    metric_index: usize,
+}
+
+impl<'de, 'a, 'b, F> DeserializeSeed<'de> for DataPointsSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, 'b, F> Visitor<'de> for DataPointsSeed<'a, 'b, F>
+where
+    F: FnMut(DataPointBatch) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a sequence of data points")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let kind = self
+            .batcher
+            .kinds
+            .get(self.metric_index)
+            .copied()
+            .unwrap_or(DataPointKind::Generic);
+
+        let mut data_points = Vec::with_capacity(self.batcher.batch_size);
+
+        loop {
+            let seed = ParsedDataPointSeed {
+                kind,
+                metric_index: self.metric_index,
+                prepare: self.batcher.prepare,
+            };
+            let data_point = match seq.next_element_seed(seed)? {
+                Some(data_point) => data_point,
+                None => break,
+            };
+
+            data_points.push(data_point);
+
+            if data_points.len() >= self.batcher.batch_size {
+                self.batcher.flush(self.metric_index, &mut data_points)?;
+            }
+        }
+
+        self.batcher.flush(self.metric_index, &mut data_points)
+    }
 }
 
 #[cfg(test)]
@@ -82,31 +714,215 @@ mod tests {
         "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour][offset_minute]"
     );
 
+    fn parse_all_data_points(input: &str, batch_size: usize) -> Vec<DataPointBatch> {
+        let outline: PayloadOutline = serde_json::from_str(input).unwrap();
+        let kinds: Vec<_> = outline
+            .data
+            .metrics
+            .iter()
+            .map(|metric| lookup_metric(&metric.name).unwrap_or(DataPointKind::Generic))
+            .collect();
+
+        let mut batches = Vec::new();
+        parse_data_points(
+            input.as_bytes(),
+            &kinds,
+            batch_size,
+            |_, _| Ok(()),
+            |batch| {
+                batches.push(batch);
+                Ok(())
+            },
+        )
+        .unwrap();
+        batches
+    }
+
+    #[test]
+    fn parse_outline() {
+        let input = r#"{"data":{"metrics":[{"data":[{"Avg":76,"Max":76,"Min":76,"date":"2022-07-23 00:04:48 +0200"},{"foo":"bar"}],"name":"heart_rate","units":"count/min"},{"name":"step_count","units":"count","data":[]}]}}"#;
+
+        let exp = PayloadOutline {
+            data: HealthDataOutline {
+                metrics: vec![
+                    MetricOutline {
+                        name: "heart_rate".to_owned(),
+                        units: "count/min".to_owned(),
+                        nb_data_points: 2,
+                    },
+                    MetricOutline {
+                        name: "step_count".to_owned(),
+                        units: "count".to_owned(),
+                        nb_data_points: 0,
+                    },
+                ],
+            },
+        };
+
+        let outline: PayloadOutline = serde_json::from_str(input).unwrap();
+        assert_eq!(exp, outline);
+    }
+
     #[test]
     fn parse_metric() {
-        let input = r#"{"data":[{"Avg":83.15994644165039,"Max":85,"Min":81.31989288330078,"date":"2022-07-23 00:01:41 +0200"},{"Avg":76,"Max":76,"Min":76,"date":"2022-07-23 00:04:48 +0200"}],"name":"heart_rate","units":"count/min"}"#;
+        let input = r#"{"data":{"metrics":[{"data":[{"Avg":83.15994644165039,"Max":85,"Min":81.31989288330078,"date":"2022-07-23 00:01:41 +0200"},{"Avg":76,"Max":76,"Min":76,"date":"2022-07-23 00:04:48 +0200"}],"name":"heart_rate","units":"count/min"}]}}"#;
 
-        let exp = Metric {
-            name: "heart_rate".to_owned(),
-            units: "count/min".to_owned(),
-            data: vec![
-                MetricDataPoint::HeartRate(HeartRateDataPoint {
+        let exp = vec![DataPointBatch {
+            metric_index: 0,
+            data_points: vec![
+                ParsedDataPoint::Valid(MetricDataPoint::HeartRate(HeartRateDataPoint {
                     date: datetime!(2022-07-23 00:01:41 +2),
                     avg: 83.15994644165039,
                     max: 85.0,
                     min: 81.31989288330078,
-                }),
-                MetricDataPoint::HeartRate(HeartRateDataPoint {
+                    source: None,
+                })),
+                ParsedDataPoint::Valid(MetricDataPoint::HeartRate(HeartRateDataPoint {
                     date: datetime!(2022-07-23 00:04:48 +2),
                     avg: 76.0,
                     max: 76.0,
                     min: 76.0,
-                }),
+                    source: None,
+                })),
             ],
-        };
+        }];
 
-        let metric: Metric = serde_json::from_str(input).unwrap();
-        assert_eq!(exp, metric);
+        assert_eq!(exp, parse_all_data_points(input, 100));
+    }
+
+    #[test]
+    fn parse_data_points_in_batches() {
+        let input = r#"{"data":{"metrics":[{"name":"step_count","units":"count","data":[{"date":"2022-07-23 00:01:00 +0200","qty":1},{"date":"2022-07-23 00:02:00 +0200","qty":2},{"date":"2022-07-23 00:03:00 +0200","qty":3}]},{"name":"foobar","units":"count","data":[]},{"data":[{"date":"2022-07-23 00:04:00 +0200","qty":4}],"name":"weight_body_mass","units":"kg"}]}}"#;
+
+        let batches = parse_all_data_points(input, 2);
+
+        let batch_sizes: Vec<_> = batches
+            .iter()
+            .map(|batch| (batch.metric_index, batch.data_points.len()))
+            .collect();
+        assert_eq!(vec![(0, 2), (0, 1), (2, 1)], batch_sizes);
+    }
+
+    #[test]
+    fn parse_data_points_stops_on_error() {
+        let input = r#"{"data":{"metrics":[{"name":"step_count","units":"count","data":[{"date":"2022-07-23 00:01:00 +0200","qty":1},{"date":"2022-07-23 00:02:00 +0200","qty":2}]}]}}"#;
+
+        let mut nb_batches = 0;
+        let err = parse_data_points(
+            input.as_bytes(),
+            &[],
+            1,
+            |_, _| Ok(()),
+            |_| {
+                nb_batches += 1;
+                Err("aborted".to_owned())
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(1, nb_batches);
+        assert!(err.to_string().starts_with("aborted"));
+    }
+
+    #[test]
+    fn parse_data_points_prepares_valid_data_points() {
+        let input = r#"{"data":{"metrics":[{"name":"step_count","units":"count","data":[{"date":"2022-07-23 00:01:00 +0200","qty":1},{"date":"2022-07-23 00:02:00 +0200","qty":-2},{"date":true,"qty":3}]}]}}"#;
+
+        let mut batches = Vec::new();
+        parse_data_points(
+            input.as_bytes(),
+            &[],
+            100,
+            |_, data_point| match data_point {
+                MetricDataPoint::Generic(data_point) if data_point.quantity >= 0.0 => {
+                    data_point.quantity *= 10.0;
+                    Ok(())
+                }
+                _ => Err("negative".to_owned()),
+            },
+            |batch| {
+                batches.push(batch);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let data_points = &batches[0].data_points;
+        assert!(matches!(
+            &data_points[0],
+            ParsedDataPoint::Valid(MetricDataPoint::Generic(data_point)) if data_point.quantity == 10.0
+        ));
+        assert!(matches!(
+            &data_points[1],
+            ParsedDataPoint::Rejected(data_point) if data_point.reason == "negative"
+        ));
+        // The invalid data points never reach the prepare function
+        assert!(matches!(&data_points[2], ParsedDataPoint::Invalid(_)));
+    }
+
+    #[test]
+    fn parse_metric_with_invalid_data_point() {
+        let input = r#"{"data":{"metrics":[{"data":[{"Avg":76,"Max":76,"Min":76,"date":"2022-07-23 00:04:48 +0200"},{"Avg":76,"date":"2022-07-23 00:05:48 +0200"}],"name":"heart_rate","units":"count/min"}]}}"#;
+
+        let batches = parse_all_data_points(input, 100);
+        assert_eq!(1, batches.len());
+
+        let data_points = &batches[0].data_points;
+        assert_eq!(2, data_points.len());
+
+        assert!(matches!(
+            data_points[0],
+            ParsedDataPoint::Valid(MetricDataPoint::HeartRate(_))
+        ));
+        match &data_points[1] {
+            ParsedDataPoint::Invalid(data_point) => {
+                assert_eq!(
+                    r#"{"Avg":76,"date":"2022-07-23 00:05:48 +0200"}"#,
+                    data_point.raw.as_str()
+                );
+                assert!(!data_point.reason.is_empty());
+            }
+            data_point => panic!("expected an invalid data point, got {:?}", data_point),
+        }
+    }
+
+    #[test]
+    fn parse_data_points_by_metric_name() {
+        let input = r#"{"data":{"metrics":[{"data":[{"Avg":76,"Max":76,"date":"2022-07-23 00:04:48 +0200"},{"date":"2022-07-23 00:05:48 +0200","qty":76}],"name":"heart_rate","units":"count/min"},{"data":[{"Avg":76,"date":"2022-07-23 00:05:48 +0200","qty":3}],"name":"my_metric","units":"count"}]}}"#;
+
+        let batches = parse_all_data_points(input, 100);
+        assert_eq!(2, batches.len());
+
+        // Heart rate data points must have all the heart rate fields
+        for data_point in &batches[0].data_points {
+            match data_point {
+                ParsedDataPoint::Invalid(data_point) => {
+                    assert!(
+                        data_point.reason.starts_with("missing field `Min`"),
+                        "reason: {}",
+                        data_point.reason
+                    );
+                }
+                data_point => panic!("expected an invalid data point, got {:?}", data_point),
+            }
+        }
+
+        // Unknown metrics are generic whatever their fields
+        assert!(matches!(
+            batches[1].data_points[0],
+            ParsedDataPoint::Valid(MetricDataPoint::Generic(_))
+        ));
+    }
+
+    #[test]
+    fn lookup_metrics() {
+        assert_eq!(Some(DataPointKind::HeartRate), lookup_metric("heart_rate"));
+        assert_eq!(
+            Some(DataPointKind::SleepAnalysis),
+            lookup_metric("sleep_analysis")
+        );
+        assert_eq!(Some(DataPointKind::Generic), lookup_metric("step_count"));
+        assert_eq!(None, lookup_metric("foobar"));
     }
 
     #[test]
@@ -118,6 +934,75 @@ mod tests {
         assert_eq!(exp, dt);
     }
 
+    #[test]
+    fn parse_timestamps() {
+        let exp = datetime!(2022-07-23 08:13:00 +2);
+
+        let inputs = [
+            "2022-07-23 08:13:00 +0200",
+            "2022-07-23T08:13:00+02:00",
+            "2022-07-23T06:13:00Z",
+            "1658556780",
+            "1658556780000",
+        ];
+        for input in inputs {
+            assert_eq!(Some(exp), timestamp::parse(input), "input: {}", input);
+        }
+
+        let exp = datetime!(2022-07-23 08:13:00.25 +2);
+
+        let inputs = [
+            "2022-07-23 08:13:00.25 +0200",
+            "2022-07-23T08:13:00.250+02:00",
+            "1658556780.25",
+            "1658556780250",
+        ];
+        for input in inputs {
+            assert_eq!(Some(exp), timestamp::parse(input), "input: {}", input);
+        }
+
+        let inputs = ["", "2022-07-23", "2022-07-23 08:13:00", "yesterday", "NaN"];
+        for input in inputs {
+            assert_eq!(None, timestamp::parse(input), "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn deserialize_epoch_timestamps() {
+        let data = r#"{"date":1658556780,"qty":1}"#;
+        let data_point: GenericDataPoint = serde_json::from_str(data).unwrap();
+        assert_eq!(datetime!(2022-07-23 06:13:00 UTC), data_point.date);
+
+        let data = r#"{"date":1658556780250,"qty":1}"#;
+        let data_point: GenericDataPoint = serde_json::from_str(data).unwrap();
+        assert_eq!(datetime!(2022-07-23 06:13:00.25 UTC), data_point.date);
+
+        let data = r#"{"date":1658556780.5,"qty":1}"#;
+        let data_point: GenericDataPoint = serde_json::from_str(data).unwrap();
+        assert_eq!(datetime!(2022-07-23 06:13:00.5 UTC), data_point.date);
+    }
+
+    #[test]
+    fn invalid_timestamp_names_the_field() {
+        let data = r#"{"asleep":5.6,"date":"2022-07-23 00:22:33 +0200","inBed":5.6,"inBedEnd":"2022-07-23 06:00:11 +0200","inBedSource":"José","inBedStart":"2022-07-23 00:22:33 +0200","sleepEnd":"tomorrow","sleepSource":"Foobar","sleepStart":"2022-07-23 00:17:49 +0200"}"#;
+
+        let reason = MetricDataPoint::parse(data, DataPointKind::SleepAnalysis).unwrap_err();
+        assert!(
+            reason.starts_with("sleepEnd: invalid value: string \"tomorrow\""),
+            "reason: {}",
+            reason
+        );
+
+        let data = r#"{"date":true,"qty":1}"#;
+
+        let reason = MetricDataPoint::parse(data, DataPointKind::Generic).unwrap_err();
+        assert!(
+            reason.starts_with("date: invalid type: boolean `true`"),
+            "reason: {}",
+            reason
+        );
+    }
+
     #[test]
     fn deserialize_generic_data_point() {
         let data = r#"{"date":"2022-07-23 08:13:00 +0200","qty":3.924}"#;
@@ -125,6 +1010,7 @@ mod tests {
         let exp = GenericDataPoint {
             date: datetime!(2022-07-23 08:13:00 +2),
             quantity: 3.924,
+            source: None,
         };
 
         let data_point: GenericDataPoint = serde_json::from_str(data).unwrap();
@@ -138,7 +1024,8 @@ mod tests {
             "Avg": 66,
             "Max": 66,
             "Min": 66,
-            "date": "2022-07-24 15:21:29 +0200"
+            "date": "2022-07-24 15:21:29 +0200",
+            "source": "Apple Watch"
           }"#;
 
         let exp = HeartRateDataPoint {
@@ -146,6 +1033,7 @@ mod tests {
             avg: 66.0,
             max: 66.0,
             min: 66.0,
+            source: Some("Apple Watch".to_owned()),
         };
 
         let data_point: HeartRateDataPoint = serde_json::from_str(data).unwrap();
@@ -0,0 +1,217 @@
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    SQLx(#[from] sqlx::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Header carrying the idempotency key of an upload.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Header set on the responses replayed for a known idempotency key.
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// The upload created with an idempotency key.
+#[derive(Debug, PartialEq)]
+pub struct StoredKey {
+    pub body_hash: Vec<u8>,
+    pub upload_id: i64,
+}
+
+impl StoredKey {
+    /// Returns true if the key was used with a body of the same hash.
+    pub fn matches(&self, body_hash: &[u8]) -> bool {
+        self.body_hash == body_hash
+    }
+}
+
+/// Returns true if `key` can be used as an idempotency key.
+pub fn is_valid_key(key: &str) -> bool {
+    (1..=255).contains(&key.len()) && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Hashes a body as it's received.
+#[derive(Default)]
+pub struct BodyHasher(Sha256);
+
+impl BodyHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.0.finalize().to_vec()
+    }
+}
+
+/// Returns the upload created with a key of a user, if the key was used less than `ttl` ago.
+pub async fn find<'e, E>(
+    executor: E,
+    user_id: i64,
+    key: &str,
+    ttl: Duration,
+) -> Result<Option<StoredKey>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let stored_key = sqlx::query_as!(
+        StoredKey,
+        r#"
+        SELECT body_hash, upload_id FROM idempotency_key
+        WHERE user_id = $1 AND key = $2
+        AND created_at > now() - make_interval(secs => $3)"#,
+        user_id,
+        key,
+        ttl.as_secs_f64(),
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(stored_key)
+}
+
+/// Stores the upload created with a key, returns false if the key is already used.
+///
+/// An expired key is replaced. Concurrent uploads with the same key wait for each other,
+/// only the first one to commit stores the key.
+pub async fn store<'e, E>(
+    executor: E,
+    user_id: i64,
+    key: &str,
+    body_hash: &[u8],
+    upload_id: i64,
+    ttl: Duration,
+) -> Result<bool>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO idempotency_key(user_id, key, body_hash, upload_id)
+        VALUES($1, $2, $3, $4)
+        ON CONFLICT (user_id, key) DO UPDATE
+        SET body_hash = excluded.body_hash, upload_id = excluded.upload_id, created_at = now()
+        WHERE idempotency_key.created_at <= now() - make_interval(secs => $5)
+        RETURNING upload_id"#,
+        user_id,
+        key,
+        body_hash,
+        upload_id,
+        ttl.as_secs_f64(),
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(record.is_some())
+}
+
+/// Deletes the keys used more than `ttl` ago, returns the number of keys deleted.
+pub async fn delete_expired<'e, E>(executor: E, ttl: Duration) -> Result<u64>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM idempotency_key
+        WHERE created_at <= now() - make_interval(secs => $1)"#,
+        ttl.as_secs_f64(),
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive;
+    use crate::configuration;
+    use crate::db;
+    use crate::ingester;
+    use crate::user;
+    use secrecy::ExposeSecret;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    fn hash(body: &[u8]) -> Vec<u8> {
+        let mut hasher = BodyHasher::default();
+        hasher.update(body);
+        hasher.finish()
+    }
+
+    #[test]
+    fn valid_keys() {
+        assert!(is_valid_key("6f1ce3a4-1b5a-4d6c-9a0e-3c1c6f2c8f49"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("foo bar"));
+        assert!(!is_valid_key(&"a".repeat(256)));
+    }
+
+    #[tokio::test]
+    async fn test_store_and_find() {
+        const TTL: Duration = Duration::from_secs(3600);
+
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let user_id = user::get_or_create_user(&mut tx, "test-user")
+            .await
+            .unwrap();
+
+        let body = br#"{"data":{"metrics":[]}}"#;
+        let raw_upload_id = archive::store(
+            &mut tx,
+            user_id,
+            None,
+            &http::HeaderMap::new(),
+            archive::Format::Json,
+            body.to_vec(),
+        )
+        .await
+        .unwrap();
+        let upload_id = ingester::enqueue_upload(&mut tx, raw_upload_id, false)
+            .await
+            .unwrap();
+
+        assert!(find(&mut tx, user_id, "key", TTL).await.unwrap().is_none());
+
+        assert!(store(&mut tx, user_id, "key", &hash(body), upload_id, TTL)
+            .await
+            .unwrap());
+        assert!(!store(&mut tx, user_id, "key", &hash(body), upload_id, TTL)
+            .await
+            .unwrap());
+
+        let stored_key = find(&mut tx, user_id, "key", TTL).await.unwrap().unwrap();
+        assert_eq!(upload_id, stored_key.upload_id);
+        assert!(stored_key.matches(&hash(body)));
+        assert!(!stored_key.matches(&hash(b"{}")));
+
+        // Expired keys are ignored and replaced
+        assert!(find(&mut tx, user_id, "key", Duration::ZERO)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store(
+            &mut tx,
+            user_id,
+            "key",
+            &hash(b"{}"),
+            upload_id,
+            Duration::ZERO
+        )
+        .await
+        .unwrap());
+    }
+}
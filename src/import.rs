@@ -0,0 +1,590 @@
+use crate::apple_health::{self, Element};
+use crate::archive;
+use crate::db;
+use crate::health_data::{InvalidDataPoint, ParsedDataPoint};
+use crate::ingester::{self, Ingestion, Origin, Summary};
+use crate::units;
+use crate::validation;
+use crate::workout;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// Number of elements of an Apple Health export between two progress logs.
+const PROGRESS_INTERVAL: u64 = 100_000;
+
+/// Number of batches of elements of an Apple Health export inserted in a single transaction.
+///
+/// Every transaction commits on its own so that a long import doesn't hold back the exporter,
+/// and a failure only loses the elements of the current transaction.
+const BATCHES_PER_INGESTION: u64 = 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unable to read {path}: {err}")]
+    Read { path: PathBuf, err: io::Error },
+    #[error(transparent)]
+    Ingester(#[from] ingester::Error),
+    #[error(transparent)]
+    AppleHealth(#[from] apple_health::Error),
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Returns the files at `paths`, sorted by path.
+///
+/// The directories are searched recursively for JSON, FIT, GPX and TCX files.
+pub fn collect_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        collect_path(path, true, &mut files)?;
+    }
+
+    files.sort();
+    files.dedup();
+
+    Ok(files)
+}
+
+/// Adds the file at `path` or the JSON, FIT, GPX and TCX files under it.
+///
+/// A file named explicitly is always imported, the files found in a directory only if they have one of these extensions.
+fn collect_path(path: &Path, explicit: bool, files: &mut Vec<PathBuf>) -> Result<()> {
+    let read_err = |err| Error::Read {
+        path: path.to_owned(),
+        err,
+    };
+
+    if !fs::metadata(path).map_err(read_err)?.is_dir() {
+        if explicit || file_format(path).is_some() {
+            files.push(path.to_owned());
+        }
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path).map_err(read_err)? {
+        let entry = entry.map_err(read_err)?;
+        collect_path(&entry.path(), false, files)?;
+    }
+
+    Ok(())
+}
+
+/// Imports Health Auto Export files, Apple Health exports or FIT, GPX and TCX files for a user, without going through the web server.
+///
+/// The files go through the same parsing, conversion and validation as the uploads, and the
+/// data points already stored are skipped so importing a file again inserts nothing.
+/// The files aren't archived and the rejected data points aren't attached to an upload.
+pub struct Importer {
+    pub db: Arc<db::Db>,
+    pub user_id: i64,
+    pub strict: bool,
+    pub dry_run: bool,
+    pub batch_size: usize,
+    pub canonical_units: Arc<units::CanonicalUnits>,
+    pub validator: Arc<validation::Validator>,
+}
+
+impl Importer {
+    /// Imports the files one by one, printing the progress and a summary of every file.
+    ///
+    /// A file failing to be imported doesn't stop the others, returns the number of failed files.
+    pub async fn run(&self, files: &[PathBuf]) -> usize {
+        let mut total = Summary::default();
+        let mut nb_failed = 0;
+
+        for (i, path) in files.iter().enumerate() {
+            let progress = format!("[{}/{}] {}", i + 1, files.len(), path.display());
+
+            match self.import_file(path).await {
+                Ok(summary) => {
+                    println!("{}: {}", progress, format_summary(&summary));
+
+                    total.add(summary);
+                }
+                Err(err) => {
+                    error!(path = %path.display(), %err, "unable to import file");
+                    println!("{}: failed, {}", progress, err);
+
+                    nb_failed += 1;
+                }
+            }
+        }
+
+        println!(
+            "imported {} of {} files: {}{}",
+            files.len() - nb_failed,
+            files.len(),
+            format_summary(&total),
+            if self.dry_run {
+                " (dry run, nothing was stored)"
+            } else {
+                ""
+            }
+        );
+
+        nb_failed
+    }
+
+    pub async fn import_file(&self, path: &Path) -> Result<Summary> {
+        if apple_health::is_export(path) {
+            return self.import_apple_health_export(path).await;
+        }
+        let format = file_format(path).unwrap_or(archive::Format::Json);
+
+        let read_path = path.to_owned();
+        let data = tokio::task::spawn_blocking(move || fs::read(&read_path))
+            .await?
+            .map_err(|err| Error::Read {
+                path: path.to_owned(),
+                err,
+            })?;
+
+        let origin = Origin {
+            user_id: self.user_id,
+            api_token_id: None,
+        };
+        let body = Arc::new(archive::Body::new(format, data));
+
+        let summary = match format {
+            archive::Format::Fit => {
+                ingester::ingest_fit(
+                    &self.db,
+                    None,
+                    origin,
+                    body,
+                    self.strict,
+                    self.dry_run,
+                    &self.canonical_units,
+                    &self.validator,
+                )
+                .await?
+            }
+            archive::Format::Gpx | archive::Format::Tcx => {
+                ingester::ingest_route(
+                    &self.db,
+                    None,
+                    origin,
+                    body,
+                    self.dry_run,
+                    &self.canonical_units,
+                    &self.validator,
+                )
+                .await?
+            }
+            archive::Format::Json => {
+                ingester::ingest_payload(
+                    &self.db,
+                    None,
+                    origin,
+                    body,
+                    self.strict,
+                    self.dry_run,
+                    self.batch_size,
+                    &self.canonical_units,
+                    &self.validator,
+                )
+                .await?
+            }
+        };
+
+        Ok(summary)
+    }
+
+    /// Imports an Apple Health export, either the zip archive or its `export.xml`.
+    ///
+    /// The records and the daily totals of the activity summaries are imported as data points,
+    /// the workouts as workouts of their source.
+    ///
+    /// The export is read in a blocking task which sends the elements in batches,
+    /// so that exports of multiple gigabytes are never held in memory. The batches are
+    /// inserted in transactions of [`BATCHES_PER_INGESTION`] batches, an import failing
+    /// midway keeps the elements of the transactions already committed.
+    async fn import_apple_health_export(&self, path: &Path) -> Result<Summary> {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+        let reader_path = path.to_owned();
+        let batch_size = self.batch_size;
+        let reader = tokio::task::spawn_blocking(move || {
+            apple_health::read_export_file(&reader_path, batch_size, |elements| {
+                sender
+                    .blocking_send(elements)
+                    .map_err(|_| "import aborted".to_owned())
+            })
+        });
+
+        let mut mapper = apple_health::Mapper::default();
+        let mut summary = Summary::default();
+
+        let result: ingester::Result<()> = async {
+            let mut ingestion = self.begin_ingestion().await?;
+            let mut metric_indexes = HashMap::new();
+
+            let mut nb_elements = 0;
+            let mut nb_batches = 0;
+            while let Some(elements) = receiver.recv().await {
+                let previous_nb_elements = nb_elements;
+                nb_elements += elements.len() as u64;
+
+                let mut data_points = Vec::new();
+                let mut workouts: BTreeMap<String, Vec<workout::Workout>> = BTreeMap::new();
+                for element in elements {
+                    match element {
+                        Element::Record(record) => data_points.extend(mapper.map(record)),
+                        Element::ActivitySummary(summary) => {
+                            data_points.extend(mapper.map_activity_summary(summary))
+                        }
+                        Element::Workout(workout) => match workout.to_workout() {
+                            Ok(mapped) => workouts
+                                .entry(workout.source_name)
+                                .or_default()
+                                .push(mapped),
+                            Err(reason) => {
+                                let raw = serde_json::to_string(&workout).unwrap_or_default();
+                                warn!(%reason, %raw, "skipped an invalid workout");
+                            }
+                        },
+                    }
+                }
+                insert_data_points(&mut ingestion, &mut metric_indexes, data_points).await?;
+                for (source, workouts) in &workouts {
+                    ingestion.insert_workouts(source, workouts).await?;
+                }
+
+                nb_batches += 1;
+                if nb_batches % BATCHES_PER_INGESTION == 0 {
+                    summary.add(ingestion.finish(self.dry_run).await?);
+                    ingestion = self.begin_ingestion().await?;
+                    metric_indexes.clear();
+                }
+
+                if nb_elements / PROGRESS_INTERVAL != previous_nb_elements / PROGRESS_INTERVAL {
+                    info!(path = %path.display(), nb_elements, "importing Apple Health export");
+                }
+            }
+
+            insert_data_points(&mut ingestion, &mut metric_indexes, mapper.sleep_analysis())
+                .await?;
+            summary.add(ingestion.finish(self.dry_run).await?);
+
+            Ok(())
+        }
+        .await;
+
+        // Stops the reader if the insertion failed
+        drop(receiver);
+        let read_result = reader.await?;
+
+        result?;
+        let stats = read_result?;
+
+        for (type_, nb_records) in mapper.unmapped_types() {
+            info!(%type_, nb_records, "skipped records of an unknown type");
+        }
+        info!(
+            nb_records = stats.nb_records,
+            nb_workouts = stats.nb_workouts,
+            nb_activity_summaries = stats.nb_activity_summaries,
+            "read Apple Health export"
+        );
+
+        Ok(summary)
+    }
+
+    async fn begin_ingestion(&self) -> ingester::Result<Ingestion<'_>> {
+        Ingestion::begin(
+            &self.db,
+            None,
+            Origin {
+                user_id: self.user_id,
+                api_token_id: None,
+            },
+            self.strict,
+            &self.canonical_units,
+            &self.validator,
+        )
+        .await
+    }
+}
+
+/// Inserts the data points mapped from an Apple Health export, grouped by metric.
+///
+/// `metric_indexes` caches the index of every metric and units added to the ingestion.
+async fn insert_data_points(
+    ingestion: &mut Ingestion<'_>,
+    metric_indexes: &mut HashMap<(&'static str, String), usize>,
+    data_points: Vec<apple_health::DataPoint>,
+) -> ingester::Result<()> {
+    let mut batches: BTreeMap<usize, Vec<ParsedDataPoint>> = BTreeMap::new();
+
+    for apple_health::DataPoint {
+        metric,
+        units,
+        data_point,
+        raw,
+    } in data_points
+    {
+        let key = (metric, units);
+        let metric_index = match metric_indexes.get(&key) {
+            Some(metric_index) => *metric_index,
+            None => {
+                let metric_index = ingestion.add_metric(key.0, &key.1).await?;
+                metric_indexes.insert(key, metric_index);
+                metric_index
+            }
+        };
+
+        let parsed_data_point = match data_point {
+            Ok(mut data_point) => match ingestion.prepare(metric_index, &mut data_point) {
+                Ok(()) => ParsedDataPoint::Valid(data_point),
+                Err(reason) => ParsedDataPoint::Rejected(InvalidDataPoint {
+                    raw: raw.to_json(),
+                    reason,
+                }),
+            },
+            Err(reason) => ParsedDataPoint::Invalid(InvalidDataPoint {
+                raw: raw.to_json(),
+                reason,
+            }),
+        };
+
+        batches
+            .entry(metric_index)
+            .or_default()
+            .push(parsed_data_point);
+    }
+
+    for (metric_index, parsed_data_points) in batches {
+        ingestion.insert(metric_index, parsed_data_points).await?;
+    }
+
+    Ok(())
+}
+
+/// Returns the format of a file by its extension, `None` if it's not a known format.
+fn file_format(path: &Path) -> Option<archive::Format> {
+    let extension = path.extension().and_then(OsStr::to_str)?.to_lowercase();
+
+    match extension.as_str() {
+        "json" => Some(archive::Format::Json),
+        "fit" => Some(archive::Format::Fit),
+        "gpx" => Some(archive::Format::Gpx),
+        "tcx" => Some(archive::Format::Tcx),
+        _ => None,
+    }
+}
+
+fn format_summary(summary: &Summary) -> String {
+    let nb_duplicate: u64 = summary
+        .metrics
+        .values()
+        .map(|metric| metric.duplicate)
+        .sum();
+
+    let mut formatted = format!(
+        "{} metrics, {} inserted, {} duplicate, {} rejected",
+        summary.metrics.len(),
+        summary.nb_inserted(),
+        nb_duplicate,
+        summary.nb_rejected()
+    );
+    if summary.workouts.inserted > 0 || summary.workouts.duplicate > 0 {
+        formatted.push_str(&format!(
+            ", {} workouts inserted, {} duplicate",
+            summary.workouts.inserted, summary.workouts.duplicate
+        ));
+    }
+    if summary.workouts.attached > 0 {
+        formatted.push_str(&format!(
+            ", {} routes attached to workouts",
+            summary.workouts.attached
+        ));
+    }
+
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use crate::user;
+    use secrecy::ExposeSecret;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn collect_files_in_directories() {
+        let dir = std::env::temp_dir().join(format!("hdas-import-test-{}", rand::random::<u32>()));
+        fs::create_dir_all(dir.join("2022")).unwrap();
+        for name in ["2022/b.json", "2022/notes.txt", "a.json", "export.data"] {
+            fs::write(dir.join(name), "{}").unwrap();
+        }
+
+        let files = collect_files(&[dir.clone(), dir.join("export.data"), dir.join("a.json")]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let exp = vec![
+            dir.join("2022/b.json"),
+            dir.join("a.json"),
+            dir.join("export.data"),
+        ];
+        assert_eq!(exp, files.unwrap());
+
+        assert!(matches!(collect_files(&[dir]), Err(Error::Read { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_import_dry_run() {
+        let db = Arc::new(get_db().await);
+
+        let user_id = user::get_or_create_user(&db.pool, "import-test-user")
+            .await
+            .unwrap();
+
+        let path = std::env::temp_dir().join("hdas-import-test.json");
+        fs::write(
+            &path,
+            r#"{"data":{"metrics":[{"name":"step_count","units":"count","data":[
+                {"date":"2022-08-01 10:00:00 +0200","qty":120},
+                {"date":"2022-08-01 10:00:00 +0200","qty":120},
+                {"date":"2022-08-01 11:00:00 +0200","qty":"lots"}
+            ]}]}}"#,
+        )
+        .unwrap();
+
+        let importer = Importer {
+            db: db.clone(),
+            user_id,
+            strict: false,
+            dry_run: true,
+            batch_size: 100,
+            canonical_units: Arc::new(units::CanonicalUnits::default()),
+            validator: Arc::new(validation::Validator::new(&Default::default())),
+        };
+
+        // Nothing is stored so a second dry run gives the same summary
+        for _ in 0..2 {
+            let summary = importer.import_file(&path).await.unwrap();
+            let metric = &summary.metrics["step_count"];
+            assert_eq!(
+                (1, 1, 1),
+                (metric.inserted, metric.duplicate, metric.rejected)
+            );
+        }
+        fs::remove_file(&path).unwrap();
+
+        let record = sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM metric WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(0, record.count);
+    }
+
+    #[tokio::test]
+    async fn test_import_apple_health_export_in_transactions() {
+        let db = Arc::new(get_db().await);
+
+        let user_id = user::get_or_create_user(&db.pool, "import-test-user")
+            .await
+            .unwrap();
+
+        // With batches of a single record the export spans multiple transactions
+        let nb_records = BATCHES_PER_INGESTION * 2 + 5;
+        let mut export = String::from("<HealthData>\n");
+        for i in 0..nb_records {
+            export.push_str(&format!(
+                r#"<Record type="HKQuantityTypeIdentifierStepCount" sourceName="iPhone" unit="count" startDate="2023-04-01 10:{:02}:00 +0200" endDate="2023-04-01 10:{:02}:00 +0200" value="10"/>"#,
+                i, i
+            ));
+            export.push('\n');
+        }
+        export.push_str("</HealthData>\n");
+
+        let path = std::env::temp_dir().join("hdas-import-test-export.xml");
+        fs::write(&path, export).unwrap();
+
+        let importer = Importer {
+            db: db.clone(),
+            user_id,
+            strict: false,
+            dry_run: true,
+            batch_size: 1,
+            canonical_units: Arc::new(units::CanonicalUnits::default()),
+            validator: Arc::new(validation::Validator::new(&Default::default())),
+        };
+
+        let summary = importer.import_file(&path).await.unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let metric = &summary.metrics["step_count"];
+        assert_eq!(
+            (nb_records, 0, 0),
+            (metric.inserted, metric.duplicate, metric.rejected)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_apple_health_export_workouts() {
+        let db = Arc::new(get_db().await);
+
+        let user_id = user::get_or_create_user(&db.pool, "import-test-user")
+            .await
+            .unwrap();
+
+        let path = std::env::temp_dir().join("hdas-import-test-workouts.xml");
+        fs::write(
+            &path,
+            r#"<HealthData>
+<ExportDate value="2023-04-02 10:00:00 +0200"/>
+<Workout workoutActivityType="HKWorkoutActivityTypeRunning" duration="30" durationUnit="min" sourceName="Apple Watch" startDate="2023-04-01 18:00:00 +0200" endDate="2023-04-01 18:32:00 +0200"/>
+<Workout workoutActivityType="HKWorkoutActivityTypeRunning" sourceName="Apple Watch" startDate="yesterday" endDate="2023-04-01 19:00:00 +0200"/>
+<ActivitySummary dateComponents="2023-04-01" activeEnergyBurned="500" activeEnergyBurnedUnit="kcal" appleExerciseTime="35" appleStandHours="11"/>
+</HealthData>
+"#,
+        )
+        .unwrap();
+
+        let importer = Importer {
+            db: db.clone(),
+            user_id,
+            strict: false,
+            dry_run: true,
+            batch_size: 100,
+            canonical_units: Arc::new(units::CanonicalUnits::default()),
+            validator: Arc::new(validation::Validator::new(&Default::default())),
+        };
+
+        let summary = importer.import_file(&path).await.unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // The invalid workout is skipped
+        assert_eq!(
+            (1, 0),
+            (summary.workouts.inserted, summary.workouts.duplicate)
+        );
+        assert_eq!(
+            vec![
+                "activity_active_energy",
+                "activity_exercise_time",
+                "activity_stand_hours"
+            ],
+            summary.metrics.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(3, summary.nb_inserted());
+    }
+}
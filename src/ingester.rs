@@ -0,0 +1,1851 @@
+use crate::archive;
+use crate::archive::ParseOutcome;
+use crate::configuration::IngesterConfig;
+use crate::db;
+use crate::fit;
+use crate::health_data;
+use crate::metrics;
+use crate::route;
+use crate::shutdown::Shutdown;
+use crate::units;
+use crate::validation;
+use crate::workout::{self, Workout};
+use health_data::{
+    DataPointBatch, DataPointKind, GenericDataPoint, HeartRateDataPoint, InvalidDataPoint,
+    MetricDataPoint, ParsedDataPoint, PayloadOutline, SleepAnalysisDataPoint,
+};
+use sqlx::postgres::PgListener;
+use sqlx::types::Json;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    SQLx(#[from] sqlx::Error),
+    #[error(transparent)]
+    Archive(#[from] archive::Error),
+    #[error(transparent)]
+    Db(#[from] db::Error),
+    #[error("invalid payload: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Fit(#[from] fit::Error),
+    #[error(transparent)]
+    Route(#[from] route::Error),
+    #[error(transparent)]
+    Workout(#[from] workout::Error),
+    #[error("{0} invalid data points rejected in strict mode")]
+    Strict(usize),
+    #[error("units {from:?} of metric {metric:?} can't be converted to {to:?}")]
+    IncompatibleUnits {
+        metric: String,
+        from: String,
+        to: String,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Counts of the data points of a metric.
+#[derive(Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MetricSummary {
+    pub inserted: u64,
+    pub duplicate: u64,
+    pub rejected: u64,
+}
+
+/// Counts of the workouts of a FIT, GPX or TCX file.
+#[derive(Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WorkoutSummary {
+    pub inserted: u64,
+    pub duplicate: u64,
+    /// The routes attached to a workout already stored.
+    #[serde(default)]
+    pub attached: u64,
+}
+
+impl WorkoutSummary {
+    fn is_empty(&self) -> bool {
+        self.inserted == 0 && self.duplicate == 0 && self.attached == 0
+    }
+}
+
+/// Result of the ingestion of a payload, by metric name.
+#[derive(Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Summary {
+    #[serde(flatten)]
+    pub metrics: BTreeMap<String, MetricSummary>,
+    /// Next to the metrics so that the summaries stored before the workouts existed still parse.
+    #[serde(default, skip_serializing_if = "WorkoutSummary::is_empty")]
+    pub workouts: WorkoutSummary,
+}
+
+impl Summary {
+    pub fn nb_inserted(&self) -> u64 {
+        self.metrics.values().map(|metric| metric.inserted).sum()
+    }
+
+    pub fn nb_rejected(&self) -> u64 {
+        self.metrics.values().map(|metric| metric.rejected).sum()
+    }
+
+    /// Adds the counts of another summary to this one.
+    pub fn add(&mut self, other: Summary) {
+        for (name, metric) in other.metrics {
+            let total = self.metrics.entry(name).or_default();
+            total.inserted += metric.inserted;
+            total.duplicate += metric.duplicate;
+            total.rejected += metric.rejected;
+        }
+        self.workouts.inserted += other.workouts.inserted;
+        self.workouts.duplicate += other.workouts.duplicate;
+        self.workouts.attached += other.workouts.attached;
+    }
+}
+
+/// Who the ingested data points belong to.
+#[derive(Debug, Clone, Copy)]
+pub struct Origin {
+    pub user_id: i64,
+    /// The API token used to upload the data points, if any.
+    pub api_token_id: Option<i64>,
+}
+
+/// Parses a health data payload and inserts all its metrics and data points.
+///
+/// The payload is parsed incrementally and the data points inserted in batches of `batch_size`
+/// as they're parsed, so the memory used doesn't depend on the size of the payload.
+///
+/// Invalid data points are skipped and stored in the `rejected_data_point` table.
+/// In strict mode any invalid data point fails the ingestion and nothing is inserted.
+///
+/// The data points are converted to the units of their metric, which are the canonical units
+/// if configured. The ingestion fails if a metric is uploaded in units that can't be converted.
+/// The converted data points breaking a validation rule are rejected like the invalid ones.
+///
+/// In dry run mode everything is done but rolled back, the summary tells what would have been inserted.
+#[allow(clippy::too_many_arguments)]
+pub async fn ingest_payload(
+    db: &db::Db,
+    upload_id: Option<i64>,
+    origin: Origin,
+    body: Arc<archive::Body>,
+    strict: bool,
+    dry_run: bool,
+    batch_size: usize,
+    canonical_units: &units::CanonicalUnits,
+    validator: &validation::Validator,
+) -> Result<Summary> {
+    // The name and units of a metric can come after its data points, get them first
+
+    let outline_body = body.clone();
+    let outline: PayloadOutline = tokio::task::spawn_blocking(move || {
+        serde_json::from_reader(io::BufReader::new(outline_body.reader()))
+    })
+    .await??;
+    let metrics = outline.data.metrics;
+
+    let mut ingestion =
+        Ingestion::begin(db, upload_id, origin, strict, canonical_units, validator).await?;
+
+    for metric in &metrics {
+        ingestion.add_metric(&metric.name, &metric.units).await?;
+
+        if metric.nb_data_points > 0 {
+            info!(
+                metric_name = metric.name,
+                metric_datapoints = metric.nb_data_points,
+                metric_units = metric.units,
+                "got data points",
+            );
+        }
+    }
+
+    // The type of the data points is decided by the metric name, not by their fields
+
+    let kinds: Vec<_> = metrics
+        .iter()
+        .map(|metric| match health_data::lookup_metric(&metric.name) {
+            Some(kind) => kind,
+            None => {
+                warn!(
+                    metric_name = metric.name,
+                    "unknown metric, parsing its data points as generic"
+                );
+                metrics::UNKNOWN_METRICS
+                    .with_label_values(&[&metric.name])
+                    .inc();
+
+                DataPointKind::Generic
+            }
+        })
+        .collect();
+
+    // Parse the data points in a blocking task and insert them as they come,
+    // every data point is converted then validated as it's parsed
+
+    let prepare = ingestion.preparer();
+
+    let (sender, mut receiver) = mpsc::channel::<DataPointBatch>(2);
+    let parser = tokio::task::spawn_blocking(move || {
+        health_data::parse_data_points(body.reader(), &kinds, batch_size, prepare, |batch| {
+            sender
+                .blocking_send(batch)
+                .map_err(|_| "ingestion aborted".to_owned())
+        })
+    });
+
+    let result: Result<()> = async {
+        while let Some(batch) = receiver.recv().await {
+            ingestion
+                .insert(batch.metric_index, batch.data_points)
+                .await?;
+        }
+
+        Ok(())
+    }
+    .await;
+
+    // Unblocks the parser if the insertion stopped early
+    drop(receiver);
+    let parse_result = parser.await?;
+
+    result?;
+    parse_result?;
+
+    ingestion.finish(dry_run).await
+}
+
+/// Decodes a FIT file and inserts its data points and workouts.
+///
+/// The data points are converted and validated like the ones of a payload.
+/// The workouts already stored are skipped, with their laps and records.
+#[allow(clippy::too_many_arguments)]
+pub async fn ingest_fit(
+    db: &db::Db,
+    upload_id: Option<i64>,
+    origin: Origin,
+    body: Arc<archive::Body>,
+    strict: bool,
+    dry_run: bool,
+    canonical_units: &units::CanonicalUnits,
+    validator: &validation::Validator,
+) -> Result<Summary> {
+    let fit_file = tokio::task::spawn_blocking(move || -> Result<fit::FitFile> {
+        let mut data = Vec::new();
+        body.reader().read_to_end(&mut data)?;
+
+        Ok(fit::decode(&data)?)
+    })
+    .await??;
+
+    info!(
+        source = fit_file.source,
+        nb_data_points = fit_file.data_points.len(),
+        nb_workouts = fit_file.workouts.len(),
+        "decoded FIT file"
+    );
+
+    let mut ingestion =
+        Ingestion::begin(db, upload_id, origin, strict, canonical_units, validator).await?;
+
+    let mut metric_indexes = HashMap::new();
+    let mut batches: BTreeMap<usize, Vec<ParsedDataPoint>> = BTreeMap::new();
+    for data_point in fit_file.data_points {
+        let metric_index = match metric_indexes.get(&(data_point.metric, data_point.units)) {
+            Some(metric_index) => *metric_index,
+            None => {
+                let metric_index = ingestion
+                    .add_metric(data_point.metric, data_point.units)
+                    .await?;
+                metric_indexes.insert((data_point.metric, data_point.units), metric_index);
+                metric_index
+            }
+        };
+
+        let raw = data_point.to_json();
+        let mut metric_data_point = data_point.data_point;
+        let parsed_data_point = match ingestion.prepare(metric_index, &mut metric_data_point) {
+            Ok(()) => ParsedDataPoint::Valid(metric_data_point),
+            Err(reason) => ParsedDataPoint::Rejected(InvalidDataPoint { raw, reason }),
+        };
+
+        batches
+            .entry(metric_index)
+            .or_default()
+            .push(parsed_data_point);
+    }
+
+    for (metric_index, parsed_data_points) in batches {
+        ingestion.insert(metric_index, parsed_data_points).await?;
+    }
+
+    ingestion
+        .insert_workouts(&fit_file.source, &fit_file.workouts)
+        .await?;
+
+    ingestion.finish(dry_run).await
+}
+
+/// Decodes a GPX or TCX file and inserts its tracks, their points are records of workouts.
+///
+/// A track is attached to the workout of the user it overlaps, recorded by a watch for example.
+/// A workout is only created for the tracks that don't overlap any.
+pub async fn ingest_route(
+    db: &db::Db,
+    upload_id: Option<i64>,
+    origin: Origin,
+    body: Arc<archive::Body>,
+    dry_run: bool,
+    canonical_units: &units::CanonicalUnits,
+    validator: &validation::Validator,
+) -> Result<Summary> {
+    let route_file = tokio::task::spawn_blocking(move || -> Result<route::RouteFile> {
+        let mut data = Vec::new();
+        body.reader().read_to_end(&mut data)?;
+
+        Ok(route::decode(&data)?)
+    })
+    .await??;
+
+    info!(
+        source = route_file.source,
+        nb_workouts = route_file.workouts.len(),
+        "decoded route file"
+    );
+
+    // There are no data points to reject so the strict mode doesn't matter
+    let mut ingestion =
+        Ingestion::begin(db, upload_id, origin, false, canonical_units, validator).await?;
+
+    ingestion
+        .insert_routes(&route_file.source, &route_file.workouts)
+        .await?;
+
+    ingestion.finish(dry_run).await
+}
+
+/// A metric of an ingestion, in the units it was uploaded in.
+struct IngestedMetric {
+    name: String,
+    units: String,
+    id: i64,
+    conversion: units::Conversion,
+    rules: validation::Rules,
+}
+
+/// Inserts the data points of a payload.
+///
+/// Everything is inserted in a single transaction so that a payload is either
+/// fully ingested or not at all, which makes retrying it safe.
+pub struct Ingestion<'a> {
+    pool: sqlx::PgPool,
+    tx: db::Transaction,
+    /// ID of the transaction, registered for the exporter.
+    xid: i64,
+    /// In strict mode the rejected data points are kept for inspection even though
+    /// the ingestion is rolled back.
+    rejected_tx: Option<db::Transaction>,
+    upload_id: Option<i64>,
+    origin: Origin,
+    canonical_units: &'a units::CanonicalUnits,
+    validator: &'a validation::Validator,
+    now: time::OffsetDateTime,
+    metrics: Vec<IngestedMetric>,
+    summary: Summary,
+    nb_rejected: usize,
+}
+
+impl<'a> Ingestion<'a> {
+    pub async fn begin(
+        db: &db::Db,
+        upload_id: Option<i64>,
+        origin: Origin,
+        strict: bool,
+        canonical_units: &'a units::CanonicalUnits,
+        validator: &'a validation::Validator,
+    ) -> Result<Ingestion<'a>> {
+        let mut tx = db.pool.begin().await?;
+        let xid = db::register_ingestion(&db.pool, &mut tx).await?;
+        let rejected_tx = if strict {
+            Some(db.pool.begin().await?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            pool: db.pool.clone(),
+            tx,
+            xid,
+            rejected_tx,
+            upload_id,
+            origin,
+            canonical_units,
+            validator,
+            now: time::OffsetDateTime::now_utc(),
+            metrics: Vec::new(),
+            summary: Summary::default(),
+            nb_rejected: 0,
+        })
+    }
+
+    /// Adds a metric with data points in `units`, returns its index.
+    ///
+    /// The same metric can be added multiple times with different units.
+    pub async fn add_metric(&mut self, name: &str, units: &str) -> Result<usize> {
+        self.summary.metrics.entry(name.to_owned()).or_default();
+
+        let (id, metric_units) = insert_metric(
+            &mut self.tx,
+            self.origin.user_id,
+            name,
+            units,
+            self.canonical_units.get(name),
+        )
+        .await?;
+
+        let conversion = units::Conversion::new(units, &metric_units).ok_or_else(|| {
+            Error::IncompatibleUnits {
+                metric: name.to_owned(),
+                from: units.to_owned(),
+                to: metric_units.clone(),
+            }
+        })?;
+        if units != metric_units {
+            debug!(
+                metric_name = name,
+                from = units,
+                to = metric_units,
+                "converting data points"
+            );
+        }
+
+        self.metrics.push(IngestedMetric {
+            name: name.to_owned(),
+            units: units.to_owned(),
+            id,
+            conversion,
+            rules: self.validator.rules(name, self.now),
+        });
+
+        Ok(self.metrics.len() - 1)
+    }
+
+    /// Converts a data point of the metric at `metric_index` to the units of the metric and validates it.
+    pub fn prepare(
+        &self,
+        metric_index: usize,
+        data_point: &mut MetricDataPoint,
+    ) -> std::result::Result<(), String> {
+        let metric = &self.metrics[metric_index];
+        prepare_data_point(&metric.name, metric.conversion, &metric.rules, data_point)
+    }
+
+    /// Returns a function doing the same as [`Ingestion::prepare`] for the metrics added so far,
+    /// usable from another thread.
+    pub fn preparer(
+        &self,
+    ) -> impl FnMut(usize, &mut MetricDataPoint) -> std::result::Result<(), String> + Send + 'static
+    {
+        let metrics: Vec<_> = self
+            .metrics
+            .iter()
+            .map(|metric| (metric.name.clone(), metric.conversion, metric.rules.clone()))
+            .collect();
+
+        move |metric_index, data_point| {
+            let (name, conversion, rules) = &metrics[metric_index];
+            prepare_data_point(name, *conversion, rules, data_point)
+        }
+    }
+
+    /// Inserts data points of the metric at `metric_index`, the valid ones must have been prepared.
+    pub async fn insert(
+        &mut self,
+        metric_index: usize,
+        parsed_data_points: Vec<ParsedDataPoint>,
+    ) -> Result<()> {
+        let metric = &self.metrics[metric_index];
+
+        let mut data_points = Vec::with_capacity(parsed_data_points.len());
+        let mut rejected_data_points = Vec::new();
+        let mut nb_mismatched = 0;
+        for data_point in parsed_data_points {
+            match data_point {
+                ParsedDataPoint::Valid(data_point) => data_points.push(data_point),
+                ParsedDataPoint::Invalid(data_point) => {
+                    nb_mismatched += 1;
+                    rejected_data_points.push((metric.name.clone(), data_point));
+                }
+                ParsedDataPoint::Rejected(data_point) => {
+                    rejected_data_points.push((metric.name.clone(), data_point));
+                }
+            }
+        }
+
+        if nb_mismatched > 0 {
+            warn!(
+                metric_name = metric.name,
+                nb_mismatched, "data points don't match the type of their metric",
+            );
+            metrics::MISMATCHED_DATA_POINTS
+                .with_label_values(&[&metric.name])
+                .inc_by(nb_mismatched);
+        }
+
+        let metric_summary = self.summary.metrics.entry(metric.name.clone()).or_default();
+
+        if !rejected_data_points.is_empty() {
+            self.nb_rejected += rejected_data_points.len();
+            metric_summary.rejected += rejected_data_points.len() as u64;
+
+            let rejected_tx = match self.rejected_tx {
+                Some(ref mut rejected_tx) => rejected_tx,
+                None => &mut self.tx,
+            };
+            insert_rejected_data_points(rejected_tx, self.upload_id, &rejected_data_points).await?;
+        }
+
+        if !data_points.is_empty() {
+            let inserted = insert_metric_data_points(
+                &mut self.tx,
+                metric.id,
+                self.origin,
+                &metric.units,
+                &data_points,
+            )
+            .await?;
+            metric_summary.inserted += inserted;
+            metric_summary.duplicate += data_points.len() as u64 - inserted;
+        }
+
+        Ok(())
+    }
+
+    /// Inserts workouts recorded by `source`, the workouts already stored are counted as duplicates.
+    pub async fn insert_workouts(&mut self, source: &str, workouts: &[Workout]) -> Result<()> {
+        if workouts.is_empty() {
+            return Ok(());
+        }
+
+        let sources = insert_sources(&mut self.tx, [source]).await?;
+        let source_id = sources[source];
+
+        for workout in workouts {
+            let inserted = workout::insert_workout(
+                &mut self.tx,
+                self.origin.user_id,
+                self.origin.api_token_id,
+                source_id,
+                workout,
+            )
+            .await?;
+
+            if inserted {
+                self.summary.workouts.inserted += 1;
+            } else {
+                self.summary.workouts.duplicate += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Inserts the tracks recorded by `source`, attached to the workouts they overlap.
+    ///
+    /// The tracks that don't add any record to the workout they overlap are counted as duplicates,
+    /// the ones overlapping no workout are inserted as workouts.
+    pub async fn insert_routes(&mut self, source: &str, routes: &[Workout]) -> Result<()> {
+        let mut new_workouts = Vec::new();
+        for route in routes {
+            match workout::attach_route(&mut self.tx, self.origin.user_id, route).await? {
+                Some(0) => self.summary.workouts.duplicate += 1,
+                Some(_) => self.summary.workouts.attached += 1,
+                None => new_workouts.push(route.clone()),
+            }
+        }
+
+        self.insert_workouts(source, &new_workouts).await
+    }
+
+    /// Commits the ingestion, or rolls it back in dry run mode.
+    pub async fn finish(self, dry_run: bool) -> Result<Summary> {
+        let pool = self.pool.clone();
+        let xid = self.xid;
+
+        let result = self.end(dry_run).await;
+        db::unregister_ingestion(&pool, xid).await?;
+
+        result
+    }
+
+    async fn end(mut self, dry_run: bool) -> Result<Summary> {
+        if self.nb_rejected > 0 {
+            warn!(
+                nb_rejected = self.nb_rejected,
+                "rejected invalid data points"
+            );
+
+            if let Some(rejected_tx) = self.rejected_tx {
+                // Nothing is ingested but the rejected data points are still kept for inspection
+                self.tx.rollback().await?;
+                if dry_run {
+                    rejected_tx.rollback().await?;
+                } else {
+                    rejected_tx.commit().await?;
+                }
+
+                return Err(Error::Strict(self.nb_rejected));
+            }
+        }
+
+        if dry_run {
+            self.tx.rollback().await?;
+            return Ok(self.summary);
+        }
+
+        if self.summary.nb_inserted() > 0 {
+            db::notify_new_data(&mut self.tx).await?;
+        }
+
+        self.tx.commit().await?;
+
+        Ok(self.summary)
+    }
+}
+
+fn prepare_data_point(
+    metric_name: &str,
+    conversion: units::Conversion,
+    rules: &validation::Rules,
+    data_point: &mut MetricDataPoint,
+) -> std::result::Result<(), String> {
+    data_point.map_values(|value| conversion.apply(value));
+
+    rules.check(data_point).map_err(|violation| {
+        metrics::INVALID_DATA_POINTS
+            .with_label_values(&[metric_name, violation.rule()])
+            .inc();
+
+        violation.to_string()
+    })
+}
+
+/// Processes the uploads queued by the web server.
+///
+/// Multiple ingesters can run concurrently, each upload is claimed by a single one.
+pub struct Ingester {
+    db: Arc<db::Db>,
+    poll_interval: Duration,
+    batch_size: usize,
+    canonical_units: Arc<units::CanonicalUnits>,
+    validator: Arc<validation::Validator>,
+}
+
+impl Ingester {
+    pub fn new(
+        db: Arc<db::Db>,
+        config: &IngesterConfig,
+        canonical_units: Arc<units::CanonicalUnits>,
+        validator: Arc<validation::Validator>,
+    ) -> Self {
+        Self {
+            db,
+            poll_interval: config.poll_interval(),
+            batch_size: config.batch_size,
+            canonical_units,
+            validator,
+        }
+    }
+
+    pub async fn run(mut self, mut shutdown: Shutdown) -> Result<()> {
+        let mut listener = PgListener::connect_with(&self.db.pool).await?;
+        listener.listen(db::UPLOAD_CHANNEL).await?;
+
+        let mut interval = tokio::time::interval(self.poll_interval);
+
+        // Set when the listener failed, it's not polled again until the deadline is reached.
+        let mut listen_retry_deadline: Option<Instant> = None;
+
+        'outer_loop: loop {
+            tokio::select! {
+                _ = shutdown.recv() => {
+                    info!("ingester shutting down");
+                    break 'outer_loop;
+                },
+                _ = tokio::time::sleep_until(listen_retry_deadline.unwrap_or_else(Instant::now)), if listen_retry_deadline.is_some() => {
+                    listen_retry_deadline = None;
+                },
+                notification = listener.try_recv(), if listen_retry_deadline.is_none() => {
+                    match notification {
+                        Ok(Some(_)) => {},
+                        Ok(None) => {
+                            // The connection was lost and will be reestablished on the next call,
+                            // notifications may have been missed in the meantime so look anyway.
+                            warn!("lost connection to the database, reconnecting listener");
+                        },
+                        Err(err) => {
+                            error!(%err, "unable to receive notification");
+                            listen_retry_deadline = Some(Instant::now() + Duration::from_secs(1));
+                        },
+                    }
+
+                    self.process_pending_uploads().await;
+                },
+                _ = interval.tick() => {
+                    self.process_pending_uploads().await;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn process_pending_uploads(&mut self) {
+        loop {
+            match self.process_next_upload().await {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => {
+                    error!(%err, "unable to process upload");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Processes the oldest pending upload, returns false if there was none.
+    async fn process_next_upload(&mut self) -> Result<bool> {
+        let upload = match claim_upload(&self.db.pool).await? {
+            Some(upload) => upload,
+            None => return Ok(false),
+        };
+
+        debug!(
+            upload_id = upload.id,
+            raw_upload_id = upload.raw_upload_id,
+            "processing upload"
+        );
+
+        let origin = Origin {
+            user_id: upload.user_id,
+            api_token_id: upload.api_token_id,
+        };
+        let result = match archive::load_body(&self.db.pool, upload.raw_upload_id).await {
+            Ok(body) if body.format() == archive::Format::Fit => {
+                ingest_fit(
+                    &self.db,
+                    Some(upload.id),
+                    origin,
+                    Arc::new(body),
+                    upload.strict,
+                    false,
+                    &self.canonical_units,
+                    &self.validator,
+                )
+                .await
+            }
+            Ok(body) if matches!(body.format(), archive::Format::Gpx | archive::Format::Tcx) => {
+                ingest_route(
+                    &self.db,
+                    Some(upload.id),
+                    origin,
+                    Arc::new(body),
+                    false,
+                    &self.canonical_units,
+                    &self.validator,
+                )
+                .await
+            }
+            Ok(body) => {
+                ingest_payload(
+                    &self.db,
+                    Some(upload.id),
+                    origin,
+                    Arc::new(body),
+                    upload.strict,
+                    false,
+                    self.batch_size,
+                    &self.canonical_units,
+                    &self.validator,
+                )
+                .await
+            }
+            Err(err) => Err(err.into()),
+        };
+
+        // Record if the body could be parsed, the database errors happen after the parsing
+
+        let parse_outcome = match &result {
+            Ok(summary) if summary.nb_rejected() > 0 => Some(ParseOutcome::Partial(format!(
+                "{} invalid data points rejected",
+                summary.nb_rejected()
+            ))),
+            Ok(_) => Some(ParseOutcome::Ok),
+            Err(
+                err @ (Error::Json(_)
+                | Error::Fit(_)
+                | Error::Route(_)
+                | Error::Strict(_)
+                | Error::IncompatibleUnits { .. }),
+            ) => Some(ParseOutcome::Error(err.to_string())),
+            Err(Error::Archive(_)) => None,
+            Err(_) => Some(ParseOutcome::Ok),
+        };
+        if let Some(parse_outcome) = parse_outcome {
+            archive::set_parse_outcome(&self.db.pool, upload.raw_upload_id, parse_outcome).await?;
+        }
+
+        match result {
+            Ok(summary) => {
+                info!(
+                    upload_id = upload.id,
+                    nb_metrics = summary.metrics.len(),
+                    nb_inserted = summary.nb_inserted(),
+                    nb_rejected = summary.nb_rejected(),
+                    "processed upload"
+                );
+
+                sqlx::query!(
+                    r#"
+                    UPDATE upload
+                    SET
+                      status = 'done', finished_at = now(),
+                      nb_metrics = $2, nb_data_points = $3, summary = $4
+                    WHERE id = $1"#,
+                    upload.id,
+                    summary.metrics.len() as i64,
+                    summary.nb_inserted() as i64,
+                    Json(&summary) as _,
+                )
+                .execute(&self.db.pool)
+                .await?;
+            }
+            Err(err) => {
+                error!(upload_id = upload.id, %err, "unable to process upload");
+
+                sqlx::query!(
+                    r#"
+                    UPDATE upload
+                    SET status = 'failed', finished_at = now(), error = $2
+                    WHERE id = $1"#,
+                    upload.id,
+                    err.to_string(),
+                )
+                .execute(&self.db.pool)
+                .await?;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Puts back in the queue the uploads that were being processed when the server stopped.
+pub async fn requeue_interrupted_uploads(db: &db::Db) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE upload
+        SET status = 'pending', started_at = NULL
+        WHERE status = 'processing'"#
+    )
+    .execute(&db.pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Queues an archived raw upload for ingestion, returns the ID of the upload.
+///
+/// A raw upload can be queued multiple times to replay it.
+pub async fn enqueue_upload(
+    tx: &mut db::Transaction,
+    raw_upload_id: i64,
+    strict: bool,
+) -> Result<i64> {
+    let record = sqlx::query!(
+        r#"INSERT INTO upload(raw_upload_id, strict) VALUES($1, $2) RETURNING id"#,
+        raw_upload_id,
+        strict,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    db::notify_new_upload(tx).await?;
+
+    Ok(record.id)
+}
+
+#[derive(serde::Serialize)]
+pub struct Upload {
+    pub id: i64,
+    pub raw_upload_id: i64,
+    pub user_id: i64,
+    pub status: String,
+    pub strict: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub started_at: Option<time::OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub finished_at: Option<time::OffsetDateTime>,
+    pub nb_metrics: Option<i64>,
+    pub nb_data_points: Option<i64>,
+    pub error: Option<String>,
+    pub summary: Option<Json<Summary>>,
+}
+
+pub async fn get_upload<'e, E>(executor: E, id: i64) -> Result<Option<Upload>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let upload = sqlx::query_as!(
+        Upload,
+        r#"
+        SELECT
+          u.id, u.raw_upload_id, ru.user_id, u.status, u.strict,
+          u.created_at, u.started_at, u.finished_at,
+          u.nb_metrics, u.nb_data_points, u.error,
+          u.summary as "summary: Json<Summary>"
+        FROM upload u
+        INNER JOIN raw_upload ru ON ru.id = u.raw_upload_id
+        WHERE u.id = $1"#,
+        id,
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(upload)
+}
+
+struct ClaimedUpload {
+    id: i64,
+    raw_upload_id: i64,
+    user_id: i64,
+    api_token_id: Option<i64>,
+    strict: bool,
+}
+
+async fn claim_upload(pool: &sqlx::PgPool) -> Result<Option<ClaimedUpload>> {
+    let upload = sqlx::query_as!(
+        ClaimedUpload,
+        r#"
+        UPDATE upload u
+        SET status = 'processing', started_at = now()
+        FROM raw_upload ru
+        WHERE u.id = (
+          SELECT id FROM upload
+          WHERE status = 'pending'
+          ORDER BY id
+          FOR UPDATE SKIP LOCKED
+          LIMIT 1
+        )
+        AND ru.id = u.raw_upload_id
+        RETURNING u.id, u.raw_upload_id, ru.user_id, ru.api_token_id, u.strict"#
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(upload)
+}
+
+#[derive(serde::Serialize)]
+pub struct RejectedDataPoint {
+    pub metric_name: String,
+    pub raw: serde_json::Value,
+    pub reason: String,
+}
+
+pub async fn get_rejected_data_points<'e, E>(
+    executor: E,
+    upload_id: i64,
+) -> Result<Vec<RejectedDataPoint>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let rejected_data_points = sqlx::query_as!(
+        RejectedDataPoint,
+        r#"
+        SELECT metric_name, raw, reason
+        FROM rejected_data_point
+        WHERE upload_id = $1
+        ORDER BY id"#,
+        upload_id,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rejected_data_points)
+}
+
+async fn insert_rejected_data_points(
+    tx: &mut db::Transaction,
+    upload_id: Option<i64>,
+    data_points: &[(String, InvalidDataPoint)],
+) -> Result<()> {
+    let mut metric_names = Vec::with_capacity(data_points.len());
+    let mut raws = Vec::with_capacity(data_points.len());
+    let mut reasons = Vec::with_capacity(data_points.len());
+    for (metric_name, data_point) in data_points {
+        metric_names.push(metric_name.clone());
+        raws.push(data_point.raw.clone());
+        reasons.push(data_point.reason.clone());
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO rejected_data_point(upload_id, metric_name, raw, reason)
+        SELECT $1, metric_name, raw::jsonb, reason
+        FROM UNNEST($2::text[], $3::text[], $4::text[]) AS t(metric_name, raw, reason)"#,
+        upload_id,
+        &metric_names,
+        &raws,
+        &reasons,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Inserts a metric if it doesn't exist, returns its ID and units.
+///
+/// A metric keeps the units of its first upload unless it has canonical units.
+async fn insert_metric(
+    tx: &mut db::Transaction,
+    user_id: i64,
+    name: &str,
+    units: &str,
+    canonical_units: Option<&str>,
+) -> Result<(i64, String)> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO metric(user_id, name, units) VALUES($1, $2, COALESCE($4, $3))
+        ON CONFLICT (user_id, name) DO UPDATE SET units = COALESCE($4, metric.units)
+        RETURNING id, units"#,
+        user_id,
+        name,
+        units,
+        canonical_units,
+    )
+    .fetch_one(tx)
+    .await?;
+
+    Ok((result.id, result.units))
+}
+
+/// Inserts the data points of a metric, with one statement per data point type.
+///
+/// Returns the number of data points inserted, data points already present are ignored.
+async fn insert_metric_data_points(
+    tx: &mut db::Transaction,
+    metric_id: i64,
+    origin: Origin,
+    original_units: &str,
+    data_points: &[MetricDataPoint],
+) -> Result<u64> {
+    let mut heart_rate_data_points = Vec::new();
+    let mut sleep_analysis_data_points = Vec::new();
+    let mut generic_data_points = Vec::new();
+
+    for data_point in data_points {
+        match data_point {
+            MetricDataPoint::HeartRate(data_point) => heart_rate_data_points.push(data_point),
+            MetricDataPoint::SleepAnalysis(data_point) => {
+                sleep_analysis_data_points.push(data_point)
+            }
+            MetricDataPoint::Generic(data_point) => generic_data_points.push(data_point),
+        }
+    }
+
+    let source_names = heart_rate_data_points
+        .iter()
+        .map(|data_point| data_point.source.as_deref())
+        .chain(
+            generic_data_points
+                .iter()
+                .map(|data_point| data_point.source.as_deref()),
+        )
+        .map(|source| source.unwrap_or_default());
+    let sources = insert_sources(tx, source_names).await?;
+
+    let mut inserted = 0;
+    inserted += insert_heart_rate_data_points(
+        tx,
+        metric_id,
+        origin,
+        original_units,
+        &sources,
+        &heart_rate_data_points,
+    )
+    .await?;
+    inserted += insert_sleep_analysis_data_points(
+        tx,
+        metric_id,
+        origin,
+        original_units,
+        &sleep_analysis_data_points,
+    )
+    .await?;
+    inserted += insert_generic_data_points(
+        tx,
+        metric_id,
+        origin,
+        original_units,
+        &sources,
+        &generic_data_points,
+    )
+    .await?;
+
+    Ok(inserted)
+}
+
+/// Inserts the sources that don't exist yet, returns the ID of every source by name.
+///
+/// The data points without a source reference the source with an empty name.
+async fn insert_sources<'a, I>(tx: &mut db::Transaction, names: I) -> Result<HashMap<String, i64>>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    // Sorted so that concurrent ingestions lock the sources in the same order
+    let mut names: Vec<_> = names.into_iter().collect();
+    names.sort_unstable();
+    names.dedup();
+
+    if names.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let records = sqlx::query!(
+        r#"
+        INSERT INTO source(name)
+        SELECT * FROM UNNEST($1::text[])
+        ON CONFLICT (name) DO UPDATE SET name = excluded.name
+        RETURNING id, name"#,
+        &names as _,
+    )
+    .fetch_all(tx)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|record| (record.name, record.id))
+        .collect())
+}
+
+fn source_id(sources: &HashMap<String, i64>, source: &Option<String>) -> i64 {
+    sources[source.as_deref().unwrap_or_default()]
+}
+
+async fn insert_heart_rate_data_points(
+    tx: &mut db::Transaction,
+    metric_id: i64,
+    origin: Origin,
+    original_units: &str,
+    sources: &HashMap<String, i64>,
+    data_points: &[&HeartRateDataPoint],
+) -> Result<u64> {
+    if data_points.is_empty() {
+        return Ok(0);
+    }
+
+    let mut dates = Vec::with_capacity(data_points.len());
+    let mut mins = Vec::with_capacity(data_points.len());
+    let mut maxs = Vec::with_capacity(data_points.len());
+    let mut avgs = Vec::with_capacity(data_points.len());
+    let mut source_ids = Vec::with_capacity(data_points.len());
+    for data_point in data_points {
+        dates.push(data_point.date);
+        source_ids.push(source_id(sources, &data_point.source));
+        mins.push(data_point.min);
+        maxs.push(data_point.max);
+        avgs.push(data_point.avg);
+    }
+
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO data_point_heart_rate(
+          metric_id, user_id, api_token_id, original_units, date, min, max, avg, source_id
+        )
+        SELECT $1, $2, $3, $4, *
+        FROM UNNEST($5::timestamptz[], $6::float8[], $7::float8[], $8::float8[], $9::int8[])
+        ON CONFLICT DO NOTHING"#,
+        metric_id,
+        origin.user_id,
+        origin.api_token_id,
+        original_units,
+        &dates,
+        &mins,
+        &maxs,
+        &avgs,
+        &source_ids,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+async fn insert_sleep_analysis_data_points(
+    tx: &mut db::Transaction,
+    metric_id: i64,
+    origin: Origin,
+    original_units: &str,
+    data_points: &[&SleepAnalysisDataPoint],
+) -> Result<u64> {
+    if data_points.is_empty() {
+        return Ok(0);
+    }
+
+    let mut dates = Vec::with_capacity(data_points.len());
+    let mut sleep_starts = Vec::with_capacity(data_points.len());
+    let mut sleep_ends = Vec::with_capacity(data_points.len());
+    let mut sleep_sources = Vec::with_capacity(data_points.len());
+    let mut in_bed_starts = Vec::with_capacity(data_points.len());
+    let mut in_bed_ends = Vec::with_capacity(data_points.len());
+    let mut in_bed_sources = Vec::with_capacity(data_points.len());
+    let mut in_beds = Vec::with_capacity(data_points.len());
+    let mut asleeps = Vec::with_capacity(data_points.len());
+    for data_point in data_points {
+        dates.push(data_point.date);
+        sleep_starts.push(data_point.sleep_start);
+        sleep_ends.push(data_point.sleep_end);
+        sleep_sources.push(data_point.sleep_source.clone());
+        in_bed_starts.push(data_point.in_bed_start);
+        in_bed_ends.push(data_point.in_bed_end);
+        in_bed_sources.push(data_point.in_bed_source.clone());
+        in_beds.push(data_point.in_bed);
+        asleeps.push(data_point.asleep);
+    }
+
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO data_point_sleep_analysis(
+          metric_id, user_id, api_token_id, original_units, date,
+          sleep_start, sleep_end, sleep_source,
+          in_bed_start, in_bed_end, in_bed_source,
+          in_bed, asleep
+        )
+        SELECT $1, $2, $3, $4, *
+        FROM UNNEST(
+          $5::timestamptz[],
+          $6::timestamptz[], $7::timestamptz[], $8::text[],
+          $9::timestamptz[], $10::timestamptz[], $11::text[],
+          $12::float8[], $13::float8[]
+        )
+        ON CONFLICT DO NOTHING"#,
+        metric_id,
+        origin.user_id,
+        origin.api_token_id,
+        original_units,
+        &dates,
+        &sleep_starts,
+        &sleep_ends,
+        &sleep_sources,
+        &in_bed_starts,
+        &in_bed_ends,
+        &in_bed_sources,
+        &in_beds,
+        &asleeps,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+async fn insert_generic_data_points(
+    tx: &mut db::Transaction,
+    metric_id: i64,
+    origin: Origin,
+    original_units: &str,
+    sources: &HashMap<String, i64>,
+    data_points: &[&GenericDataPoint],
+) -> Result<u64> {
+    if data_points.is_empty() {
+        return Ok(0);
+    }
+
+    let mut dates = Vec::with_capacity(data_points.len());
+    let mut quantities = Vec::with_capacity(data_points.len());
+    let mut source_ids = Vec::with_capacity(data_points.len());
+    for data_point in data_points {
+        dates.push(data_point.date);
+        quantities.push(data_point.quantity);
+        source_ids.push(source_id(sources, &data_point.source));
+    }
+
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO data_point_generic(
+          metric_id, user_id, api_token_id, original_units, date, quantity, source_id
+        )
+        SELECT $1, $2, $3, $4, *
+        FROM UNNEST($5::timestamptz[], $6::float8[], $7::int8[])
+        ON CONFLICT DO NOTHING"#,
+        metric_id,
+        origin.user_id,
+        origin.api_token_id,
+        original_units,
+        &dates,
+        &quantities,
+        &source_ids,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+    use crate::configuration;
+    use crate::db;
+    use crate::user;
+    use health_data::*;
+    use secrecy::ExposeSecret;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    async fn test_origin(tx: &mut db::Transaction) -> Origin {
+        let user_id = user::get_or_create_user(&mut *tx, "test-user")
+            .await
+            .unwrap();
+
+        Origin {
+            user_id,
+            api_token_id: None,
+        }
+    }
+
+    #[test]
+    fn summary_with_workouts() {
+        let mut summary = Summary::default();
+        summary.metrics.insert(
+            "step_count".to_owned(),
+            MetricSummary {
+                inserted: 2,
+                duplicate: 1,
+                rejected: 0,
+            },
+        );
+
+        // The summaries without workouts keep the format they had before the workouts
+        let exp = r#"{"step_count":{"inserted":2,"duplicate":1,"rejected":0}}"#;
+        assert_eq!(exp, serde_json::to_string(&summary).unwrap());
+        assert_eq!(summary, serde_json::from_str(exp).unwrap());
+
+        summary.workouts.inserted = 1;
+        let serialized = serde_json::to_string(&summary).unwrap();
+        assert_eq!(
+            r#"{"step_count":{"inserted":2,"duplicate":1,"rejected":0},"workouts":{"inserted":1,"duplicate":0,"attached":0}}"#,
+            serialized
+        );
+        assert_eq!(summary, serde_json::from_str(&serialized).unwrap());
+    }
+
+    #[test]
+    fn add_summaries() {
+        let mut total = Summary::default();
+        for inserted in [2, 3] {
+            let mut summary = Summary::default();
+            summary.metrics.insert(
+                "step_count".to_owned(),
+                MetricSummary {
+                    inserted,
+                    duplicate: 1,
+                    rejected: 0,
+                },
+            );
+            summary.workouts.inserted = 1;
+
+            total.add(summary);
+        }
+
+        let metric = &total.metrics["step_count"];
+        assert_eq!(
+            (5, 2, 0),
+            (metric.inserted, metric.duplicate, metric.rejected)
+        );
+        assert_eq!(2, total.workouts.inserted);
+    }
+
+    async fn insert_test_metric(tx: &mut db::Transaction, origin: Origin) -> i64 {
+        let (metric_id, _) = insert_metric(tx, origin.user_id, "foobar", "j/Min", None)
+            .await
+            .unwrap();
+        assert!(metric_id > 0);
+
+        metric_id
+    }
+
+    fn now() -> time::OffsetDateTime {
+        time::OffsetDateTime::now_utc()
+            .replace_microsecond(0)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_insert_metric() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+
+        let metric = sqlx::query!(r#"SELECT name, units FROM metric WHERE id = $1"#, metric_id)
+            .fetch_one(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!("foobar", metric.name.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_insert_metric_per_user() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+        assert_eq!(metric_id, insert_test_metric(&mut tx, origin).await);
+
+        let other_user_id = user::get_or_create_user(&mut tx, "other-test-user")
+            .await
+            .unwrap();
+        let (other_metric_id, _) = insert_metric(&mut tx, other_user_id, "foobar", "j/Min", None)
+            .await
+            .unwrap();
+        assert_ne!(metric_id, other_metric_id);
+    }
+
+    #[tokio::test]
+    async fn test_insert_metric_units() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+
+        let (metric_id, units) = insert_metric(&mut tx, origin.user_id, "foobar", "lb", None)
+            .await
+            .unwrap();
+        assert_eq!("lb", units);
+
+        // The units of the first upload are kept
+        let (_, units) = insert_metric(&mut tx, origin.user_id, "foobar", "kg", None)
+            .await
+            .unwrap();
+        assert_eq!("lb", units);
+
+        // Unless there are canonical units
+        let (same_metric_id, units) =
+            insert_metric(&mut tx, origin.user_id, "foobar", "lb", Some("kg"))
+                .await
+                .unwrap();
+        assert_eq!(metric_id, same_metric_id);
+        assert_eq!("kg", units);
+    }
+
+    #[tokio::test]
+    async fn test_insert_metric_data_points_generic() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+
+        let generic_data_point = GenericDataPoint {
+            date: now(),
+            quantity: 234.0,
+            source: None,
+        };
+
+        let inserted = insert_metric_data_points(
+            &mut tx,
+            metric_id,
+            origin,
+            "j/Min",
+            &[MetricDataPoint::Generic(generic_data_point.clone())],
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, inserted);
+
+        let metric = sqlx::query!(
+            r#"SELECT date, quantity FROM data_point_generic WHERE metric_id = $1"#,
+            metric_id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+
+        assert_eq!(generic_data_point.date, metric.date);
+        assert_eq!(generic_data_point.quantity, metric.quantity);
+    }
+
+    #[tokio::test]
+    async fn test_insert_metric_data_points_per_source() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+
+        let date = now();
+        let data_points: Vec<_> = [None, Some("Apple Watch"), Some("iPhone"), None]
+            .into_iter()
+            .map(|source| {
+                MetricDataPoint::Generic(GenericDataPoint {
+                    date,
+                    quantity: 60.0,
+                    source: source.map(ToOwned::to_owned),
+                })
+            })
+            .collect();
+
+        // Only the data point without a source is a duplicate
+        let inserted = insert_metric_data_points(&mut tx, metric_id, origin, "j/Min", &data_points)
+            .await
+            .unwrap();
+        assert_eq!(3, inserted);
+
+        let sources = sqlx::query!(
+            r#"
+            SELECT s.name
+            FROM data_point_generic d
+            INNER JOIN source s ON d.source_id = s.id
+            WHERE d.metric_id = $1
+            ORDER BY s.name"#,
+            metric_id,
+        )
+        .fetch_all(&mut tx)
+        .await
+        .unwrap();
+
+        let sources: Vec<_> = sources.into_iter().map(|record| record.name).collect();
+        assert_eq!(vec!["", "Apple Watch", "iPhone"], sources);
+    }
+
+    #[tokio::test]
+    async fn test_insert_metric_data_points_heart_rate() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+
+        let data_point = HeartRateDataPoint {
+            date: now(),
+            min: 2.0,
+            max: 50.0,
+            avg: 25.0,
+            source: None,
+        };
+
+        let inserted = insert_metric_data_points(
+            &mut tx,
+            metric_id,
+            origin,
+            "j/Min",
+            &[MetricDataPoint::HeartRate(data_point.clone())],
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, inserted);
+
+        let metric = sqlx::query!(
+            r#"
+            SELECT date, min, max, avg
+            FROM data_point_heart_rate WHERE metric_id = $1"#,
+            metric_id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+
+        assert_eq!(data_point.date, metric.date);
+        assert_eq!(data_point.min, metric.min);
+        assert_eq!(data_point.max, metric.max);
+        assert_eq!(data_point.avg, metric.avg);
+    }
+
+    #[tokio::test]
+    async fn test_insert_metric_data_points_sleep_analysis() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+
+        let data_point = SleepAnalysisDataPoint {
+            date: now(),
+            asleep: 34.0,
+            sleep_source: "foobar".to_owned(),
+            sleep_start: now(),
+            sleep_end: now(),
+            in_bed: 5012.0,
+            in_bed_source: "barbaz".to_owned(),
+            in_bed_start: now(),
+            in_bed_end: now(),
+        };
+
+        let inserted = insert_metric_data_points(
+            &mut tx,
+            metric_id,
+            origin,
+            "j/Min",
+            &[MetricDataPoint::SleepAnalysis(data_point.clone())],
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, inserted);
+
+        let metric = sqlx::query!(
+            r#"
+            SELECT
+              date, sleep_start, sleep_end, sleep_source,
+              in_bed_start, in_bed_end, in_bed_source,
+              in_bed, asleep
+            FROM data_point_sleep_analysis WHERE metric_id = $1"#,
+            metric_id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+
+        assert_eq!(data_point.date, metric.date);
+        assert_eq!(data_point.sleep_start, metric.sleep_start);
+        assert_eq!(data_point.sleep_end, metric.sleep_end);
+        assert_eq!(data_point.sleep_source, metric.sleep_source);
+        assert_eq!(data_point.in_bed_start, metric.in_bed_start);
+        assert_eq!(data_point.in_bed_end, metric.in_bed_end);
+        assert_eq!(data_point.in_bed_source, metric.in_bed_source);
+        assert_eq!(data_point.in_bed, metric.in_bed);
+        assert_eq!(data_point.asleep, metric.asleep);
+    }
+
+    #[tokio::test]
+    async fn test_insert_metric_data_points_duplicate() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+
+        let data_points = vec![MetricDataPoint::Generic(GenericDataPoint {
+            date: now(),
+            quantity: 234.0,
+            source: None,
+        })];
+
+        let inserted = insert_metric_data_points(&mut tx, metric_id, origin, "j/Min", &data_points)
+            .await
+            .unwrap();
+        assert_eq!(1, inserted);
+
+        let inserted = insert_metric_data_points(&mut tx, metric_id, origin, "j/Min", &data_points)
+            .await
+            .unwrap();
+        assert_eq!(0, inserted);
+    }
+
+    #[tokio::test]
+    async fn test_insert_metric_data_points_api_token() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+        let (api_token_id, _) =
+            auth::create_token(&mut tx, origin.user_id, "iPhone", &[auth::Scope::Ingest])
+                .await
+                .unwrap();
+        let origin = Origin {
+            api_token_id: Some(api_token_id),
+            ..origin
+        };
+
+        let data_points = vec![MetricDataPoint::Generic(GenericDataPoint {
+            date: now(),
+            quantity: 234.0,
+            source: None,
+        })];
+
+        insert_metric_data_points(&mut tx, metric_id, origin, "j/Min", &data_points)
+            .await
+            .unwrap();
+
+        let record = sqlx::query!(
+            r#"SELECT api_token_id FROM data_point_generic WHERE metric_id = $1"#,
+            metric_id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+        assert_eq!(Some(api_token_id), record.api_token_id);
+    }
+
+    #[tokio::test]
+    async fn test_insert_rejected_data_points() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let raw_upload_id = archive::store(
+            &mut tx,
+            origin.user_id,
+            None,
+            &http::HeaderMap::new(),
+            archive::Format::Json,
+            b"{}",
+        )
+        .await
+        .unwrap();
+        let upload_id = enqueue_upload(&mut tx, raw_upload_id, false).await.unwrap();
+
+        let data_point = InvalidDataPoint {
+            raw: r#"{"qty":"foo"}"#.to_owned(),
+            reason: "invalid type".to_owned(),
+        };
+        insert_rejected_data_points(
+            &mut tx,
+            Some(upload_id),
+            &[("foobar".to_owned(), data_point)],
+        )
+        .await
+        .unwrap();
+
+        let rejected_data_points = get_rejected_data_points(&mut tx, upload_id).await.unwrap();
+        assert_eq!(1, rejected_data_points.len());
+        assert_eq!("foobar", rejected_data_points[0].metric_name.as_str());
+        assert_eq!(
+            serde_json::json!({"qty": "foo"}),
+            rejected_data_points[0].raw
+        );
+        assert_eq!("invalid type", rejected_data_points[0].reason.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_upload() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let origin = test_origin(&mut tx).await;
+        let raw_upload_id = archive::store(
+            &mut tx,
+            origin.user_id,
+            None,
+            &http::HeaderMap::new(),
+            archive::Format::Json,
+            b"{}",
+        )
+        .await
+        .unwrap();
+        let upload_id = enqueue_upload(&mut tx, raw_upload_id, true).await.unwrap();
+
+        let upload = get_upload(&mut tx, upload_id).await.unwrap().unwrap();
+        assert_eq!(upload_id, upload.id);
+        assert_eq!(raw_upload_id, upload.raw_upload_id);
+        assert_eq!(origin.user_id, upload.user_id);
+        assert_eq!("pending", upload.status.as_str());
+        assert!(upload.strict);
+        assert!(upload.started_at.is_none());
+        assert!(upload.nb_data_points.is_none());
+        assert!(upload.summary.is_none());
+    }
+
+    /// Compares the bulk insert with one INSERT per data point.
+    ///
+    /// Run it with `just bench`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_insert_metric_data_points() {
+        const NB_DATA_POINTS: i64 = 20_000;
+
+        let db = get_db().await;
+
+        let start_date = now();
+        let data_points: Vec<_> = (0..NB_DATA_POINTS)
+            .map(|i| HeartRateDataPoint {
+                date: start_date - time::Duration::minutes(i),
+                min: 60.0,
+                max: 80.0,
+                avg: 70.0,
+                source: None,
+            })
+            .collect();
+
+        // One INSERT per data point
+
+        let mut tx = db.pool.begin().await.unwrap();
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+        // The data points without a source reference the source with an empty name, like in the bulk insert
+        let source_id = insert_sources(&mut tx, [""]).await.unwrap()[""];
+
+        let start = std::time::Instant::now();
+        for data_point in &data_points {
+            sqlx::query!(
+                r#"
+                INSERT INTO data_point_heart_rate(metric_id, user_id, date, min, max, avg, source_id)
+                VALUES($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT DO NOTHING"#,
+                metric_id,
+                origin.user_id,
+                data_point.date,
+                data_point.min,
+                data_point.max,
+                data_point.avg,
+                source_id,
+            )
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        }
+        let per_row_elapsed = start.elapsed();
+
+        drop(tx);
+
+        // Bulk insert
+
+        let data_points: Vec<_> = data_points
+            .into_iter()
+            .map(MetricDataPoint::HeartRate)
+            .collect();
+
+        let mut tx = db.pool.begin().await.unwrap();
+        let origin = test_origin(&mut tx).await;
+        let metric_id = insert_test_metric(&mut tx, origin).await;
+
+        let start = std::time::Instant::now();
+        let inserted = insert_metric_data_points(&mut tx, metric_id, origin, "j/Min", &data_points)
+            .await
+            .unwrap();
+        let bulk_elapsed = start.elapsed();
+
+        assert_eq!(NB_DATA_POINTS as u64, inserted);
+
+        println!(
+            "inserted {} data points: per row in {:?}, bulk in {:?}",
+            NB_DATA_POINTS, per_row_elapsed, bulk_elapsed,
+        );
+    }
+}
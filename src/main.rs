@@ -1,31 +1,62 @@
 use core::future::Future;
 use secrecy::ExposeSecret;
 use shutdown::Shutdown;
+use std::collections::BTreeMap;
 use std::net;
 use std::str::FromStr;
 use std::sync::Arc;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
+mod apple_health;
+mod archive;
+mod auth;
 mod cleaner;
 mod configuration;
 mod db;
 mod exporter;
+mod fit;
 mod health_data;
+mod idempotency;
+mod import;
+mod ingester;
+mod metrics;
+mod problem;
+mod query;
+mod rate_limit;
+mod route;
 mod shutdown;
+mod source;
+mod tls;
+mod units;
+mod user;
+mod validation;
 mod web;
+mod workout;
 
-async fn fallback_handler() -> (http::StatusCode, String) {
-    (http::StatusCode::NOT_FOUND, "Page Not Found".to_owned())
+async fn fallback_handler() -> problem::Problem {
+    problem::Problem::new(http::StatusCode::NOT_FOUND).with_detail("Page Not Found")
 }
 
 struct App {
     connection_string: String,
     listen_addr: net::SocketAddr,
     victoria_addr: net::SocketAddr,
+    max_body_size: usize,
+    idempotency_key_ttl: std::time::Duration,
+    tls_config: Option<configuration::TlsConfig>,
+    exporter_config: configuration::ExporterConfig,
+    ingester_config: configuration::IngesterConfig,
+    canonical_units: Arc<units::CanonicalUnits>,
+    source_priority: BTreeMap<String, Vec<String>>,
+    priority_resolution: configuration::PriorityResolutionConfig,
+    validator: Arc<validation::Validator>,
+    rate_limiter: Arc<rate_limit::RateLimiter>,
 }
 
 impl App {
     fn build(config: configuration::Config) -> anyhow::Result<Self> {
+        config.validate()?;
+
         let listen_addr = std::net::SocketAddr::from_str(&config.application.listen_addr)?;
         info!(listen_addr = listen_addr.to_string(), "got listen addr");
 
@@ -35,6 +66,8 @@ impl App {
             "got victoria addr"
         );
 
+        let canonical_units = units::CanonicalUnits::new(&config.units)?;
+
         Ok(Self {
             connection_string: config
                 .database
@@ -43,51 +76,226 @@ impl App {
                 .to_string(),
             listen_addr,
             victoria_addr,
+            max_body_size: config.application.max_body_size,
+            idempotency_key_ttl: config.application.idempotency_key_ttl(),
+            tls_config: config.application.tls,
+            exporter_config: config.exporter,
+            ingester_config: config.ingester,
+            canonical_units: Arc::new(canonical_units),
+            source_priority: config.source_priority,
+            priority_resolution: config.priority_resolution,
+            validator: Arc::new(validation::Validator::new(&config.validation)),
+            rate_limiter: Arc::new(rate_limit::RateLimiter::new(&config.rate_limit)),
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn run_web_app(
         db: Arc<db::Db>,
         listen_addr: net::SocketAddr,
+        max_body_size: usize,
+        idempotency_key_ttl: std::time::Duration,
+        priority_window: std::time::Duration,
+        ingester_config: &configuration::IngesterConfig,
+        rate_limiter: Arc<rate_limit::RateLimiter>,
+        rustls_config: Option<axum_server::tls_rustls::RustlsConfig>,
         mut shutdown: Shutdown,
     ) -> anyhow::Result<()> {
-        let state = web::State::new(db);
+        let state = web::State::new(
+            db,
+            ingester_config,
+            idempotency_key_ttl,
+            max_body_size,
+            priority_window,
+            rate_limiter.clone(),
+        );
 
         // Build the router
         let web_app = axum::Router::new()
-            .route("/health_data", axum::routing::post(web::health_data))
+            .route(
+                "/health_data",
+                axum::routing::post(web::health_data)
+                    .layer(web::decompression())
+                    .layer(axum::error_handling::HandleErrorLayer::new(
+                        web::handle_decompression_error,
+                    ))
+                    .layer(axum::middleware::from_fn_with_state(
+                        rate_limiter.clone(),
+                        rate_limit::limit_uploads,
+                    )),
+            )
+            .route(
+                "/api/v1/import/:format",
+                axum::routing::post(web::import_file)
+                    .layer(web::decompression())
+                    .layer(axum::error_handling::HandleErrorLayer::new(
+                        web::handle_decompression_error,
+                    ))
+                    .layer(axum::middleware::from_fn_with_state(
+                        rate_limiter.clone(),
+                        rate_limit::limit_uploads,
+                    )),
+            )
+            .route("/api/v1/uploads/:id", axum::routing::get(web::upload))
+            .route(
+                "/api/v1/workouts/:id/route",
+                axum::routing::get(web::workout_route),
+            )
+            .route(
+                "/api/v1/uploads/:id/rejected_data_points",
+                axum::routing::get(web::upload_rejected_data_points),
+            )
+            .route(
+                "/api/v1/raw_uploads/replay",
+                axum::routing::post(web::replay),
+            )
+            .route(
+                "/api/v1/tokens",
+                axum::routing::get(web::tokens).post(web::create_token),
+            )
+            .route(
+                "/api/v1/tokens/:id",
+                axum::routing::delete(web::revoke_token),
+            )
+            .route(
+                "/api/v1/users",
+                axum::routing::get(web::users).post(web::create_user),
+            )
+            .route(
+                "/api/v1/metrics/:name/data_points",
+                axum::routing::get(web::data_points),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                rate_limiter,
+                rate_limit::limit_rate,
+            ))
             .route("/metrics", axum::routing::get(web::metrics))
             .fallback(fallback_handler)
+            .layer(axum::extract::DefaultBodyLimit::max(max_body_size))
+            .layer(tower_http::compression::CompressionLayer::new())
             .layer(tower_http::trace::TraceLayer::new_for_http())
+            .layer(axum::middleware::from_fn(problem::request_id))
             .with_state(state);
 
+        if let Some(rustls_config) = rustls_config {
+            info!(listen_addr = listen_addr.to_string(), "serving HTTPS");
+
+            let handle = axum_server::Handle::new();
+            let shutdown_handle = handle.clone();
+            tokio::spawn(async move {
+                shutdown.recv().await;
+                shutdown_handle.graceful_shutdown(None);
+            });
+
+            axum_server::bind_rustls(listen_addr, rustls_config)
+                .handle(handle)
+                .serve(web_app.into_make_service_with_connect_info::<net::SocketAddr>())
+                .await?;
+
+            return Ok(());
+        }
+
         let web_server = axum::Server::bind(&listen_addr)
-            .serve(web_app.into_make_service())
+            .serve(web_app.into_make_service_with_connect_info::<net::SocketAddr>())
             .with_graceful_shutdown(shutdown.recv());
 
         Ok(web_server.await?)
     }
 
-    async fn run(self, shutdown: impl Future + std::marker::Send + 'static) -> anyhow::Result<()> {
+    async fn run(
+        self,
+        migrate: bool,
+        shutdown: impl Future + std::marker::Send + 'static,
+    ) -> anyhow::Result<()> {
         // Used for shutdown notinfications
         let (notify_shutdown_sender, _) = tokio::sync::broadcast::channel(2);
 
         // Initialize the database
         let db = Arc::new(db::Db::build(&self.connection_string).await?);
 
+        if migrate {
+            db.migrate().await?;
+        } else {
+            check_migrations(&db).await?;
+        }
+
+        let mut tx = db.pool.begin().await?;
+        source::sync_priorities(&mut tx, &self.source_priority).await?;
+        tx.commit().await?;
+
+        // Make sure the API is usable
+
+        if let Some(token) = auth::ensure_admin_token(&db).await? {
+            // Printed outside of the logs which are usually kept
+            warn!("created bootstrap admin token, it's printed on stderr and won't be shown again");
+            eprintln!("bootstrap admin token: {}", token);
+        }
+
         // Start the exporter
-        let exporter = exporter::Exporter::new(db.clone(), self.victoria_addr);
+        let exporter = exporter::Exporter::new(
+            db.clone(),
+            self.victoria_addr,
+            &self.exporter_config,
+            &self.priority_resolution,
+        );
         let exporter_shutdown = Shutdown::new(notify_shutdown_sender.subscribe());
         let exporter = tokio::task::spawn(exporter.run(exporter_shutdown));
 
+        // Start the ingesters
+        let nb_requeued = ingester::requeue_interrupted_uploads(&db).await?;
+        if nb_requeued > 0 {
+            info!(nb_requeued, "requeued interrupted uploads");
+        }
+
+        let mut ingesters = Vec::with_capacity(self.ingester_config.workers);
+        for _ in 0..self.ingester_config.workers {
+            let ingester = ingester::Ingester::new(
+                db.clone(),
+                &self.ingester_config,
+                self.canonical_units.clone(),
+                self.validator.clone(),
+            );
+            let ingester_shutdown = Shutdown::new(notify_shutdown_sender.subscribe());
+            ingesters.push(tokio::task::spawn(ingester.run(ingester_shutdown)));
+        }
+
         // Start the cleaner
-        let cleaner = cleaner::Cleaner::new(db.clone());
+        let cleaner = cleaner::Cleaner::new(db.clone(), self.idempotency_key_ttl);
         let cleaner_shutdown = Shutdown::new(notify_shutdown_sender.subscribe());
         let cleaner = tokio::task::spawn(cleaner.run(cleaner_shutdown));
 
+        // Load the certificates and watch them for changes
+        let rustls_config = match &self.tls_config {
+            Some(tls_config) => {
+                let server_config = tls::load_server_config(tls_config)?;
+                let rustls_config =
+                    axum_server::tls_rustls::RustlsConfig::from_config(Arc::new(server_config));
+
+                let tls_shutdown = Shutdown::new(notify_shutdown_sender.subscribe());
+                tokio::spawn(tls::watch(
+                    rustls_config.clone(),
+                    tls_config.clone(),
+                    tls_shutdown,
+                ));
+
+                Some(rustls_config)
+            }
+            None => None,
+        };
+
         // Start the web app and web server
         let web_server_shutdown = Shutdown::new(notify_shutdown_sender.subscribe());
-        let web_server = Self::run_web_app(db.clone(), self.listen_addr, web_server_shutdown);
+        let web_server = Self::run_web_app(
+            db.clone(),
+            self.listen_addr,
+            self.max_body_size,
+            self.idempotency_key_ttl,
+            self.priority_resolution.window(),
+            &self.ingester_config,
+            self.rate_limiter.clone(),
+            rustls_config,
+            web_server_shutdown,
+        );
 
         // Spawn a task that will notify shutdowns
         tokio::spawn(async move {
@@ -98,27 +306,134 @@ impl App {
         web_server.await?;
         exporter.await??;
         cleaner.await??;
+        for ingester in ingesters {
+            ingester.await??;
+        }
 
         Ok(())
     }
 }
 
-fn serve(config: configuration::Config) -> anyhow::Result<()> {
-    // Build the Tokio runtime
-    let runtime = tokio::runtime::Builder::new_current_thread()
+/// Fails if the database has pending migrations.
+async fn check_migrations(db: &db::Db) -> anyhow::Result<()> {
+    let nb_pending = db
+        .migrations()
+        .await?
+        .iter()
+        .filter(|migration| !migration.applied)
+        .count();
+    if nb_pending > 0 {
+        anyhow::bail!(
+            "{} pending migrations, run `hdas migrate up` or start with `hdas serve --migrate`",
+            nb_pending
+        );
+    }
+
+    Ok(())
+}
+
+fn build_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
         .worker_threads(4)
         .thread_name("hdas")
         .thread_stack_size(3 * 1024 * 1024)
         .enable_all()
         .build()
-        .unwrap();
+        .unwrap()
+}
+
+fn serve(config: configuration::Config, migrate: bool) -> anyhow::Result<()> {
+    let runtime = build_runtime();
     let _runtime_guard = runtime.enter();
 
     let app = App::build(config)?;
-    let future = app.run(shutdown_signal());
+    let future = app.run(migrate, shutdown_signal());
 
     // Run the app
-    let _ = runtime.block_on(future);
+    runtime.block_on(future)
+}
+
+fn migrate(config: configuration::Config) -> anyhow::Result<()> {
+    build_runtime().block_on(async {
+        let db = db::Db::build(config.database.connection_string().expose_secret()).await?;
+        db.migrate().await?;
+
+        info!("database is up to date");
+
+        Ok(())
+    })
+}
+
+fn migration_status(config: configuration::Config) -> anyhow::Result<()> {
+    build_runtime().block_on(async {
+        let db = db::Db::build(config.database.connection_string().expose_secret()).await?;
+
+        for migration in db.migrations().await? {
+            let status = if migration.applied {
+                "applied"
+            } else {
+                "pending"
+            };
+            println!(
+                "{}\t{}\t{}",
+                migration.version, status, migration.description
+            );
+        }
+
+        Ok(())
+    })
+}
+
+fn import(config: configuration::Config, matches: &clap::ArgMatches) -> anyhow::Result<()> {
+    let paths: Vec<std::path::PathBuf> = matches
+        .get_many::<String>("paths")
+        .unwrap_or_default()
+        .map(Into::into)
+        .collect();
+    let user_name = matches.get_one::<String>("user").unwrap();
+
+    let files = import::collect_files(&paths)?;
+    if files.is_empty() {
+        anyhow::bail!("no JSON file found");
+    }
+
+    let canonical_units = units::CanonicalUnits::new(&config.units)?;
+
+    build_runtime().block_on(async {
+        let db = db::Db::build(config.database.connection_string().expose_secret()).await?;
+        check_migrations(&db).await?;
+
+        let user = user::find_user_by_name(&db.pool, user_name)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("user {:?} not found", user_name))?;
+
+        let importer = import::Importer {
+            db: Arc::new(db),
+            user_id: user.id,
+            strict: matches.get_flag("strict") || config.ingester.strict,
+            dry_run: matches.get_flag("dry-run"),
+            batch_size: config.ingester.batch_size,
+            canonical_units: Arc::new(canonical_units),
+            validator: Arc::new(validation::Validator::new(&config.validation)),
+        };
+
+        let nb_failed = importer.run(&files).await;
+        if nb_failed > 0 {
+            anyhow::bail!("{} files failed to be imported", nb_failed);
+        }
+
+        Ok(())
+    })
+}
+
+/// Checks that the server can start with the configuration, then prints it.
+fn check_config(config: configuration::Config) -> anyhow::Result<()> {
+    if let Some(tls_config) = &config.application.tls {
+        tls::load_server_config(tls_config)?;
+    }
+    App::build(config.clone())?;
+
+    print!("{}", config.to_redacted_toml()?);
 
     Ok(())
 }
@@ -145,8 +460,90 @@ async fn shutdown_signal() {
     debug!("signal received, starting graceful shutdown");
 }
 
+fn cli() -> clap::Command<'static> {
+    clap::command!()
+        .arg(
+            clap::Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .takes_value(true)
+                .global(true)
+                .help("Read the configuration from this file instead of the default locations"),
+        )
+        .subcommand(
+            clap::Command::new("serve")
+                .about("Run the server, the default command")
+                .arg(
+                    clap::Arg::new("migrate")
+                        .long("migrate")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Apply the pending migrations instead of refusing to start"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("migrate")
+                .about("Manage the database migrations")
+                .subcommand(
+                    clap::Command::new("up").about("Apply the pending migrations, the default"),
+                )
+                .subcommand(
+                    clap::Command::new("status")
+                        .about("List the migrations and whether they're applied"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("import")
+                .about("Import Health Auto Export JSON files, Apple Health exports or FIT, GPX and TCX files without going through the web server")
+                .arg(
+                    clap::Arg::new("paths")
+                        .value_name("PATH")
+                        .required(true)
+                        .multiple_values(true)
+                        .help("Files to import, JSON files, Apple Health export.zip or export.xml files or FIT, GPX and TCX files; the directories are searched for JSON, FIT, GPX and TCX files"),
+                )
+                .arg(
+                    clap::Arg::new("user")
+                        .long("user")
+                        .value_name("NAME")
+                        .takes_value(true)
+                        .default_value(user::DEFAULT_USER)
+                        .help("User owning the imported data points"),
+                )
+                .arg(
+                    clap::Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Import the files but roll everything back"),
+                )
+                .arg(
+                    clap::Arg::new("strict")
+                        .long("strict")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Fail a file with any invalid data point instead of skipping them"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("config")
+                .about("Manage the configuration")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("check")
+                        .about("Validate the configuration and print it with the secrets redacted"),
+                ),
+        )
+}
+
 fn main() {
-    let config = configuration::get_configuration().expect("Unable to read configuration");
+    let matches = cli().get_matches();
+
+    let config_path = matches.get_one::<String>("config").map(String::as_str);
+    let config = match configuration::get_configuration(config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("unable to read configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
 
     // Initialize logger
     if std::env::var("RUST_LOG").is_err() {
@@ -155,9 +552,29 @@ fn main() {
     tracing_subscriber::fmt::init();
 
     // Run the appropriate command
+    let result = match matches.subcommand() {
+        Some(("serve", matches)) => serve(config, matches.get_flag("migrate")),
+        Some(("migrate", matches)) => match matches.subcommand() {
+            Some(("status", _)) => migration_status(config),
+            _ => migrate(config),
+        },
+        Some(("import", matches)) => import(config, matches),
+        Some(("config", _)) => check_config(config),
+        _ => serve(config, false),
+    };
+
+    if let Err(err) = result {
+        error!(%err, "command failed");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    match serve(config) {
-        Ok(()) => {}
-        Err(err) => error!(%err, "unable to serve, got err"),
+    #[test]
+    fn verify_cli() {
+        cli().debug_assert();
     }
 }
@@ -0,0 +1,44 @@
+//! Application metrics, exposed on `/metrics` with the process metrics.
+
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+
+/// Metrics not in the registry of [`crate::health_data`], their data points are parsed as generic.
+pub static UNKNOWN_METRICS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hdas_unknown_metrics_total",
+        "Number of ingested payloads containing a metric unknown to the registry",
+        &["metric"]
+    )
+    .unwrap()
+});
+
+/// Data points not matching the type of their metric.
+pub static MISMATCHED_DATA_POINTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hdas_mismatched_data_points_total",
+        "Number of data points rejected because they don't match the type of their metric",
+        &["metric"]
+    )
+    .unwrap()
+});
+
+/// Data points breaking a validation rule.
+pub static INVALID_DATA_POINTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hdas_invalid_data_points_total",
+        "Number of data points rejected because they break a validation rule",
+        &["metric", "rule"]
+    )
+    .unwrap()
+});
+
+/// Requests rejected by the rate limits of [`crate::rate_limit`].
+pub static RATE_LIMITED_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hdas_rate_limited_requests_total",
+        "Number of requests rejected with a 429 because of a rate or concurrency limit",
+        &["reason"]
+    )
+    .unwrap()
+});
@@ -0,0 +1,151 @@
+use axum::extract::rejection::{BytesRejection, JsonRejection, PathRejection, QueryRejection};
+use std::fmt;
+use tracing::error;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Header carrying the ID of a request, generated if the client didn't provide one.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// An error returned by the API, rendered as a RFC 7807 `application/problem+json` document.
+#[derive(Debug)]
+pub struct Problem {
+    pub status: http::StatusCode,
+    pub detail: Option<String>,
+    /// JSON pointer to the invalid field of the request body, if any.
+    pub pointer: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: http::StatusCode) -> Self {
+        Self {
+            status,
+            detail: None,
+            pointer: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_pointer(mut self, pointer: impl Into<String>) -> Self {
+        self.pointer = Some(pointer.into());
+        self
+    }
+
+    /// Returns a generic internal server error.
+    ///
+    /// The error is logged along with the request ID but never exposed to the client,
+    /// the request ID in the response is enough to find it.
+    pub fn internal(err: impl fmt::Display) -> Self {
+        error!(
+            request_id = current_request_id(),
+            %err,
+            "internal server error"
+        );
+
+        Self::new(http::StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ProblemDocument<'a> {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pointer: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl axum::response::IntoResponse for Problem {
+    fn into_response(self) -> axum::response::Response {
+        let document = ProblemDocument {
+            type_: "about:blank",
+            title: self.status.canonical_reason().unwrap_or("Unknown Error"),
+            status: self.status.as_u16(),
+            detail: self.detail.as_deref(),
+            pointer: self.pointer.as_deref(),
+            request_id: current_request_id(),
+        };
+
+        let body = match serde_json::to_vec(&document) {
+            Ok(body) => body,
+            Err(err) => {
+                error!(%err, "unable to serialize problem document");
+                return self.status.into_response();
+            }
+        };
+
+        (
+            self.status,
+            [(http::header::CONTENT_TYPE, "application/problem+json")],
+            body,
+        )
+            .into_response()
+    }
+}
+
+impl From<BytesRejection> for Problem {
+    fn from(rejection: BytesRejection) -> Self {
+        Self::new(rejection.status()).with_detail(rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for Problem {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(rejection.status()).with_detail(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for Problem {
+    fn from(rejection: PathRejection) -> Self {
+        Self::new(rejection.status()).with_detail(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for Problem {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(rejection.status()).with_detail(rejection.body_text())
+    }
+}
+
+/// Returns the ID of the request being handled, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Middleware assigning an ID to every request.
+///
+/// The ID is taken from the `X-Request-Id` header if the client set it, otherwise it's generated.
+/// It's returned in the same header and available to the handlers with [`current_request_id`].
+pub async fn request_id<B>(
+    request: http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(request))
+        .await;
+
+    if let Ok(value) = http::HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    response
+}
@@ -0,0 +1,254 @@
+use crate::health_data::{self, DataPointKind};
+use time::OffsetDateTime;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    SQLx(#[from] sqlx::Error),
+    #[error("reading the data points of metric {0:?} is not supported")]
+    Unsupported(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A stored data point.
+///
+/// When multiple sources have data points less than the priority window apart, only the data points
+/// of the sources with the highest priority are returned.
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(untagged)]
+pub enum DataPoint {
+    HeartRate(HeartRateDataPoint),
+    Generic(GenericDataPoint),
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct HeartRateDataPoint {
+    #[serde(with = "time::serde::rfc3339")]
+    pub date: OffsetDateTime,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub source: Option<String>,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct GenericDataPoint {
+    #[serde(with = "time::serde::rfc3339")]
+    pub date: OffsetDateTime,
+    pub quantity: f64,
+    pub source: Option<String>,
+}
+
+/// Returns at most `limit` data points of a metric of a user in the range `[from, to)`, oldest first.
+pub async fn list_data_points<'e, E>(
+    executor: E,
+    user_id: i64,
+    metric_name: &str,
+    from: OffsetDateTime,
+    to: OffsetDateTime,
+    limit: i64,
+    priority_window: std::time::Duration,
+) -> Result<Vec<DataPoint>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let kind = health_data::lookup_metric(metric_name).unwrap_or(DataPointKind::Generic);
+
+    let data_points = match kind {
+        DataPointKind::HeartRate => {
+            let records = sqlx::query!(
+                r#"
+                SELECT d.date, d.min, d.max, d.avg, s.name AS source_name
+                FROM data_point_heart_rate d
+                INNER JOIN metric m ON d.metric_id = m.id
+                INNER JOIN source s ON d.source_id = s.id
+                WHERE m.user_id = $1 AND m.name = $2
+                AND d.date >= $3 AND d.date < $4
+                AND NOT EXISTS (
+                  SELECT 1 FROM data_point_heart_rate o
+                  INNER JOIN source os ON o.source_id = os.id
+                  WHERE o.metric_id = d.metric_id
+                  AND o.date BETWEEN d.date - make_interval(secs => $6) AND d.date + make_interval(secs => $6)
+                  AND source_rank(m.name, os.name) < source_rank(m.name, s.name)
+                )
+                ORDER BY d.date, d.id
+                LIMIT $5"#,
+                user_id,
+                metric_name,
+                from,
+                to,
+                limit,
+                priority_window.as_secs_f64(),
+            )
+            .fetch_all(executor)
+            .await?;
+
+            records
+                .into_iter()
+                .map(|record| {
+                    DataPoint::HeartRate(HeartRateDataPoint {
+                        date: record.date,
+                        min: record.min,
+                        max: record.max,
+                        avg: record.avg,
+                        source: source(record.source_name),
+                    })
+                })
+                .collect()
+        }
+        DataPointKind::Generic => {
+            let records = sqlx::query!(
+                r#"
+                SELECT d.date, d.quantity, s.name AS source_name
+                FROM data_point_generic d
+                INNER JOIN metric m ON d.metric_id = m.id
+                INNER JOIN source s ON d.source_id = s.id
+                WHERE m.user_id = $1 AND m.name = $2
+                AND d.date >= $3 AND d.date < $4
+                AND NOT EXISTS (
+                  SELECT 1 FROM data_point_generic o
+                  INNER JOIN source os ON o.source_id = os.id
+                  WHERE o.metric_id = d.metric_id
+                  AND o.date BETWEEN d.date - make_interval(secs => $6) AND d.date + make_interval(secs => $6)
+                  AND source_rank(m.name, os.name) < source_rank(m.name, s.name)
+                )
+                ORDER BY d.date, d.id
+                LIMIT $5"#,
+                user_id,
+                metric_name,
+                from,
+                to,
+                limit,
+                priority_window.as_secs_f64(),
+            )
+            .fetch_all(executor)
+            .await?;
+
+            records
+                .into_iter()
+                .map(|record| {
+                    DataPoint::Generic(GenericDataPoint {
+                        date: record.date,
+                        quantity: record.quantity,
+                        source: source(record.source_name),
+                    })
+                })
+                .collect()
+        }
+        DataPointKind::SleepAnalysis => return Err(Error::Unsupported(metric_name.to_owned())),
+    };
+
+    Ok(data_points)
+}
+
+/// The data points without a source reference the source with an empty name.
+fn source(name: String) -> Option<String> {
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use crate::db;
+    use crate::source;
+    use crate::user;
+    use secrecy::ExposeSecret;
+    use std::collections::BTreeMap;
+    use time::macros::datetime;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_list_data_points_by_source_priority() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let mut priorities = BTreeMap::new();
+        priorities.insert(
+            "step_count".to_owned(),
+            vec!["Watch".to_owned(), "iPhone".to_owned()],
+        );
+        source::sync_priorities(&mut tx, &priorities).await.unwrap();
+
+        let user_id = user::get_or_create_user(&mut tx, "test-user")
+            .await
+            .unwrap();
+
+        let metric = sqlx::query!(
+            r#"INSERT INTO metric(user_id, name, units) VALUES($1, 'step_count', 'count') RETURNING id"#,
+            user_id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+
+        // The watch and the phone overlap around 10:00, only the phone has a data point at 11:00
+        let data_points = [
+            (datetime!(2022-08-01 10:00 UTC), 100.0, "iPhone de Test"),
+            (
+                datetime!(2022-08-01 10:00:30 UTC),
+                80.0,
+                "Apple Watch de Test",
+            ),
+            (datetime!(2022-08-01 11:00 UTC), 50.0, "iPhone de Test"),
+        ];
+        for (date, quantity, source_name) in data_points {
+            sqlx::query!(
+                r#"
+                WITH s AS (
+                  INSERT INTO source(name) VALUES($4)
+                  ON CONFLICT (name) DO UPDATE SET name = excluded.name
+                  RETURNING id
+                )
+                INSERT INTO data_point_generic(metric_id, user_id, date, quantity, source_id)
+                SELECT $1, $2, $3, $5, s.id FROM s"#,
+                metric.id,
+                user_id,
+                date,
+                source_name,
+                quantity,
+            )
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        }
+
+        let data_points = list_data_points(
+            &mut tx,
+            user_id,
+            "step_count",
+            datetime!(2022-08-01 00:00 UTC),
+            datetime!(2022-08-02 00:00 UTC),
+            100,
+            std::time::Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+
+        let exp = vec![
+            DataPoint::Generic(GenericDataPoint {
+                date: datetime!(2022-08-01 10:00:30 UTC),
+                quantity: 80.0,
+                source: Some("Apple Watch de Test".to_owned()),
+            }),
+            DataPoint::Generic(GenericDataPoint {
+                date: datetime!(2022-08-01 11:00 UTC),
+                quantity: 50.0,
+                source: Some("iPhone de Test".to_owned()),
+            }),
+        ];
+        assert_eq!(exp, data_points);
+    }
+}
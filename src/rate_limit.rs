@@ -0,0 +1,216 @@
+use crate::configuration::RateLimitConfig;
+use crate::metrics;
+use crate::problem::Problem;
+use axum::extract::{ConnectInfo, State};
+use axum::response::IntoResponse;
+use std::collections::HashMap;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// Number of buckets above which the full buckets are dropped.
+///
+/// A full bucket is the same as no bucket, dropping them keeps the memory bounded
+/// when many clients come and go.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Identifies a client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    /// ID of an authenticated API token.
+    Token(i64),
+    Ip(net::IpAddr),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token bucket rate limiter per client, and concurrency limiter of the uploads.
+pub struct RateLimiter {
+    /// Tokens added to a bucket per second.
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+    uploads: Semaphore,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            rate: f64::from(config.requests_per_minute) / 60.0,
+            burst: f64::from(config.burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+            uploads: Semaphore::new(config.max_concurrent_uploads.max(1)),
+        }
+    }
+
+    /// Takes a token from the bucket of an authenticated API token.
+    ///
+    /// Returns how long to wait for the next token if the bucket is empty.
+    pub fn limit_token(&self, api_token_id: i64) -> Result<(), Duration> {
+        self.acquire(Key::Token(api_token_id), Instant::now())
+    }
+
+    /// Takes a token from the bucket of a client.
+    ///
+    /// Returns how long to wait for the next token if the bucket is empty.
+    fn acquire(&self, key: Key, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        if self.rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        } else {
+            Err(Duration::from_secs(60))
+        }
+    }
+
+    /// Returns the tokens of a bucket at `now`.
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst)
+    }
+}
+
+/// Middleware limiting the rate of the requests of every IP address.
+///
+/// Every request is charged to its IP address, so that limited requests are rejected
+/// before reaching the database whatever bearer token they carry. The requests of a token
+/// are also charged to the token once it's authenticated, see [`RateLimiter::limit_token`].
+pub async fn limit_rate<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(remote_addr): ConnectInfo<net::SocketAddr>,
+    request: http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    if let Err(retry_after) = limiter.acquire(Key::Ip(remote_addr.ip()), Instant::now()) {
+        return rate_limited(retry_after);
+    }
+
+    next.run(request).await
+}
+
+/// Middleware limiting the number of uploads handled concurrently.
+///
+/// The uploads over the limit are rejected rather than queued, the clients retry them later.
+pub async fn limit_uploads<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let _permit = match limiter.uploads.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            metrics::RATE_LIMITED_REQUESTS
+                .with_label_values(&["concurrency"])
+                .inc();
+
+            return too_many_requests("too many concurrent uploads", Duration::from_secs(1));
+        }
+    };
+
+    next.run(request).await
+}
+
+/// Returns the response of a request exceeding its rate limit.
+pub fn rate_limited(retry_after: Duration) -> axum::response::Response {
+    metrics::RATE_LIMITED_REQUESTS
+        .with_label_values(&["rate"])
+        .inc();
+
+    too_many_requests("rate limit exceeded", retry_after)
+}
+
+fn too_many_requests(detail: &str, retry_after: Duration) -> axum::response::Response {
+    let mut response = Problem::new(http::StatusCode::TOO_MANY_REQUESTS)
+        .with_detail(detail)
+        .into_response();
+
+    // Retry-After is in whole seconds, round up so that the retry isn't limited again
+    let retry_after_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    response
+        .headers_mut()
+        .insert(http::header::RETRY_AFTER, retry_after_secs.into());
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests_per_minute: u32, burst: u32) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            requests_per_minute,
+            burst,
+            max_concurrent_uploads: 1,
+        })
+    }
+
+    fn ip(last: u8) -> Key {
+        Key::Ip(net::IpAddr::V4(net::Ipv4Addr::new(192, 168, 1, last)))
+    }
+
+    #[test]
+    fn acquire_up_to_burst() {
+        let limiter = limiter(60, 3);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(Ok(()), limiter.acquire(ip(1), now));
+        }
+        assert_eq!(Err(Duration::from_secs(1)), limiter.acquire(ip(1), now));
+
+        // The other clients have their own bucket
+        assert_eq!(Ok(()), limiter.acquire(ip(2), now));
+        assert_eq!(Ok(()), limiter.acquire(Key::Token(1), now));
+    }
+
+    #[test]
+    fn acquire_refills() {
+        let limiter = limiter(30, 1);
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limiter.acquire(ip(1), now));
+        assert_eq!(Err(Duration::from_secs(2)), limiter.acquire(ip(1), now));
+        assert_eq!(
+            Err(Duration::from_secs(1)),
+            limiter.acquire(ip(1), now + Duration::from_secs(1))
+        );
+        assert_eq!(Ok(()), limiter.acquire(ip(1), now + Duration::from_secs(2)));
+
+        // The bucket never holds more than the burst
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(Ok(()), limiter.acquire(ip(1), later));
+        assert!(limiter.acquire(ip(1), later).is_err());
+    }
+
+    #[test]
+    fn too_many_requests_has_retry_after() {
+        let response = too_many_requests("rate limit exceeded", Duration::from_millis(1500));
+
+        assert_eq!(http::StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!(
+            "2",
+            response.headers().get(http::header::RETRY_AFTER).unwrap()
+        );
+    }
+}
@@ -0,0 +1,745 @@
+use crate::workout::{Lap, Record, Workout};
+use quick_xml::events::{BytesStart, Event};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::warn;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid XML: {0}")]
+    Xml(#[from] quick_xml::Error),
+    #[error("not a GPX or TCX file")]
+    UnknownFormat,
+    #[error("invalid {element} {value:?}")]
+    InvalidValue { element: String, value: String },
+    #[error("no track with timestamps")]
+    NoWorkout,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Format of a route file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gpx,
+    Tcx,
+}
+
+/// What's imported from a GPX or TCX file.
+#[derive(Debug, Default, PartialEq)]
+pub struct RouteFile {
+    /// The device or app that recorded the file.
+    pub source: String,
+    pub workouts: Vec<Workout>,
+}
+
+/// Returns the format of a route file by its root element, `None` if it's neither GPX nor TCX.
+pub fn detect_format(data: &[u8]) -> Option<Format> {
+    let mut reader = quick_xml::Reader::from_reader(data);
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(element) | Event::Empty(element)) => {
+                return match element.local_name().as_ref() {
+                    b"gpx" => Some(Format::Gpx),
+                    b"TrainingCenterDatabase" => Some(Format::Tcx),
+                    _ => None,
+                };
+            }
+            Ok(Event::Eof) | Err(_) => return None,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Decodes a GPX or TCX file, the format is detected from the root element.
+///
+/// Every track of a GPX file and every activity of a TCX file is a workout, its points are
+/// the records of the workout. The points without a time are skipped since they can't be placed.
+/// The distance and speed of the points are computed from their positions if they're missing.
+pub fn decode(data: &[u8]) -> Result<RouteFile> {
+    let route_file = match detect_format(data).ok_or(Error::UnknownFormat)? {
+        Format::Gpx => parse(data, GpxHandler::default())?,
+        Format::Tcx => parse(data, TcxHandler::default())?,
+    };
+
+    if route_file.workouts.is_empty() {
+        return Err(Error::NoWorkout);
+    }
+
+    Ok(route_file)
+}
+
+/// Handles the elements of a route file, identified by their local name.
+trait Handler {
+    fn start(&mut self, element: &BytesStart) -> Result<()>;
+    /// Called with the text of the element at the end of `path`.
+    fn text(&mut self, path: &[Vec<u8>], text: &str) -> Result<()>;
+    fn end(&mut self, name: &[u8]);
+    fn finish(self) -> RouteFile;
+}
+
+fn parse<H: Handler>(data: &[u8], mut handler: H) -> Result<RouteFile> {
+    let mut reader = quick_xml::Reader::from_reader(data);
+    reader.trim_text(true);
+
+    let mut path = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                handler.start(&element)?;
+                path.push(element.local_name().as_ref().to_vec());
+            }
+            Event::Empty(element) => {
+                handler.start(&element)?;
+                handler.end(element.local_name().as_ref());
+            }
+            Event::End(element) => {
+                path.pop();
+                handler.end(element.local_name().as_ref());
+            }
+            Event::Text(text) => handler.text(&path, &text.unescape()?)?,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(handler.finish())
+}
+
+/// A point of a track, without a time until its time element is read.
+#[derive(Debug, Default)]
+struct Point {
+    date: Option<OffsetDateTime>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    altitude: Option<f64>,
+    distance: Option<f64>,
+    speed: Option<f64>,
+    heart_rate: Option<f64>,
+    cadence: Option<f64>,
+    power: Option<f64>,
+}
+
+impl Point {
+    fn into_record(self) -> Option<Record> {
+        Some(Record {
+            date: self.date?,
+            heart_rate: self.heart_rate,
+            latitude: self.latitude,
+            longitude: self.longitude,
+            altitude: self.altitude,
+            distance: self.distance,
+            speed: self.speed,
+            cadence: self.cadence,
+            power: self.power,
+        })
+    }
+}
+
+#[derive(Default)]
+struct GpxHandler {
+    source: Option<String>,
+    workouts: Vec<Workout>,
+    sport: Option<String>,
+    records: Vec<Record>,
+    point: Option<Point>,
+}
+
+impl Handler for GpxHandler {
+    fn start(&mut self, element: &BytesStart) -> Result<()> {
+        match element.local_name().as_ref() {
+            b"gpx" => self.source = attribute(element, b"creator")?,
+            b"trk" => {
+                self.sport = None;
+                self.records.clear();
+            }
+            b"trkpt" => {
+                self.point = Some(Point {
+                    latitude: attribute(element, b"lat")?
+                        .map(|value| parse_number("lat", &value))
+                        .transpose()?,
+                    longitude: attribute(element, b"lon")?
+                        .map(|value| parse_number("lon", &value))
+                        .transpose()?,
+                    ..Default::default()
+                })
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn text(&mut self, path: &[Vec<u8>], text: &str) -> Result<()> {
+        let (name, parent) = match path {
+            [.., parent, name] => (name.as_slice(), parent.as_slice()),
+            _ => return Ok(()),
+        };
+
+        let point = match &mut self.point {
+            Some(point) => point,
+            None => {
+                if name == b"type" && parent == b"trk" {
+                    self.sport = Some(text.to_owned());
+                }
+                return Ok(());
+            }
+        };
+
+        // The extensions of Garmin and Health Auto Export have the same names
+        match name {
+            b"time" => point.date = Some(parse_date(text)?),
+            b"ele" => point.altitude = Some(parse_number("ele", text)?),
+            b"hr" => point.heart_rate = Some(parse_number("hr", text)?),
+            b"cad" => point.cadence = Some(parse_number("cad", text)?),
+            b"speed" => point.speed = Some(parse_number("speed", text)?),
+            b"power" => point.power = Some(parse_number("power", text)?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn end(&mut self, name: &[u8]) {
+        match name {
+            b"trkpt" => {
+                if let Some(record) = self.point.take().and_then(Point::into_record) {
+                    self.records.push(record);
+                }
+            }
+            b"trk" => {
+                let mut records = std::mem::take(&mut self.records);
+                let distance = complete_records(&mut records);
+
+                let (start_time, end_time) = match (records.first(), records.last()) {
+                    (Some(first), Some(last)) => (first.date, last.date),
+                    _ => {
+                        warn!("skipped track without timestamps");
+                        return;
+                    }
+                };
+                let heart_rates: Vec<_> = records
+                    .iter()
+                    .filter_map(|record| record.heart_rate)
+                    .collect();
+
+                self.workouts.push(Workout {
+                    sport: normalize_sport(self.sport.as_deref().unwrap_or_default()),
+                    sub_sport: None,
+                    start_time,
+                    end_time,
+                    elapsed_time: (end_time - start_time).as_seconds_f64(),
+                    timer_time: None,
+                    distance,
+                    calories: None,
+                    avg_heart_rate: average(&heart_rates),
+                    max_heart_rate: heart_rates.iter().copied().reduce(f64::max),
+                    laps: Vec::new(),
+                    records,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(self) -> RouteFile {
+        RouteFile {
+            source: self.source.unwrap_or_else(|| "gpx".to_owned()),
+            workouts: self.workouts,
+        }
+    }
+}
+
+#[derive(Default)]
+struct TcxHandler {
+    source: Option<String>,
+    workouts: Vec<Workout>,
+    activity: Option<TcxActivity>,
+    lap: Option<TcxLap>,
+    point: Option<Point>,
+}
+
+struct TcxActivity {
+    sport: String,
+    id: Option<OffsetDateTime>,
+    laps: Vec<Lap>,
+    records: Vec<Record>,
+}
+
+#[derive(Default)]
+struct TcxLap {
+    start_time: Option<OffsetDateTime>,
+    elapsed_time: Option<f64>,
+    distance: Option<f64>,
+    calories: Option<f64>,
+    avg_heart_rate: Option<f64>,
+    max_heart_rate: Option<f64>,
+}
+
+impl Handler for TcxHandler {
+    fn start(&mut self, element: &BytesStart) -> Result<()> {
+        match element.local_name().as_ref() {
+            b"Activity" => {
+                self.activity = Some(TcxActivity {
+                    sport: normalize_sport(&attribute(element, b"Sport")?.unwrap_or_default()),
+                    id: None,
+                    laps: Vec::new(),
+                    records: Vec::new(),
+                })
+            }
+            b"Lap" => {
+                self.lap = Some(TcxLap {
+                    start_time: attribute(element, b"StartTime")?
+                        .map(|value| parse_date(&value))
+                        .transpose()?,
+                    ..Default::default()
+                })
+            }
+            b"Trackpoint" => self.point = Some(Point::default()),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn text(&mut self, path: &[Vec<u8>], text: &str) -> Result<()> {
+        let (name, parent) = match path {
+            [.., parent, name] => (name.as_slice(), parent.as_slice()),
+            _ => return Ok(()),
+        };
+
+        if let Some(point) = &mut self.point {
+            match (parent, name) {
+                (_, b"Time") => point.date = Some(parse_date(text)?),
+                (_, b"LatitudeDegrees") => {
+                    point.latitude = Some(parse_number("LatitudeDegrees", text)?)
+                }
+                (_, b"LongitudeDegrees") => {
+                    point.longitude = Some(parse_number("LongitudeDegrees", text)?)
+                }
+                (_, b"AltitudeMeters") => {
+                    point.altitude = Some(parse_number("AltitudeMeters", text)?)
+                }
+                (_, b"DistanceMeters") => {
+                    point.distance = Some(parse_number("DistanceMeters", text)?)
+                }
+                (b"HeartRateBpm", b"Value") => {
+                    point.heart_rate = Some(parse_number("HeartRateBpm", text)?)
+                }
+                (_, b"Cadence") => point.cadence = Some(parse_number("Cadence", text)?),
+                (_, b"Speed") => point.speed = Some(parse_number("Speed", text)?),
+                (_, b"Watts") => point.power = Some(parse_number("Watts", text)?),
+                _ => {}
+            }
+        } else if let Some(lap) = &mut self.lap {
+            match (parent, name) {
+                (_, b"TotalTimeSeconds") => {
+                    lap.elapsed_time = Some(parse_number("TotalTimeSeconds", text)?)
+                }
+                (b"Lap", b"DistanceMeters") => {
+                    lap.distance = Some(parse_number("DistanceMeters", text)?)
+                }
+                (_, b"Calories") => lap.calories = Some(parse_number("Calories", text)?),
+                (b"AverageHeartRateBpm", b"Value") => {
+                    lap.avg_heart_rate = Some(parse_number("AverageHeartRateBpm", text)?)
+                }
+                (b"MaximumHeartRateBpm", b"Value") => {
+                    lap.max_heart_rate = Some(parse_number("MaximumHeartRateBpm", text)?)
+                }
+                _ => {}
+            }
+        } else if let Some(activity) = &mut self.activity {
+            match (parent, name) {
+                (b"Activity", b"Id") => activity.id = Some(parse_date(text)?),
+                (b"Creator", b"Name") => self.source = Some(text.to_owned()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn end(&mut self, name: &[u8]) {
+        match name {
+            b"Trackpoint" => {
+                let record = self.point.take().and_then(Point::into_record);
+                if let (Some(activity), Some(record)) = (&mut self.activity, record) {
+                    activity.records.push(record);
+                }
+            }
+            b"Lap" => {
+                let lap = self.lap.take().and_then(|lap| {
+                    let start_time = lap.start_time?;
+                    let elapsed_time = lap.elapsed_time.unwrap_or_default();
+
+                    Some(Lap {
+                        start_time,
+                        end_time: start_time + time::Duration::seconds_f64(elapsed_time),
+                        elapsed_time,
+                        timer_time: None,
+                        distance: lap.distance,
+                        calories: lap.calories,
+                        avg_heart_rate: lap.avg_heart_rate,
+                        max_heart_rate: lap.max_heart_rate,
+                    })
+                });
+                if let (Some(activity), Some(lap)) = (&mut self.activity, lap) {
+                    activity.laps.push(lap);
+                }
+            }
+            b"Activity" => {
+                if let Some(activity) = self.activity.take() {
+                    match activity.into_workout() {
+                        Some(workout) => self.workouts.push(workout),
+                        None => warn!("skipped activity without timestamps"),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(self) -> RouteFile {
+        RouteFile {
+            source: self.source.unwrap_or_else(|| "tcx".to_owned()),
+            workouts: self.workouts,
+        }
+    }
+}
+
+impl TcxActivity {
+    /// The totals of the workout are the ones of its laps, or computed from the records without laps.
+    fn into_workout(mut self) -> Option<Workout> {
+        let record_distance = complete_records(&mut self.records);
+
+        let start_time = self
+            .id
+            .or_else(|| self.laps.first().map(|lap| lap.start_time))
+            .or_else(|| self.records.first().map(|record| record.date))?;
+
+        let elapsed_time = if self.laps.is_empty() {
+            let end_time = self.records.last().map_or(start_time, |record| record.date);
+            (end_time - start_time).as_seconds_f64()
+        } else {
+            self.laps.iter().map(|lap| lap.elapsed_time).sum()
+        };
+
+        let sum = |value: fn(&Lap) -> Option<f64>| -> Option<f64> {
+            self.laps.iter().filter_map(value).reduce(|a, b| a + b)
+        };
+
+        // The average heart rate of the laps is weighted by their duration
+        let weighted_heart_rates: Vec<_> = self
+            .laps
+            .iter()
+            .filter_map(|lap| Some((lap.avg_heart_rate?, lap.elapsed_time)))
+            .collect();
+        let total_weight: f64 = weighted_heart_rates.iter().map(|(_, weight)| weight).sum();
+        let avg_heart_rate = if total_weight > 0.0 {
+            Some(
+                weighted_heart_rates
+                    .iter()
+                    .map(|(heart_rate, weight)| heart_rate * weight)
+                    .sum::<f64>()
+                    / total_weight,
+            )
+        } else {
+            None
+        };
+
+        Some(Workout {
+            sport: self.sport,
+            sub_sport: None,
+            start_time,
+            end_time: start_time + time::Duration::seconds_f64(elapsed_time),
+            elapsed_time,
+            timer_time: None,
+            distance: sum(|lap| lap.distance).or(record_distance),
+            calories: sum(|lap| lap.calories),
+            avg_heart_rate,
+            max_heart_rate: self
+                .laps
+                .iter()
+                .filter_map(|lap| lap.max_heart_rate)
+                .reduce(f64::max),
+            laps: self.laps,
+            records: self.records,
+        })
+    }
+}
+
+/// Sorts the records and fills in their missing distance and speed, returns the total distance.
+///
+/// The distance is computed from the positions, the speed from the distance and the time
+/// since the previous record.
+fn complete_records(records: &mut [Record]) -> Option<f64> {
+    records.sort_by_key(|record| record.date);
+
+    if let Some(first) = records.first_mut() {
+        if first.distance.is_none() && first.latitude.is_some() && first.longitude.is_some() {
+            first.distance = Some(0.0);
+        }
+    }
+
+    for index in 1..records.len() {
+        let (before, after) = records.split_at_mut(index);
+        let (previous, record) = (&before[index - 1], &mut after[0]);
+
+        if record.distance.is_none() {
+            let position = record.latitude.zip(record.longitude);
+            let previous_position = previous.latitude.zip(previous.longitude);
+            if let (Some(position), Some(previous_position)) = (position, previous_position) {
+                record.distance = Some(
+                    previous.distance.unwrap_or_default()
+                        + haversine_distance(previous_position, position),
+                );
+            }
+        }
+
+        let elapsed = (record.date - previous.date).as_seconds_f64();
+        if let (None, Some(distance), Some(previous_distance)) =
+            (record.speed, record.distance, previous.distance)
+        {
+            if elapsed > 0.0 {
+                record.speed = Some((distance - previous_distance).max(0.0) / elapsed);
+            }
+        }
+    }
+
+    records.iter().rev().find_map(|record| record.distance)
+}
+
+/// Distance in meters between two positions in degrees, on a spherical Earth.
+fn haversine_distance((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_008.8;
+
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let delta_lat = lat2 - lat1;
+    let delta_lon = (lon2 - lon1).to_radians();
+
+    let a =
+        (delta_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (delta_lon / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// Names the sports like the FIT files do, `Biking` in a TCX file is `cycling`.
+fn normalize_sport(sport: &str) -> String {
+    let sport = sport.trim().to_lowercase().replace(' ', "_");
+    match sport.as_str() {
+        "" | "other" => "generic".to_owned(),
+        "biking" => "cycling".to_owned(),
+        _ => sport,
+    }
+}
+
+fn average(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f64>() / values.len() as f64)
+    }
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Result<Option<String>> {
+    for attribute in element.attributes() {
+        let attribute = attribute.map_err(quick_xml::Error::from)?;
+        if attribute.key.local_name().as_ref() == name {
+            return Ok(Some(attribute.unescape_value()?.into_owned()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn parse_number(element: &str, value: &str) -> Result<f64> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|value: &f64| value.is_finite())
+        .ok_or_else(|| Error::InvalidValue {
+            element: element.to_owned(),
+            value: value.to_owned(),
+        })
+}
+
+fn parse_date(value: &str) -> Result<OffsetDateTime> {
+    OffsetDateTime::parse(value.trim(), &Rfc3339).map_err(|_| Error::InvalidValue {
+        element: "time".to_owned(),
+        value: value.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="Health Auto Export" xmlns="http://www.topografix.com/GPX/1/1"
+  xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v1">
+  <trk>
+    <name>Morning Run</name>
+    <type>running</type>
+    <trkseg>
+      <trkpt lat="48.8566" lon="2.3522">
+        <ele>35.0</ele>
+        <time>2023-03-25T09:00:00Z</time>
+        <extensions><gpxtpx:TrackPointExtension><gpxtpx:hr>120</gpxtpx:hr></gpxtpx:TrackPointExtension></extensions>
+      </trkpt>
+      <trkpt lat="48.8576" lon="2.3522">
+        <ele>36.0</ele>
+        <time>2023-03-25T09:00:30Z</time>
+        <extensions><gpxtpx:TrackPointExtension><gpxtpx:hr>140</gpxtpx:hr></gpxtpx:TrackPointExtension></extensions>
+      </trkpt>
+      <trkpt lat="48.8586" lon="2.3522"><ele>37.0</ele></trkpt>
+      <trkpt lat="48.8586" lon="2.3522">
+        <time>2023-03-25T09:01:00Z</time>
+        <extensions><speed>3.5</speed><course>0</course></extensions>
+      </trkpt>
+    </trkseg>
+  </trk>
+</gpx>"#;
+
+    const TCX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2"
+  xmlns:ns3="http://www.garmin.com/xmlschemas/ActivityExtension/v2">
+  <Activities>
+    <Activity Sport="Biking">
+      <Id>2023-03-26T10:00:00Z</Id>
+      <Lap StartTime="2023-03-26T10:00:00Z">
+        <TotalTimeSeconds>600</TotalTimeSeconds>
+        <DistanceMeters>4000</DistanceMeters>
+        <Calories>100</Calories>
+        <AverageHeartRateBpm><Value>130</Value></AverageHeartRateBpm>
+        <MaximumHeartRateBpm><Value>150</Value></MaximumHeartRateBpm>
+        <Track>
+          <Trackpoint>
+            <Time>2023-03-26T10:00:00Z</Time>
+            <Position><LatitudeDegrees>45.0</LatitudeDegrees><LongitudeDegrees>5.0</LongitudeDegrees></Position>
+            <AltitudeMeters>200</AltitudeMeters>
+            <DistanceMeters>0</DistanceMeters>
+            <HeartRateBpm><Value>110</Value></HeartRateBpm>
+          </Trackpoint>
+          <Trackpoint>
+            <Time>2023-03-26T10:00:10Z</Time>
+            <Position><LatitudeDegrees>45.001</LatitudeDegrees><LongitudeDegrees>5.0</LongitudeDegrees></Position>
+            <DistanceMeters>70</DistanceMeters>
+            <Extensions><ns3:TPX><ns3:Speed>7.5</ns3:Speed><ns3:Watts>180</ns3:Watts></ns3:TPX></Extensions>
+          </Trackpoint>
+        </Track>
+      </Lap>
+      <Lap StartTime="2023-03-26T10:10:00Z">
+        <TotalTimeSeconds>300</TotalTimeSeconds>
+        <DistanceMeters>2000</DistanceMeters>
+        <AverageHeartRateBpm><Value>160</Value></AverageHeartRateBpm>
+        <MaximumHeartRateBpm><Value>170</Value></MaximumHeartRateBpm>
+      </Lap>
+      <Creator><Name>Edge 530</Name></Creator>
+    </Activity>
+  </Activities>
+  <Author><Name>Garmin Connect</Name></Author>
+</TrainingCenterDatabase>"#;
+
+    #[test]
+    fn decode_gpx() {
+        let route_file = decode(GPX.as_bytes()).unwrap();
+
+        assert_eq!("Health Auto Export", route_file.source);
+        assert_eq!(1, route_file.workouts.len());
+
+        let workout = &route_file.workouts[0];
+        assert_eq!("running", workout.sport);
+        assert_eq!(datetime!(2023-03-25 09:00 UTC), workout.start_time);
+        assert_eq!(datetime!(2023-03-25 09:01 UTC), workout.end_time);
+        assert_eq!(60.0, workout.elapsed_time);
+        assert_eq!(Some(130.0), workout.avg_heart_rate);
+        assert_eq!(Some(140.0), workout.max_heart_rate);
+
+        // The point without a time is skipped
+        let records = &workout.records;
+        assert_eq!(3, records.len());
+        assert_eq!(Some(35.0), records[0].altitude);
+        assert_eq!(Some(0.0), records[0].distance);
+        assert_eq!(None, records[0].speed);
+
+        // 0.001° of latitude is about 111 meters
+        let distance = records[1].distance.unwrap();
+        assert!((distance - 111.2).abs() < 0.1, "{}", distance);
+        assert!((records[1].speed.unwrap() - distance / 30.0).abs() < 1e-9);
+
+        // The speed of the file is kept
+        assert_eq!(Some(3.5), records[2].speed);
+        assert_eq!(records[2].distance, workout.distance);
+    }
+
+    #[test]
+    fn decode_tcx() {
+        let route_file = decode(TCX.as_bytes()).unwrap();
+
+        assert_eq!("Edge 530", route_file.source);
+        assert_eq!(1, route_file.workouts.len());
+
+        let workout = &route_file.workouts[0];
+        assert_eq!("cycling", workout.sport);
+        assert_eq!(datetime!(2023-03-26 10:00 UTC), workout.start_time);
+        assert_eq!(datetime!(2023-03-26 10:15 UTC), workout.end_time);
+        assert_eq!(900.0, workout.elapsed_time);
+        assert_eq!(Some(6000.0), workout.distance);
+        assert_eq!(Some(100.0), workout.calories);
+        // Weighted by the duration of the laps
+        assert_eq!(Some(140.0), workout.avg_heart_rate);
+        assert_eq!(Some(170.0), workout.max_heart_rate);
+
+        assert_eq!(2, workout.laps.len());
+        assert_eq!(datetime!(2023-03-26 10:15 UTC), workout.laps[1].end_time);
+
+        assert_eq!(
+            vec![
+                Record {
+                    date: datetime!(2023-03-26 10:00 UTC),
+                    heart_rate: Some(110.0),
+                    latitude: Some(45.0),
+                    longitude: Some(5.0),
+                    altitude: Some(200.0),
+                    distance: Some(0.0),
+                    speed: None,
+                    cadence: None,
+                    power: None,
+                },
+                Record {
+                    date: datetime!(2023-03-26 10:00:10 UTC),
+                    heart_rate: None,
+                    latitude: Some(45.001),
+                    longitude: Some(5.0),
+                    altitude: None,
+                    distance: Some(70.0),
+                    speed: Some(7.5),
+                    cadence: None,
+                    power: Some(180.0),
+                },
+            ],
+            workout.records
+        );
+    }
+
+    #[test]
+    fn decode_invalid_files() {
+        assert!(matches!(decode(b"<kml></kml>"), Err(Error::UnknownFormat)));
+        assert!(matches!(decode(b"not xml"), Err(Error::UnknownFormat)));
+
+        let without_times = r#"<gpx><trk><trkseg><trkpt lat="1" lon="2"/></trkseg></trk></gpx>"#;
+        assert!(matches!(
+            decode(without_times.as_bytes()),
+            Err(Error::NoWorkout)
+        ));
+
+        let invalid = r#"<gpx><trk><trkseg><trkpt lat="north" lon="2"/></trkseg></trk></gpx>"#;
+        assert!(matches!(
+            decode(invalid.as_bytes()),
+            Err(Error::InvalidValue { .. })
+        ));
+    }
+}
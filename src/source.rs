@@ -0,0 +1,54 @@
+use crate::db;
+use std::collections::BTreeMap;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    SQLx(#[from] sqlx::Error),
+    #[error("empty source pattern for metric {0:?}")]
+    EmptyPattern(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Replaces the source priorities stored in the database with the configured ones.
+///
+/// They're stored so that the exporter and the read path can resolve overlapping data points in SQL.
+pub async fn sync_priorities(
+    tx: &mut db::Transaction,
+    priorities: &BTreeMap<String, Vec<String>>,
+) -> Result<()> {
+    let mut metric_names = Vec::new();
+    let mut patterns = Vec::new();
+    let mut ranks = Vec::new();
+
+    for (metric_name, metric_patterns) in priorities {
+        for (rank, pattern) in metric_patterns.iter().enumerate() {
+            if pattern.is_empty() {
+                return Err(Error::EmptyPattern(metric_name.clone()));
+            }
+
+            metric_names.push(metric_name.clone());
+            patterns.push(pattern.clone());
+            ranks.push(rank as i32);
+        }
+    }
+
+    sqlx::query!(r#"DELETE FROM source_priority"#)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO source_priority(metric_name, pattern, rank)
+        SELECT * FROM UNNEST($1::text[], $2::text[], $3::int4[])
+        ON CONFLICT DO NOTHING"#,
+        &metric_names,
+        &patterns,
+        &ranks,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
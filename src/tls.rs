@@ -0,0 +1,169 @@
+use crate::configuration::TlsConfig;
+use crate::shutdown::Shutdown;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::server::AllowAnyAuthenticatedClient;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{error, info};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unable to read {path}: {err}")]
+    Read { path: String, err: io::Error },
+    #[error("no certificate found in {0}")]
+    NoCertificate(String),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Builds the rustls configuration of the server from the certificate files.
+///
+/// If a client CA is configured the clients must present a certificate signed by it,
+/// which restricts the server to the devices given such a certificate.
+pub fn load_server_config(config: &TlsConfig) -> Result<rustls::ServerConfig> {
+    let certs = read_certs(&config.cert_path)?;
+    let key = read_private_key(&config.key_path)?;
+
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+
+    let mut server_config = match &config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in read_certs(client_ca_path)? {
+                roots.add(&cert)?;
+            }
+
+            builder
+                .with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(roots)))
+                .with_single_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+    };
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+/// Reloads the certificates when their files change, until shutdown.
+///
+/// The files are polled since certificates are renewed rarely. A certificate that can't be
+/// loaded is logged and the previous one kept, so a renewal caught halfway doesn't stop the server.
+pub async fn watch(rustls_config: RustlsConfig, config: TlsConfig, mut shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(config.reload_interval());
+    let mut last_modified = modification_times(&config);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = interval.tick() => {},
+        }
+
+        let modified = modification_times(&config);
+        if modified == last_modified {
+            continue;
+        }
+
+        match load_server_config(&config) {
+            Ok(server_config) => {
+                rustls_config.reload_from_config(Arc::new(server_config));
+                last_modified = modified;
+
+                info!(cert_path = config.cert_path, "reloaded TLS certificates");
+            }
+            Err(err) => error!(%err, "unable to reload TLS certificates"),
+        }
+    }
+}
+
+fn modification_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [
+        Some(&config.cert_path),
+        Some(&config.key_path),
+        config.client_ca_path.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    fs::read(Path::new(path)).map_err(|err| Error::Read {
+        path: path.to_owned(),
+        err,
+    })
+}
+
+fn read_certs(path: &str) -> Result<Vec<rustls::Certificate>> {
+    let data = read_file(path)?;
+
+    let certs = rustls_pemfile::certs(&mut data.as_slice()).map_err(|err| Error::Read {
+        path: path.to_owned(),
+        err,
+    })?;
+    if certs.is_empty() {
+        return Err(Error::NoCertificate(path.to_owned()));
+    }
+
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn read_private_key(path: &str) -> Result<rustls::PrivateKey> {
+    let data = read_file(path)?;
+    let mut reader = data.as_slice();
+
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader).map_err(|err| Error::Read {
+            path: path.to_owned(),
+            err,
+        })?;
+
+        match item {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => return Ok(rustls::PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(Error::NoPrivateKey(path.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls_config(cert_path: &str, key_path: &str) -> TlsConfig {
+        TlsConfig {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            client_ca_path: None,
+            reload_interval_secs: 60,
+        }
+    }
+
+    #[test]
+    fn load_missing_files() {
+        let err = load_server_config(&tls_config("/nonexistent/cert.pem", "/nonexistent/key.pem"))
+            .unwrap_err();
+        assert!(matches!(err, Error::Read { path, .. } if path == "/nonexistent/cert.pem"));
+    }
+
+    #[test]
+    fn load_files_without_pem_items() {
+        let path = std::env::temp_dir().join("hdas-tls-test-empty.pem");
+        fs::write(&path, "not a certificate\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let err = load_server_config(&tls_config(path, path)).unwrap_err();
+        assert!(matches!(err, Error::NoCertificate(_)));
+    }
+}
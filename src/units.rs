@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown unit {0:?}")]
+    UnknownUnit(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dimension {
+    Mass,
+    Length,
+    Energy,
+    Duration,
+    Speed,
+    Volume,
+    Temperature,
+}
+
+/// A unit, converted to the base unit of its dimension with `value * factor + offset`.
+struct Unit {
+    name: &'static str,
+    dimension: Dimension,
+    factor: f64,
+    offset: f64,
+}
+
+const fn unit(name: &'static str, dimension: Dimension, factor: f64) -> Unit {
+    Unit {
+        name,
+        dimension,
+        factor,
+        offset: 0.0,
+    }
+}
+
+/// The units that can be converted, named like Health Auto Export names them.
+///
+/// The base units are kg, m, kJ, s, m/s, L and degC.
+const UNITS: &[Unit] = &[
+    unit("kg", Dimension::Mass, 1.0),
+    unit("g", Dimension::Mass, 0.001),
+    unit("mg", Dimension::Mass, 0.000_001),
+    unit("lb", Dimension::Mass, 0.453_592_37),
+    unit("oz", Dimension::Mass, 0.028_349_523_125),
+    unit("st", Dimension::Mass, 6.350_293_18),
+    unit("m", Dimension::Length, 1.0),
+    unit("km", Dimension::Length, 1000.0),
+    unit("cm", Dimension::Length, 0.01),
+    unit("mm", Dimension::Length, 0.001),
+    unit("mi", Dimension::Length, 1609.344),
+    unit("yd", Dimension::Length, 0.9144),
+    unit("ft", Dimension::Length, 0.3048),
+    unit("in", Dimension::Length, 0.0254),
+    unit("kJ", Dimension::Energy, 1.0),
+    unit("kcal", Dimension::Energy, 4.184),
+    unit("Cal", Dimension::Energy, 4.184),
+    unit("s", Dimension::Duration, 1.0),
+    unit("ms", Dimension::Duration, 0.001),
+    unit("min", Dimension::Duration, 60.0),
+    unit("hr", Dimension::Duration, 3600.0),
+    unit("m/s", Dimension::Speed, 1.0),
+    unit("km/hr", Dimension::Speed, 1000.0 / 3600.0),
+    unit("mi/hr", Dimension::Speed, 1609.344 / 3600.0),
+    unit("L", Dimension::Volume, 1.0),
+    unit("mL", Dimension::Volume, 0.001),
+    unit("fl_oz_us", Dimension::Volume, 0.029_573_529_562_5),
+    unit("cup_us", Dimension::Volume, 0.236_588_236_5),
+    unit("degC", Dimension::Temperature, 1.0),
+    Unit {
+        name: "degF",
+        dimension: Dimension::Temperature,
+        factor: 5.0 / 9.0,
+        offset: -32.0 * 5.0 / 9.0,
+    },
+];
+
+fn find_unit(name: &str) -> Option<&'static Unit> {
+    UNITS.iter().find(|unit| unit.name == name)
+}
+
+/// Converts values from a unit to another one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conversion {
+    factor: f64,
+    offset: f64,
+}
+
+impl Conversion {
+    /// Returns the conversion from `from` to `to`, `None` if the units are unknown
+    /// or not of the same dimension.
+    pub fn new(from: &str, to: &str) -> Option<Self> {
+        if from == to {
+            return Some(Self {
+                factor: 1.0,
+                offset: 0.0,
+            });
+        }
+
+        let from = find_unit(from)?;
+        let to = find_unit(to)?;
+        if from.dimension != to.dimension {
+            return None;
+        }
+
+        Some(Self {
+            factor: from.factor / to.factor,
+            offset: (from.offset - to.offset) / to.factor,
+        })
+    }
+
+    pub fn apply(&self, value: f64) -> f64 {
+        value * self.factor + self.offset
+    }
+}
+
+/// Canonical unit of the metrics, by metric name.
+///
+/// The data points of a metric are converted to its canonical unit when they're ingested,
+/// so that the values stay comparable when the unit used by the phone changes.
+#[derive(Debug, Default)]
+pub struct CanonicalUnits(BTreeMap<String, String>);
+
+impl CanonicalUnits {
+    /// Builds the canonical units from the configuration, failing if a unit is unknown.
+    pub fn new(units: &BTreeMap<String, String>) -> Result<Self> {
+        for unit in units.values() {
+            if find_unit(unit).is_none() {
+                return Err(Error::UnknownUnit(unit.clone()));
+            }
+        }
+
+        Ok(Self(units.clone()))
+    }
+
+    pub fn get(&self, metric_name: &str) -> Option<&str> {
+        self.0.get(metric_name).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(value: f64, from: &str, to: &str) -> f64 {
+        Conversion::new(from, to).unwrap().apply(value)
+    }
+
+    fn assert_close(exp: f64, got: f64) {
+        assert!((exp - got).abs() < 1e-9, "expected {}, got {}", exp, got);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_close(72.5, convert(72.5, "kg", "kg"));
+        assert_close(1.0, convert(2.204_622_621_848_776, "lb", "kg"));
+        assert_close(1.609_344, convert(1.0, "mi", "km"));
+        assert_close(1.0, convert(1000.0, "m", "km"));
+        assert_close(4.184, convert(1.0, "kcal", "kJ"));
+        assert_close(1.5, convert(90.0, "min", "hr"));
+        assert_close(37.0, convert(98.6, "degF", "degC"));
+        assert_close(98.6, convert(37.0, "degC", "degF"));
+    }
+
+    #[test]
+    fn incompatible_conversions() {
+        assert!(Conversion::new("kg", "km").is_none());
+        assert!(Conversion::new("foo", "kg").is_none());
+        assert!(Conversion::new("count", "count").is_some());
+    }
+
+    #[test]
+    fn canonical_units_must_be_known() {
+        let mut units = BTreeMap::new();
+        units.insert("weight_body_mass".to_owned(), "kg".to_owned());
+
+        let canonical_units = CanonicalUnits::new(&units).unwrap();
+        assert_eq!(Some("kg"), canonical_units.get("weight_body_mass"));
+        assert_eq!(None, canonical_units.get("step_count"));
+
+        units.insert("walking_speed".to_owned(), "furlong/fortnight".to_owned());
+        assert!(CanonicalUnits::new(&units).is_err());
+    }
+}
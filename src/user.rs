@@ -0,0 +1,165 @@
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    SQLx(#[from] sqlx::Error),
+    #[error("invalid user name {0:?}, only 1 to 64 letters, digits, '_', '.' and '-' are allowed")]
+    InvalidName(String),
+    #[error("user {0:?} already exists")]
+    AlreadyExists(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// User owning the data ingested before there were multiple users, and the bootstrap admin token.
+pub const DEFAULT_USER: &str = "default";
+
+#[derive(Debug, serde::Serialize)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+}
+
+/// Creates a user, returns its ID.
+///
+/// The name is used as a tag value in the exported series so its charset is restricted.
+pub async fn create_user<'e, E>(executor: E, name: &str) -> Result<i64>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    if !is_valid_name(name) {
+        return Err(Error::InvalidName(name.to_owned()));
+    }
+
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO app_user(name) VALUES($1)
+        ON CONFLICT (name) DO NOTHING
+        RETURNING id"#,
+        name,
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    match record {
+        Some(record) => Ok(record.id),
+        None => Err(Error::AlreadyExists(name.to_owned())),
+    }
+}
+
+/// Returns the ID of the user named `name`, creating it if it doesn't exist.
+pub async fn get_or_create_user<'e, E>(executor: E, name: &str) -> Result<i64>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    if !is_valid_name(name) {
+        return Err(Error::InvalidName(name.to_owned()));
+    }
+
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO app_user(name) VALUES($1)
+        ON CONFLICT (name) DO UPDATE SET name = excluded.name
+        RETURNING id"#,
+        name,
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok(record.id)
+}
+
+pub async fn get_user<'e, E>(executor: E, id: i64) -> Result<Option<User>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let user = sqlx::query_as!(
+        User,
+        r#"SELECT id, name, created_at FROM app_user WHERE id = $1"#,
+        id,
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(user)
+}
+
+pub async fn find_user_by_name<'e, E>(executor: E, name: &str) -> Result<Option<User>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let user = sqlx::query_as!(
+        User,
+        r#"SELECT id, name, created_at FROM app_user WHERE name = $1"#,
+        name,
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(user)
+}
+
+pub async fn list_users<'e, E>(executor: E) -> Result<Vec<User>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let users = sqlx::query_as!(
+        User,
+        r#"SELECT id, name, created_at FROM app_user ORDER BY id"#,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(users)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use crate::db;
+    use secrecy::ExposeSecret;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn valid_names() {
+        assert!(is_valid_name("vincent"));
+        assert!(is_valid_name("lab-member_1.2"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("vincent rischmann"));
+        assert!(!is_valid_name("foo=bar"));
+        assert!(!is_valid_name(&"a".repeat(65)));
+    }
+
+    #[tokio::test]
+    async fn test_create_user() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let id = create_user(&mut tx, "test-user").await.unwrap();
+
+        let err = create_user(&mut tx, "test-user").await.unwrap_err();
+        assert!(matches!(err, Error::AlreadyExists(_)));
+
+        let same_id = get_or_create_user(&mut tx, "test-user").await.unwrap();
+        assert_eq!(id, same_id);
+
+        let user = get_user(&mut tx, id).await.unwrap().unwrap();
+        assert_eq!("test-user", user.name);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::configuration::{RangeConfig, ValidationConfig};
+use crate::health_data::MetricDataPoint;
+use std::collections::BTreeMap;
+use time::{Duration, OffsetDateTime};
+
+/// Why a data point was refused.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum Violation {
+    #[error("value {0} is not a finite number")]
+    NotFinite(f64),
+    #[error("value {value} is below the minimum {min}")]
+    BelowMinimum { value: f64, min: f64 },
+    #[error("value {value} is above the maximum {max}")]
+    AboveMaximum { value: f64, max: f64 },
+    #[error("date {} is too far in the future", format_date(.0))]
+    InFuture(OffsetDateTime),
+    #[error("date {} is too old", format_date(.0))]
+    TooOld(OffsetDateTime),
+    #[error("{0} end doesn't come after its start")]
+    EndBeforeStart(&'static str),
+}
+
+impl Violation {
+    /// Name of the violated rule, used as a metric label.
+    pub fn rule(&self) -> &'static str {
+        match self {
+            Self::NotFinite(_) => "not_finite",
+            Self::BelowMinimum { .. } | Self::AboveMaximum { .. } => "range",
+            Self::InFuture(_) => "future",
+            Self::TooOld(_) => "too_old",
+            Self::EndBeforeStart(_) => "window",
+        }
+    }
+}
+
+fn format_date(date: &OffsetDateTime) -> String {
+    date.format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_else(|_| date.to_string())
+}
+
+/// Checks the data points against the configured rules.
+pub struct Validator {
+    max_future_skew: Duration,
+    max_age: Option<Duration>,
+    ranges: BTreeMap<String, RangeConfig>,
+}
+
+impl Validator {
+    pub fn new(config: &ValidationConfig) -> Self {
+        Self {
+            max_future_skew: Duration::seconds(config.max_future_skew_secs as i64),
+            max_age: config.max_age_days.map(|days| Duration::days(days as i64)),
+            ranges: config.ranges.clone(),
+        }
+    }
+
+    /// Returns the rules of the data points of a metric ingested at `now`.
+    pub fn rules(&self, metric_name: &str, now: OffsetDateTime) -> Rules {
+        Rules {
+            range: self.ranges.get(metric_name).cloned(),
+            not_before: self.max_age.map(|max_age| now - max_age),
+            not_after: now + self.max_future_skew,
+        }
+    }
+}
+
+/// The rules of the data points of a metric.
+#[derive(Debug, Clone)]
+pub struct Rules {
+    range: Option<RangeConfig>,
+    not_before: Option<OffsetDateTime>,
+    not_after: OffsetDateTime,
+}
+
+impl Rules {
+    /// Checks a data point, its values must be in the units of its metric.
+    pub fn check(&self, data_point: &MetricDataPoint) -> Result<(), Violation> {
+        match data_point {
+            MetricDataPoint::HeartRate(data_point) => {
+                self.check_date(data_point.date)?;
+                self.check_value(data_point.min)?;
+                self.check_value(data_point.max)?;
+                self.check_value(data_point.avg)
+            }
+            MetricDataPoint::SleepAnalysis(data_point) => {
+                self.check_date(data_point.date)?;
+                if data_point.sleep_end <= data_point.sleep_start {
+                    return Err(Violation::EndBeforeStart("sleep"));
+                }
+                if data_point.in_bed_end <= data_point.in_bed_start {
+                    return Err(Violation::EndBeforeStart("in bed"));
+                }
+                self.check_value(data_point.asleep)?;
+                self.check_value(data_point.in_bed)
+            }
+            MetricDataPoint::Generic(data_point) => {
+                self.check_date(data_point.date)?;
+                self.check_value(data_point.quantity)
+            }
+        }
+    }
+
+    fn check_date(&self, date: OffsetDateTime) -> Result<(), Violation> {
+        if date > self.not_after {
+            return Err(Violation::InFuture(date));
+        }
+        match self.not_before {
+            Some(not_before) if date < not_before => Err(Violation::TooOld(date)),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_value(&self, value: f64) -> Result<(), Violation> {
+        if !value.is_finite() {
+            return Err(Violation::NotFinite(value));
+        }
+
+        let range = match &self.range {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        match (range.min, range.max) {
+            (Some(min), _) if value < min => Err(Violation::BelowMinimum { value, min }),
+            (_, Some(max)) if value > max => Err(Violation::AboveMaximum { value, max }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health_data::{GenericDataPoint, HeartRateDataPoint, SleepAnalysisDataPoint};
+    use time::macros::datetime;
+
+    const NOW: OffsetDateTime = datetime!(2023-03-19 12:00 UTC);
+
+    fn validator() -> Validator {
+        let mut ranges = BTreeMap::new();
+        ranges.insert(
+            "heart_rate".to_owned(),
+            RangeConfig {
+                min: Some(20.0),
+                max: Some(250.0),
+            },
+        );
+
+        Validator::new(&ValidationConfig {
+            max_future_skew_secs: 3600,
+            max_age_days: Some(365),
+            ranges,
+        })
+    }
+
+    fn heart_rate(date: OffsetDateTime, avg: f64) -> MetricDataPoint {
+        MetricDataPoint::HeartRate(HeartRateDataPoint {
+            date,
+            min: 60.0,
+            max: 80.0,
+            avg,
+            source: None,
+        })
+    }
+
+    fn generic(date: OffsetDateTime, quantity: f64) -> MetricDataPoint {
+        MetricDataPoint::Generic(GenericDataPoint {
+            date,
+            quantity,
+            source: None,
+        })
+    }
+
+    #[test]
+    fn check_ranges() {
+        let rules = validator().rules("heart_rate", NOW);
+
+        assert_eq!(Ok(()), rules.check(&heart_rate(NOW, 70.0)));
+        assert_eq!(
+            Err(Violation::BelowMinimum {
+                value: 0.0,
+                min: 20.0
+            }),
+            rules.check(&heart_rate(NOW, 0.0))
+        );
+        assert_eq!(
+            Err(Violation::AboveMaximum {
+                value: 300.0,
+                max: 250.0
+            }),
+            rules.check(&heart_rate(NOW, 300.0))
+        );
+
+        // No range for the other metrics, but the values must still be finite
+        let rules = validator().rules("weight_body_mass", NOW);
+        assert_eq!(Ok(()), rules.check(&generic(NOW, -1.0)));
+        assert_eq!(
+            "not_finite",
+            rules.check(&generic(NOW, f64::NAN)).unwrap_err().rule()
+        );
+        assert_eq!(
+            "not_finite",
+            rules
+                .check(&generic(NOW, f64::INFINITY))
+                .unwrap_err()
+                .rule()
+        );
+    }
+
+    #[test]
+    fn check_dates() {
+        let rules = validator().rules("step_count", NOW);
+
+        assert_eq!(
+            Ok(()),
+            rules.check(&generic(NOW + Duration::minutes(30), 1.0))
+        );
+        assert_eq!(
+            Err(Violation::InFuture(datetime!(2099-01-01 00:00 UTC))),
+            rules.check(&generic(datetime!(2099-01-01 00:00 UTC), 1.0))
+        );
+        assert_eq!(
+            Err(Violation::TooOld(datetime!(2021-01-01 00:00 UTC))),
+            rules.check(&generic(datetime!(2021-01-01 00:00 UTC), 1.0))
+        );
+        assert_eq!(
+            "date 2021-01-01T00:00:00Z is too old",
+            Violation::TooOld(datetime!(2021-01-01 00:00 UTC)).to_string()
+        );
+    }
+
+    #[test]
+    fn check_sleep_windows() {
+        let rules = validator().rules("sleep_analysis", NOW);
+
+        let mut data_point = SleepAnalysisDataPoint {
+            asleep: 7.0,
+            date: datetime!(2023-03-19 00:00 UTC),
+            sleep_source: "Apple Watch".to_owned(),
+            sleep_start: datetime!(2023-03-18 23:00 UTC),
+            sleep_end: datetime!(2023-03-19 06:00 UTC),
+            in_bed: 7.5,
+            in_bed_source: "iPhone".to_owned(),
+            in_bed_start: datetime!(2023-03-18 22:45 UTC),
+            in_bed_end: datetime!(2023-03-19 06:15 UTC),
+        };
+        assert_eq!(
+            Ok(()),
+            rules.check(&MetricDataPoint::SleepAnalysis(data_point.clone()))
+        );
+
+        data_point.sleep_end = data_point.sleep_start;
+        assert_eq!(
+            Err(Violation::EndBeforeStart("sleep")),
+            rules.check(&MetricDataPoint::SleepAnalysis(data_point))
+        );
+    }
+}
@@ -1,48 +1,89 @@
+use crate::archive;
+use crate::auth;
+use crate::auth::{ApiToken, Scope};
+use crate::configuration::IngesterConfig;
 use crate::db;
+use crate::fit;
 use crate::health_data;
-use health_data::{Metric, MetricDataPoint};
+use crate::idempotency;
+use crate::ingester;
+use crate::problem::Problem;
+use crate::query;
+use crate::rate_limit;
+use crate::route;
+use crate::user;
+use crate::workout;
+use axum::extract::rejection::{BytesRejection, JsonRejection, PathRejection, QueryRejection};
+use futures_util::StreamExt;
 use prometheus::Encoder;
-use tracing::{error, info};
+use tokio::sync::mpsc;
+use tracing::info;
 
+use std::io;
 use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct State {
     db: Arc<db::Db>,
+    strict: bool,
+    idempotency_key_ttl: std::time::Duration,
+    max_body_size: usize,
+    priority_window: std::time::Duration,
+    rate_limiter: Arc<rate_limit::RateLimiter>,
 }
 
 impl State {
-    pub fn new(db: Arc<db::Db>) -> Self {
-        Self { db }
+    pub fn new(
+        db: Arc<db::Db>,
+        config: &IngesterConfig,
+        idempotency_key_ttl: std::time::Duration,
+        max_body_size: usize,
+        priority_window: std::time::Duration,
+        rate_limiter: Arc<rate_limit::RateLimiter>,
+    ) -> Self {
+        Self {
+            db,
+            strict: config.strict,
+            idempotency_key_ttl,
+            max_body_size,
+            priority_window,
+            rate_limiter,
+        }
+    }
+
+    pub fn db(&self) -> &db::Db {
+        &self.db
+    }
+
+    pub fn rate_limiter(&self) -> &rate_limit::RateLimiter {
+        &self.rate_limiter
     }
 }
 
 pub enum HealthDataHandleError {
-    Http(http::StatusCode),
-    Json(serde_json::Error),
+    Problem(Problem),
     SQLx(sqlx::Error),
+    Archive(archive::Error),
+    Ingester(ingester::Error),
+    Idempotency(idempotency::Error),
 }
 
 impl axum::response::IntoResponse for HealthDataHandleError {
     fn into_response(self) -> axum::response::Response {
-        let result = match self {
-            Self::Http(code) => (code, code.to_string()),
-            Self::Json(err) => (http::StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-            Self::SQLx(err) => (http::StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        let problem = match self {
+            Self::Problem(problem) => problem,
+            Self::SQLx(err) => Problem::internal(err),
+            Self::Archive(err) => Problem::internal(err),
+            Self::Ingester(err) => Problem::internal(err),
+            Self::Idempotency(err) => Problem::internal(err),
         };
-        result.into_response()
+        problem.into_response()
     }
 }
 
-impl From<http::StatusCode> for HealthDataHandleError {
-    fn from(status_code: http::StatusCode) -> Self {
-        Self::Http(status_code)
-    }
-}
-
-impl From<serde_json::Error> for HealthDataHandleError {
-    fn from(err: serde_json::Error) -> Self {
-        Self::Json(err)
+impl From<Problem> for HealthDataHandleError {
+    fn from(problem: Problem) -> Self {
+        Self::Problem(problem)
     }
 }
 
@@ -52,341 +93,981 @@ impl From<sqlx::Error> for HealthDataHandleError {
     }
 }
 
+impl From<archive::Error> for HealthDataHandleError {
+    fn from(err: archive::Error) -> Self {
+        Self::Archive(err)
+    }
+}
+
+impl From<ingester::Error> for HealthDataHandleError {
+    fn from(err: ingester::Error) -> Self {
+        Self::Ingester(err)
+    }
+}
+
+impl From<idempotency::Error> for HealthDataHandleError {
+    fn from(err: idempotency::Error) -> Self {
+        Self::Idempotency(err)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct HealthDataQuery {
+    strict: Option<bool>,
+}
+
+#[derive(serde::Serialize)]
+pub struct HealthDataResponse {
+    upload_id: i64,
+}
+
+/// Queues a payload for ingestion.
+///
+/// The ingestion is done asynchronously, the status and the counts of data points inserted
+/// and rejected are available at `/api/v1/uploads/{upload_id}`.
+///
+/// The body is decompressed beforehand by [`decompression`] if it's compressed. It's validated
+/// and archived as it's received, without being held in memory in full.
+///
+/// With an `Idempotency-Key` header, a retry with the same key and body returns the original upload
+/// instead of queuing it again, and reusing the key with another body is rejected with a 409.
 pub async fn health_data(
     axum::extract::State(state): axum::extract::State<State>,
-    body: axum::body::Bytes,
-) -> Result<(http::StatusCode, String), HealthDataHandleError> {
-    let db = &state.db;
+    api_token: ApiToken,
+    query: Result<axum::extract::Query<HealthDataQuery>, QueryRejection>,
+    headers: http::HeaderMap,
+    body: axum::extract::BodyStream,
+) -> Result<HealthDataReply, HealthDataHandleError> {
+    api_token.require(Scope::Ingest)?;
+    check_content_encoding(&headers)?;
+
+    let idempotency_key = match headers.get(idempotency::IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => match value.to_str() {
+            Ok(key) if idempotency::is_valid_key(key) => Some(key.to_owned()),
+            _ => {
+                return Err(Problem::new(http::StatusCode::BAD_REQUEST)
+                    .with_detail(
+                        "invalid idempotency key, it must be 1 to 255 visible ASCII characters",
+                    )
+                    .into())
+            }
+        },
+        None => None,
+    };
+
+    let axum::extract::Query(query) = query.map_err(Problem::from)?;
+    let mut body = BodyReader::new(body, state.max_body_size);
 
-    // First get a string of the body
+    // A retry doesn't need to be validated and archived again
 
-    let body_string = match String::from_utf8(body.to_vec()) {
-        Ok(body) => body,
-        Err(err) => {
-            error!(%err, "unable to convert body to a UTF-8 string");
-            return Err(http::StatusCode::BAD_REQUEST.into());
+    if let Some(key) = &idempotency_key {
+        let stored_key = idempotency::find(
+            &state.db.pool,
+            api_token.user_id,
+            key,
+            state.idempotency_key_ttl,
+        )
+        .await?;
+        if let Some(stored_key) = stored_key {
+            let mut hasher = idempotency::BodyHasher::default();
+            while let Some(part) = body.next().await? {
+                hasher.update(&part);
+            }
+
+            return replay_upload(&stored_key, &hasher.finish());
         }
-    };
+    }
 
-    // Parse the body
+    // Receive the body, what can't be a payload is rejected early
+    // and the data points are parsed by the ingester
 
-    let payload: health_data::HealthDataPayload = serde_json::from_str(&body_string)?;
+    let (writer, body_hash) = receive_payload(&mut body).await?;
+    let body_size = body.size;
 
-    for metric in payload.data.metrics {
-        let mut tx = db.pool.begin().await?;
+    // Archive and queue the body
 
-        let metric_id = insert_metric(&mut tx, &metric).await?;
+    let mut tx = state.db.pool.begin().await?;
+    let raw_upload_id = writer
+        .store(
+            &mut tx,
+            api_token.user_id,
+            Some(api_token.id),
+            &headers,
+            archive::Format::Json,
+        )
+        .await?;
+    let strict = query.strict.unwrap_or(state.strict);
+    let upload_id = ingester::enqueue_upload(&mut tx, raw_upload_id, strict).await?;
 
-        if !metric.data.is_empty() {
-            info!(
-                metric_name = metric.name,
-                metric_datapoints = metric.data.len(),
-                metric_units = metric.units,
-                "got data points",
-            );
+    if let Some(key) = &idempotency_key {
+        let stored = idempotency::store(
+            &mut tx,
+            api_token.user_id,
+            key,
+            &body_hash,
+            upload_id,
+            state.idempotency_key_ttl,
+        )
+        .await?;
 
-            for data_point in &metric.data {
-                insert_metric_data_point(&mut tx, metric_id, data_point).await?;
+        // A concurrent upload with the same key committed first
+        if !stored {
+            tx.rollback().await?;
+
+            let stored_key = idempotency::find(
+                &state.db.pool,
+                api_token.user_id,
+                key,
+                state.idempotency_key_ttl,
+            )
+            .await?
+            .ok_or_else(|| Problem::internal("idempotency key stored but not found"))?;
+
+            return replay_upload(&stored_key, &body_hash);
+        }
+    }
+
+    tx.commit().await?;
+
+    info!(
+        upload_id,
+        raw_upload_id,
+        api_token_id = api_token.id,
+        body_size,
+        "queued upload"
+    );
+
+    Ok((
+        http::StatusCode::ACCEPTED,
+        http::HeaderMap::new(),
+        axum::Json(HealthDataResponse { upload_id }),
+    ))
+}
+
+/// Reads a request body as it's received, rejects it once it's larger than the maximum size.
+///
+/// The [`axum::extract::DefaultBodyLimit`] doesn't apply to a body stream.
+struct BodyReader {
+    stream: axum::extract::BodyStream,
+    size: usize,
+    max_size: usize,
+}
+
+impl BodyReader {
+    fn new(stream: axum::extract::BodyStream, max_size: usize) -> Self {
+        Self {
+            stream,
+            size: 0,
+            max_size,
+        }
+    }
+
+    async fn next(&mut self) -> Result<Option<axum::body::Bytes>, Problem> {
+        let part = match self.stream.next().await {
+            Some(Ok(part)) => part,
+            Some(Err(err)) => {
+                return Err(Problem::new(http::StatusCode::BAD_REQUEST)
+                    .with_detail(format!("failed to read the request body: {}", err)))
             }
+            None => return Ok(None),
+        };
+
+        self.size += part.len();
+        if self.size > self.max_size {
+            return Err(
+                Problem::new(http::StatusCode::PAYLOAD_TOO_LARGE).with_detail(format!(
+                    "the request body is larger than {} bytes",
+                    self.max_size
+                )),
+            );
+        }
+
+        Ok(Some(part))
+    }
+}
+
+/// Compresses a payload as it's received, returns the writer to archive it with and the hash of the body.
+///
+/// The payload is validated by a blocking task as it's received, the upload stops
+/// at the first validation error. No transaction is open in the meantime.
+async fn receive_payload(
+    body: &mut BodyReader,
+) -> Result<(archive::BodyWriter, Vec<u8>), HealthDataHandleError> {
+    let mut writer = archive::BodyWriter::new().await?;
+
+    let (sender, receiver) = mpsc::channel(2);
+    let validator = tokio::task::spawn_blocking(move || {
+        validate_payload(archive::ChannelReader::new(receiver))
+    });
+
+    let mut hasher = idempotency::BodyHasher::default();
+    while let Some(part) = body.next().await? {
+        hasher.update(&part);
+
+        // The validator stopped at an error
+        if sender.send(Ok(part.clone())).await.is_err() {
+            break;
         }
 
-        tx.commit().await?;
+        writer = writer.write(part).await?;
     }
 
-    Ok((http::StatusCode::ACCEPTED, "Accepted".to_owned()))
+    drop(sender);
+    validator.await.map_err(Problem::internal)??;
+
+    Ok((writer, hasher.finish()))
 }
 
-pub enum MetricsError {
-    FromUTF8(std::string::FromUtf8Error),
-    Prometheus(prometheus::Error),
+/// Queues a FIT, GPX or TCX file for ingestion, the format is the last segment of the path.
+///
+/// The FIT monitoring files are ingested as data points and the FIT activity files as workouts.
+/// The tracks of the GPX files and the activities of the TCX files are ingested as workouts with their route.
+/// Like the payloads, the file is archived and ingested asynchronously, the status of the upload
+/// is available at `/api/v1/uploads/{upload_id}`.
+pub async fn import_file(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+    format: Result<axum::extract::Path<String>, PathRejection>,
+    query: Result<axum::extract::Query<HealthDataQuery>, QueryRejection>,
+    headers: http::HeaderMap,
+    body: Result<axum::body::Bytes, BytesRejection>,
+) -> Result<HealthDataReply, HealthDataHandleError> {
+    api_token.require(Scope::Ingest)?;
+    check_content_encoding(&headers)?;
+
+    let axum::extract::Path(format) = format.map_err(Problem::from)?;
+    let axum::extract::Query(query) = query.map_err(Problem::from)?;
+    let body = body.map_err(Problem::from)?;
+
+    let (format, is_valid) = match format.as_str() {
+        "fit" => (archive::Format::Fit, fit::is_fit_file(&body)),
+        "gpx" => (
+            archive::Format::Gpx,
+            route::detect_format(&body) == Some(route::Format::Gpx),
+        ),
+        "tcx" => (
+            archive::Format::Tcx,
+            route::detect_format(&body) == Some(route::Format::Tcx),
+        ),
+        _ => {
+            return Err(Problem::new(http::StatusCode::NOT_FOUND)
+                .with_detail(format!(
+                    "unknown format {:?}, supported formats are fit, gpx and tcx",
+                    format
+                ))
+                .into())
+        }
+    };
+    if !is_valid {
+        return Err(Problem::new(http::StatusCode::UNPROCESSABLE_ENTITY)
+            .with_detail(format!(
+                "the body isn't a {} file",
+                format.as_str().to_uppercase()
+            ))
+            .into());
+    }
+
+    let body_size = body.len();
+
+    let mut tx = state.db.pool.begin().await?;
+    let raw_upload_id = archive::store(
+        &mut tx,
+        api_token.user_id,
+        Some(api_token.id),
+        &headers,
+        format,
+        body,
+    )
+    .await?;
+    let strict = query.strict.unwrap_or(state.strict);
+    let upload_id = ingester::enqueue_upload(&mut tx, raw_upload_id, strict).await?;
+    tx.commit().await?;
+
+    info!(
+        upload_id,
+        raw_upload_id,
+        api_token_id = api_token.id,
+        body_size,
+        format = format.as_str(),
+        "queued file"
+    );
+
+    Ok((
+        http::StatusCode::ACCEPTED,
+        http::HeaderMap::new(),
+        axum::Json(HealthDataResponse { upload_id }),
+    ))
 }
 
-impl axum::response::IntoResponse for MetricsError {
+/// Rejects the bodies that weren't decompressed by [`decompression`].
+///
+/// The supported encodings are removed from the headers once decoded.
+fn check_content_encoding(headers: &http::HeaderMap) -> Result<(), Problem> {
+    match headers.get(http::header::CONTENT_ENCODING) {
+        Some(encoding) if encoding != "identity" => Err(Problem::new(
+            http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        )
+        .with_detail(format!(
+            "unsupported content encoding {:?}, supported encodings are gzip, deflate and zstd",
+            encoding
+        ))),
+        _ => Ok(()),
+    }
+}
+
+type HealthDataReply = (
+    http::StatusCode,
+    http::HeaderMap,
+    axum::Json<HealthDataResponse>,
+);
+
+/// Returns the response of the upload created with an idempotency key, if the body is the same.
+fn replay_upload(
+    stored_key: &idempotency::StoredKey,
+    body_hash: &[u8],
+) -> Result<HealthDataReply, HealthDataHandleError> {
+    if !stored_key.matches(body_hash) {
+        return Err(Problem::new(http::StatusCode::CONFLICT)
+            .with_detail("idempotency key already used with a different body")
+            .into());
+    }
+
+    info!(
+        upload_id = stored_key.upload_id,
+        "replayed upload for idempotency key"
+    );
+
+    let mut headers = http::HeaderMap::new();
+    headers.insert(
+        idempotency::IDEMPOTENT_REPLAYED_HEADER,
+        http::HeaderValue::from_static("true"),
+    );
+
+    Ok((
+        http::StatusCode::ACCEPTED,
+        headers,
+        axum::Json(HealthDataResponse {
+            upload_id: stored_key.upload_id,
+        }),
+    ))
+}
+
+/// Returns the layer decompressing the bodies sent with a `gzip`, `deflate` or `zstd` content encoding.
+///
+/// The body is decompressed as it's read, so the body size limit applies to the decompressed size
+/// and a small compressed body can't expand to more than the limit.
+/// Other encodings are passed through untouched and rejected by the handler.
+pub fn decompression() -> tower_http::decompression::RequestDecompressionLayer {
+    tower_http::decompression::RequestDecompressionLayer::new()
+        .gzip(true)
+        .deflate(true)
+        .zstd(true)
+        .pass_through_unaccepted(true)
+}
+
+/// Handles the errors of the [`decompression`] layer.
+pub async fn handle_decompression_error(err: axum::BoxError) -> Problem {
+    Problem::internal(err)
+}
+
+/// Checks that the body is a payload with the expected structure.
+///
+/// Syntax errors are reported with a 400, structure errors with a 422 and the JSON pointer
+/// of the invalid field. Invalid data points are not reported here, they're rejected
+/// individually during the ingestion.
+fn validate_payload<R: io::Read>(body: R) -> Result<(), Problem> {
+    let mut deserializer = serde_json::Deserializer::from_reader(io::BufReader::new(body));
+
+    let result =
+        serde_path_to_error::deserialize::<_, health_data::PayloadOutline>(&mut deserializer);
+    if let Err(err) = result {
+        let pointer = json_pointer(err.path());
+        return Err(json_problem(err.into_inner()).with_pointer(pointer));
+    }
+
+    deserializer.end().map_err(json_problem)
+}
+
+fn json_problem(err: serde_json::Error) -> Problem {
+    let status = match err.classify() {
+        serde_json::error::Category::Data => http::StatusCode::UNPROCESSABLE_ENTITY,
+        _ => http::StatusCode::BAD_REQUEST,
+    };
+
+    Problem::new(status).with_detail(err.to_string())
+}
+
+/// Formats a path as a RFC 6901 JSON pointer.
+///
+/// The pointer stops at the first unknown segment, for example when the body ends in the middle of an object.
+fn json_pointer(path: &serde_path_to_error::Path) -> String {
+    use serde_path_to_error::Segment;
+
+    let mut result = String::new();
+    for segment in path.iter() {
+        match segment {
+            Segment::Seq { index } => result.push_str(&format!("/{}", index)),
+            Segment::Map { key } => {
+                result.push('/');
+                result.push_str(&key.replace('~', "~0").replace('/', "~1"));
+            }
+            Segment::Enum { variant } => {
+                result.push('/');
+                result.push_str(variant);
+            }
+            Segment::Unknown => break,
+        }
+    }
+
+    result
+}
+
+pub enum UploadHandleError {
+    Problem(Problem),
+    SQLx(sqlx::Error),
+    Archive(archive::Error),
+    Ingester(ingester::Error),
+}
+
+impl axum::response::IntoResponse for UploadHandleError {
     fn into_response(self) -> axum::response::Response {
-        let body = match self {
-            Self::FromUTF8(err) => err.to_string(),
-            Self::Prometheus(err) => err.to_string(),
+        let problem = match self {
+            Self::Problem(problem) => problem,
+            Self::SQLx(err) => Problem::internal(err),
+            Self::Archive(err) => Problem::internal(err),
+            Self::Ingester(err) => Problem::internal(err),
         };
+        problem.into_response()
+    }
+}
 
-        (http::StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+impl From<Problem> for UploadHandleError {
+    fn from(problem: Problem) -> Self {
+        Self::Problem(problem)
     }
 }
 
-impl From<std::string::FromUtf8Error> for MetricsError {
-    fn from(err: std::string::FromUtf8Error) -> Self {
-        Self::FromUTF8(err)
+impl From<sqlx::Error> for UploadHandleError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::SQLx(err)
     }
 }
 
-impl From<prometheus::Error> for MetricsError {
-    fn from(err: prometheus::Error) -> Self {
-        Self::Prometheus(err)
+impl From<archive::Error> for UploadHandleError {
+    fn from(err: archive::Error) -> Self {
+        Self::Archive(err)
     }
 }
 
-pub async fn metrics() -> Result<String, MetricsError> {
-    let mut buffer = Vec::<u8>::new();
+impl From<ingester::Error> for UploadHandleError {
+    fn from(err: ingester::Error) -> Self {
+        Self::Ingester(err)
+    }
+}
 
-    let encoder = prometheus::TextEncoder::new();
-    let metric_families = prometheus::gather();
-    encoder.encode(&metric_families, &mut buffer)?;
+pub async fn upload(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+    id: Result<axum::extract::Path<i64>, PathRejection>,
+) -> Result<axum::Json<ingester::Upload>, UploadHandleError> {
+    api_token.require(Scope::Read)?;
 
-    let result = String::from_utf8(buffer)?;
+    let axum::extract::Path(id) = id.map_err(Problem::from)?;
 
-    Ok(result)
+    let upload = get_visible_upload(&state, &api_token, id).await?;
+
+    Ok(axum::Json(upload))
 }
 
-async fn insert_metric(tx: &mut db::Transaction, metric: &Metric) -> Result<i64, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"
-        INSERT INTO metric(name, units) VALUES($1, $2)
-        ON CONFLICT (name) DO UPDATE SET units = excluded.units
-        RETURNING id"#,
-        metric.name,
-        metric.units,
-    )
-    .fetch_one(tx)
-    .await?;
+pub async fn upload_rejected_data_points(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+    id: Result<axum::extract::Path<i64>, PathRejection>,
+) -> Result<axum::Json<Vec<ingester::RejectedDataPoint>>, UploadHandleError> {
+    api_token.require(Scope::Read)?;
 
-    Ok(result.id)
-}
-
-async fn insert_metric_data_point(
-    tx: &mut db::Transaction,
-    metric_id: i64,
-    data_point: &MetricDataPoint,
-) -> Result<(), sqlx::Error> {
-    match data_point {
-        MetricDataPoint::HeartRate(data_point) => {
-            sqlx::query!(
-                r#"
-                INSERT INTO data_point_heart_rate(metric_id, date, min, max, avg)
-                VALUES($1, $2, $3, $4, $5)
-                ON CONFLICT DO NOTHING"#,
-                metric_id,
-                data_point.date,
-                data_point.min,
-                data_point.max,
-                data_point.avg,
-            )
-            .execute(tx)
-            .await?;
+    let axum::extract::Path(id) = id.map_err(Problem::from)?;
+
+    get_visible_upload(&state, &api_token, id).await?;
+
+    let rejected_data_points = ingester::get_rejected_data_points(&state.db.pool, id).await?;
+
+    Ok(axum::Json(rejected_data_points))
+}
+
+/// Returns an upload if it belongs to the user of the token, admin tokens can see all uploads.
+///
+/// The uploads of other users are reported as not found to not reveal their existence.
+async fn get_visible_upload(
+    state: &State,
+    api_token: &ApiToken,
+    id: i64,
+) -> Result<ingester::Upload, UploadHandleError> {
+    match ingester::get_upload(&state.db.pool, id).await? {
+        Some(upload)
+            if upload.user_id == api_token.user_id || api_token.has_scope(Scope::Admin) =>
+        {
+            Ok(upload)
         }
-        MetricDataPoint::SleepAnalysis(data_point) => {
-            sqlx::query!(
-                r#"
-                INSERT INTO data_point_sleep_analysis(
-                  metric_id, date,
-                  sleep_start, sleep_end, sleep_source,
-                  in_bed_start, in_bed_end, in_bed_source,
-                  in_bed, asleep
-                )
-                VALUES(
-                  $1, $2,
-                  $3, $4, $5,
-                  $6, $7, $8,
-                  $9, $10
-                )
-                ON CONFLICT DO NOTHING"#,
-                metric_id,
-                data_point.date,
-                data_point.sleep_start,
-                data_point.sleep_end,
-                data_point.sleep_source,
-                data_point.in_bed_start,
-                data_point.in_bed_end,
-                data_point.in_bed_source,
-                data_point.in_bed,
-                data_point.asleep,
-            )
-            .execute(tx)
-            .await?;
+        _ => Err(Problem::new(http::StatusCode::NOT_FOUND)
+            .with_detail(format!("upload {} not found", id))
+            .into()),
+    }
+}
+
+pub enum WorkoutHandleError {
+    Problem(Problem),
+    Workout(workout::Error),
+}
+
+impl axum::response::IntoResponse for WorkoutHandleError {
+    fn into_response(self) -> axum::response::Response {
+        let problem = match self {
+            Self::Problem(problem) => problem,
+            Self::Workout(err) => Problem::internal(err),
+        };
+        problem.into_response()
+    }
+}
+
+impl From<Problem> for WorkoutHandleError {
+    fn from(problem: Problem) -> Self {
+        Self::Problem(problem)
+    }
+}
+
+impl From<workout::Error> for WorkoutHandleError {
+    fn from(err: workout::Error) -> Self {
+        Self::Workout(err)
+    }
+}
+
+/// Returns the route of a workout as a GeoJSON feature.
+///
+/// Like the uploads, the workouts of other users are reported as not found unless the token is an admin token.
+/// A workout recorded without positions has no route.
+pub async fn workout_route(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+    id: Result<axum::extract::Path<i64>, PathRejection>,
+) -> Result<impl axum::response::IntoResponse, WorkoutHandleError> {
+    api_token.require(Scope::Read)?;
+
+    let axum::extract::Path(id) = id.map_err(Problem::from)?;
+
+    let route = match workout::get_route(&state.db.pool, id).await? {
+        Some(route) if route.user_id == api_token.user_id || api_token.has_scope(Scope::Admin) => {
+            route
         }
-        MetricDataPoint::Generic(data_point) => {
-            sqlx::query!(
-                r#"
-                INSERT INTO data_point_generic(metric_id, date, quantity)
-                VALUES($1, $2, $3)
-                ON CONFLICT DO NOTHING"#,
-                metric_id,
-                data_point.date,
-                data_point.quantity,
-            )
-            .execute(tx)
-            .await?;
+        _ => {
+            return Err(Problem::new(http::StatusCode::NOT_FOUND)
+                .with_detail(format!("workout {} not found", id))
+                .into())
         }
+    };
+    if route.points.len() < 2 {
+        return Err(Problem::new(http::StatusCode::NOT_FOUND)
+            .with_detail(format!("workout {} has no route", id))
+            .into());
     }
 
-    Ok(())
+    Ok((
+        [(http::header::CONTENT_TYPE, "application/geo+json")],
+        axum::Json(route.to_geojson()),
+    ))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::configuration;
-    use crate::db;
-    use health_data::*;
-    use secrecy::ExposeSecret;
+#[derive(serde::Deserialize)]
+pub struct ReplayRequest {
+    raw_upload_id: Option<i64>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    from: Option<time::OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    to: Option<time::OffsetDateTime>,
+    strict: Option<bool>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ReplayResponse {
+    upload_ids: Vec<i64>,
+}
+
+/// Queues archived raw uploads again, either a single one or all received in a time range.
+///
+/// This is meant to be used after fixing a parser bug to ingest the data that was dropped.
+pub async fn replay(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+    request: Result<axum::Json<ReplayRequest>, JsonRejection>,
+) -> Result<axum::Json<ReplayResponse>, UploadHandleError> {
+    api_token.require(Scope::Admin)?;
+
+    let axum::Json(request) = request.map_err(Problem::from)?;
 
-    async fn get_db() -> db::Db {
-        let config = configuration::get_configuration().unwrap();
+    let mut tx = state.db.pool.begin().await?;
 
-        db::Db::build(config.database.connection_string().expose_secret())
-            .await
-            .unwrap()
+    let strict = request.strict.unwrap_or(state.strict);
+
+    let raw_upload_ids = match request {
+        ReplayRequest {
+            raw_upload_id: Some(raw_upload_id),
+            from: None,
+            to: None,
+            ..
+        } => vec![raw_upload_id],
+        ReplayRequest {
+            raw_upload_id: None,
+            from: Some(from),
+            to,
+            ..
+        } => {
+            let to = to.unwrap_or_else(time::OffsetDateTime::now_utc);
+            archive::find_in_range(&mut tx, from, to).await?
+        }
+        _ => {
+            return Err(Problem::new(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .with_detail("either raw_upload_id or from must be set")
+                .into())
+        }
+    };
+
+    let mut upload_ids = Vec::with_capacity(raw_upload_ids.len());
+    for raw_upload_id in raw_upload_ids {
+        upload_ids.push(ingester::enqueue_upload(&mut tx, raw_upload_id, strict).await?);
     }
 
-    async fn insert_test_metric(tx: &mut db::Transaction) -> i64 {
-        let metric = Metric {
-            name: "foobar".to_owned(),
-            units: "j/Min".to_owned(),
-            data: Vec::new(),
+    tx.commit().await?;
+
+    info!(nb_uploads = upload_ids.len(), "replaying raw uploads");
+
+    Ok(axum::Json(ReplayResponse { upload_ids }))
+}
+
+pub enum TokenHandleError {
+    Problem(Problem),
+    Auth(auth::Error),
+    User(user::Error),
+}
+
+impl axum::response::IntoResponse for TokenHandleError {
+    fn into_response(self) -> axum::response::Response {
+        let problem = match self {
+            Self::Problem(problem) => problem,
+            Self::Auth(err) => Problem::internal(err),
+            Self::User(err) => Problem::internal(err),
         };
+        problem.into_response()
+    }
+}
 
-        let metric_id = insert_metric(tx, &metric).await.unwrap();
-        assert!(metric_id > 0);
+impl From<Problem> for TokenHandleError {
+    fn from(problem: Problem) -> Self {
+        Self::Problem(problem)
+    }
+}
 
-        metric_id
+impl From<auth::Error> for TokenHandleError {
+    fn from(err: auth::Error) -> Self {
+        Self::Auth(err)
     }
+}
 
-    fn now() -> time::OffsetDateTime {
-        time::OffsetDateTime::now_utc()
-            .replace_microsecond(0)
-            .unwrap()
+impl From<user::Error> for TokenHandleError {
+    fn from(err: user::Error) -> Self {
+        Self::User(err)
     }
+}
 
-    #[tokio::test]
-    async fn test_insert_metric() {
-        let db = get_db().await;
-        let mut tx = db.pool.begin().await.unwrap();
+#[derive(serde::Deserialize)]
+pub struct CreateTokenRequest {
+    /// The user owning the data uploaded with the token, defaults to the user of the admin token.
+    user_id: Option<i64>,
+    label: String,
+    scopes: Vec<Scope>,
+}
 
-        let metric_id = insert_test_metric(&mut tx).await;
+#[derive(serde::Serialize)]
+pub struct CreateTokenResponse {
+    id: i64,
+    token: String,
+}
+
+/// Creates an API token, the response is the only time the token is visible.
+pub async fn create_token(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+    request: Result<axum::Json<CreateTokenRequest>, JsonRejection>,
+) -> Result<(http::StatusCode, axum::Json<CreateTokenResponse>), TokenHandleError> {
+    api_token.require(Scope::Admin)?;
 
-        let metric = sqlx::query!(r#"SELECT name, units FROM metric WHERE id = $1"#, metric_id)
-            .fetch_one(&mut tx)
-            .await
-            .unwrap();
-        assert_eq!("foobar", metric.name.as_str());
+    let axum::Json(request) = request.map_err(Problem::from)?;
+    if request.scopes.is_empty() {
+        return Err(Problem::new(http::StatusCode::UNPROCESSABLE_ENTITY)
+            .with_detail("at least one scope is required")
+            .with_pointer("/scopes")
+            .into());
     }
 
-    #[tokio::test]
-    async fn test_insert_metric_data_point_generic() {
-        let db = get_db().await;
-        let mut tx = db.pool.begin().await.unwrap();
+    let user_id = request.user_id.unwrap_or(api_token.user_id);
+    if user::get_user(&state.db.pool, user_id).await?.is_none() {
+        return Err(Problem::new(http::StatusCode::UNPROCESSABLE_ENTITY)
+            .with_detail(format!("user {} not found", user_id))
+            .with_pointer("/user_id")
+            .into());
+    }
 
-        let metric_id = insert_test_metric(&mut tx).await;
+    let (id, token) =
+        auth::create_token(&state.db.pool, user_id, &request.label, &request.scopes).await?;
 
-        let generic_data_point = GenericDataPoint {
-            date: now(),
-            quantity: 234.0,
-        };
+    info!(
+        api_token_id = id,
+        user_id,
+        label = request.label,
+        "created api token"
+    );
 
-        insert_metric_data_point(
-            &mut tx,
-            metric_id,
-            &MetricDataPoint::Generic(generic_data_point.clone()),
-        )
-        .await
-        .unwrap();
+    Ok((
+        http::StatusCode::CREATED,
+        axum::Json(CreateTokenResponse { id, token }),
+    ))
+}
 
-        let metric = sqlx::query!(
-            r#"SELECT date, quantity FROM data_point_generic WHERE metric_id = $1"#,
-            metric_id,
-        )
-        .fetch_one(&mut tx)
-        .await
-        .unwrap();
+pub async fn tokens(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+) -> Result<axum::Json<Vec<ApiToken>>, TokenHandleError> {
+    api_token.require(Scope::Admin)?;
 
-        assert_eq!(generic_data_point.date, metric.date);
-        assert_eq!(generic_data_point.quantity, metric.quantity);
+    let tokens = auth::list_tokens(&state.db.pool).await?;
+
+    Ok(axum::Json(tokens))
+}
+
+/// Revokes an API token, the data points already ingested with it are kept.
+pub async fn revoke_token(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+    id: Result<axum::extract::Path<i64>, PathRejection>,
+) -> Result<http::StatusCode, TokenHandleError> {
+    api_token.require(Scope::Admin)?;
+
+    let axum::extract::Path(id) = id.map_err(Problem::from)?;
+
+    if !auth::revoke_token(&state.db.pool, id).await? {
+        return Err(Problem::new(http::StatusCode::NOT_FOUND)
+            .with_detail(format!("active token {} not found", id))
+            .into());
     }
 
-    #[tokio::test]
-    async fn test_insert_metric_data_point_heart_rate() {
-        let db = get_db().await;
-        let mut tx = db.pool.begin().await.unwrap();
+    info!(api_token_id = id, "revoked api token");
+
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
+#[derive(serde::Deserialize)]
+pub struct CreateUserRequest {
+    name: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct CreateUserResponse {
+    id: i64,
+}
+
+pub async fn create_user(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+    request: Result<axum::Json<CreateUserRequest>, JsonRejection>,
+) -> Result<(http::StatusCode, axum::Json<CreateUserResponse>), TokenHandleError> {
+    api_token.require(Scope::Admin)?;
+
+    let axum::Json(request) = request.map_err(Problem::from)?;
+
+    let id = match user::create_user(&state.db.pool, &request.name).await {
+        Ok(id) => id,
+        Err(err @ user::Error::InvalidName(_)) => {
+            return Err(Problem::new(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .with_detail(err.to_string())
+                .with_pointer("/name")
+                .into())
+        }
+        Err(err @ user::Error::AlreadyExists(_)) => {
+            return Err(Problem::new(http::StatusCode::CONFLICT)
+                .with_detail(err.to_string())
+                .into())
+        }
+        Err(err) => return Err(err.into()),
+    };
 
-        let metric_id = insert_test_metric(&mut tx).await;
+    info!(user_id = id, name = request.name, "created user");
 
-        let data_point = HeartRateDataPoint {
-            date: now(),
-            min: 2.0,
-            max: 50.0,
-            avg: 25.0,
+    Ok((
+        http::StatusCode::CREATED,
+        axum::Json(CreateUserResponse { id }),
+    ))
+}
+
+pub async fn users(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+) -> Result<axum::Json<Vec<user::User>>, TokenHandleError> {
+    api_token.require(Scope::Admin)?;
+
+    let users = user::list_users(&state.db.pool).await?;
+
+    Ok(axum::Json(users))
+}
+
+/// Maximum number of data points returned at once.
+const MAX_DATA_POINTS: i64 = 10_000;
+
+pub enum QueryHandleError {
+    Problem(Problem),
+    Query(query::Error),
+}
+
+impl axum::response::IntoResponse for QueryHandleError {
+    fn into_response(self) -> axum::response::Response {
+        let problem = match self {
+            Self::Problem(problem) => problem,
+            Self::Query(err @ query::Error::Unsupported(_)) => {
+                Problem::new(http::StatusCode::UNPROCESSABLE_ENTITY).with_detail(err.to_string())
+            }
+            Self::Query(err) => Problem::internal(err),
         };
+        problem.into_response()
+    }
+}
 
-        insert_metric_data_point(
-            &mut tx,
-            metric_id,
-            &MetricDataPoint::HeartRate(data_point.clone()),
-        )
-        .await
-        .unwrap();
-
-        let metric = sqlx::query!(
-            r#"
-            SELECT date, min, max, avg
-            FROM data_point_heart_rate WHERE metric_id = $1"#,
-            metric_id,
-        )
-        .fetch_one(&mut tx)
-        .await
-        .unwrap();
-
-        assert_eq!(data_point.date, metric.date);
-        assert_eq!(data_point.min, metric.min);
-        assert_eq!(data_point.max, metric.max);
-        assert_eq!(data_point.avg, metric.avg);
-    }
-
-    #[tokio::test]
-    async fn test_insert_metric_data_point_sleep_analysis() {
-        let db = get_db().await;
-        let mut tx = db.pool.begin().await.unwrap();
-
-        let metric_id = insert_test_metric(&mut tx).await;
-
-        let data_point = SleepAnalysisDataPoint {
-            date: now(),
-            asleep: 34.0,
-            sleep_source: "foobar".to_owned(),
-            sleep_start: now(),
-            sleep_end: now(),
-            in_bed: 5012.0,
-            in_bed_source: "barbaz".to_owned(),
-            in_bed_start: now(),
-            in_bed_end: now(),
+impl From<Problem> for QueryHandleError {
+    fn from(problem: Problem) -> Self {
+        Self::Problem(problem)
+    }
+}
+
+impl From<query::Error> for QueryHandleError {
+    fn from(err: query::Error) -> Self {
+        Self::Query(err)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct DataPointsQuery {
+    #[serde(with = "time::serde::rfc3339")]
+    from: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    to: time::OffsetDateTime,
+    limit: Option<i64>,
+}
+
+/// Returns the data points of a metric of the user of the token, oldest first.
+///
+/// Overlapping data points from multiple sources are resolved with the source priorities.
+pub async fn data_points(
+    axum::extract::State(state): axum::extract::State<State>,
+    api_token: ApiToken,
+    name: Result<axum::extract::Path<String>, PathRejection>,
+    query: Result<axum::extract::Query<DataPointsQuery>, QueryRejection>,
+) -> Result<axum::Json<Vec<query::DataPoint>>, QueryHandleError> {
+    api_token.require(Scope::Read)?;
+
+    let axum::extract::Path(name) = name.map_err(Problem::from)?;
+    let axum::extract::Query(query) = query.map_err(Problem::from)?;
+
+    let limit = query.limit.unwrap_or(MAX_DATA_POINTS);
+    if !(1..=MAX_DATA_POINTS).contains(&limit) {
+        return Err(Problem::new(http::StatusCode::UNPROCESSABLE_ENTITY)
+            .with_detail(format!("limit must be between 1 and {}", MAX_DATA_POINTS))
+            .into());
+    }
+
+    let data_points = query::list_data_points(
+        &state.db.pool,
+        api_token.user_id,
+        &name,
+        query.from,
+        query.to,
+        limit,
+        state.priority_window,
+    )
+    .await?;
+
+    Ok(axum::Json(data_points))
+}
+
+pub enum MetricsError {
+    FromUTF8(std::string::FromUtf8Error),
+    Prometheus(prometheus::Error),
+}
+
+impl axum::response::IntoResponse for MetricsError {
+    fn into_response(self) -> axum::response::Response {
+        let problem = match self {
+            Self::FromUTF8(err) => Problem::internal(err),
+            Self::Prometheus(err) => Problem::internal(err),
         };
+        problem.into_response()
+    }
+}
 
-        insert_metric_data_point(
-            &mut tx,
-            metric_id,
-            &MetricDataPoint::SleepAnalysis(data_point.clone()),
-        )
-        .await
-        .unwrap();
-
-        let metric = sqlx::query!(
-            r#"
-            SELECT
-              date, sleep_start, sleep_end, sleep_source,
-              in_bed_start, in_bed_end, in_bed_source,
-              in_bed, asleep
-            FROM data_point_sleep_analysis WHERE metric_id = $1"#,
-            metric_id,
-        )
-        .fetch_one(&mut tx)
-        .await
-        .unwrap();
-
-        assert_eq!(data_point.date, metric.date);
-        assert_eq!(data_point.sleep_start, metric.sleep_start);
-        assert_eq!(data_point.sleep_end, metric.sleep_end);
-        assert_eq!(data_point.sleep_source, metric.sleep_source);
-        assert_eq!(data_point.in_bed_start, metric.in_bed_start);
-        assert_eq!(data_point.in_bed_end, metric.in_bed_end);
-        assert_eq!(data_point.in_bed_source, metric.in_bed_source);
-        assert_eq!(data_point.in_bed, metric.in_bed);
-        assert_eq!(data_point.asleep, metric.asleep);
+impl From<std::string::FromUtf8Error> for MetricsError {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        Self::FromUTF8(err)
+    }
+}
+
+impl From<prometheus::Error> for MetricsError {
+    fn from(err: prometheus::Error) -> Self {
+        Self::Prometheus(err)
+    }
+}
+
+pub async fn metrics() -> Result<String, MetricsError> {
+    let mut buffer = Vec::<u8>::new();
+
+    let encoder = prometheus::TextEncoder::new();
+    let metric_families = prometheus::gather();
+    encoder.encode(&metric_families, &mut buffer)?;
+
+    let result = String::from_utf8(buffer)?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_payload_valid() {
+        let body = r#"{"data":{"metrics":[{"name":"heart_rate","units":"count/min","data":[{"Avg":1}]}]}}"#;
+
+        validate_payload(body.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn validate_payload_syntax_error() {
+        let body = r#"{"data":{"metrics":["#;
+
+        let problem = validate_payload(body.as_bytes()).unwrap_err();
+        assert_eq!(http::StatusCode::BAD_REQUEST, problem.status);
+        assert_eq!(Some("/data/metrics"), problem.pointer.as_deref());
+    }
+
+    #[test]
+    fn validate_payload_trailing_data() {
+        let body = r#"{"data":{"metrics":[]}} foobar"#;
+
+        let problem = validate_payload(body.as_bytes()).unwrap_err();
+        assert_eq!(http::StatusCode::BAD_REQUEST, problem.status);
+    }
+
+    #[test]
+    fn validate_payload_invalid_field() {
+        let body = r#"{"data":{"metrics":[{"name":"heart_rate","units":"count/min","data":[]},{"name":12,"units":"count","data":[]}]}}"#;
+
+        let problem = validate_payload(body.as_bytes()).unwrap_err();
+        assert_eq!(http::StatusCode::UNPROCESSABLE_ENTITY, problem.status);
+        assert_eq!(Some("/data/metrics/1/name"), problem.pointer.as_deref());
     }
 }
@@ -0,0 +1,607 @@
+use crate::db;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    SQLx(#[from] sqlx::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A workout recorded by a device, with its laps and the records sampled during it.
+///
+/// The times are in seconds, the distances in meters and the calories in kcal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workout {
+    pub sport: String,
+    pub sub_sport: Option<String>,
+    pub start_time: OffsetDateTime,
+    pub end_time: OffsetDateTime,
+    /// Includes the pauses, unlike the timer time.
+    pub elapsed_time: f64,
+    pub timer_time: Option<f64>,
+    pub distance: Option<f64>,
+    pub calories: Option<f64>,
+    pub avg_heart_rate: Option<f64>,
+    pub max_heart_rate: Option<f64>,
+    pub laps: Vec<Lap>,
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lap {
+    pub start_time: OffsetDateTime,
+    pub end_time: OffsetDateTime,
+    pub elapsed_time: f64,
+    pub timer_time: Option<f64>,
+    pub distance: Option<f64>,
+    pub calories: Option<f64>,
+    pub avg_heart_rate: Option<f64>,
+    pub max_heart_rate: Option<f64>,
+}
+
+/// A sample of a workout, the position is in degrees, the altitude in meters and the speed in m/s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub date: OffsetDateTime,
+    pub heart_rate: Option<f64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<f64>,
+    pub distance: Option<f64>,
+    pub speed: Option<f64>,
+    pub cadence: Option<f64>,
+    pub power: Option<f64>,
+}
+
+/// Inserts a workout with its laps and records, returns false if the workout already exists.
+///
+/// A workout is identified by its start time per user and source.
+pub async fn insert_workout(
+    tx: &mut db::Transaction,
+    user_id: i64,
+    api_token_id: Option<i64>,
+    source_id: i64,
+    workout: &Workout,
+) -> Result<bool> {
+    let record = sqlx::query!(
+        r#"
+        INSERT INTO workout(
+          user_id, api_token_id, source_id, sport, sub_sport, start_time, end_time,
+          elapsed_time, timer_time, distance, calories, avg_heart_rate, max_heart_rate
+        )
+        VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+        ON CONFLICT (user_id, source_id, start_time) DO NOTHING
+        RETURNING id"#,
+        user_id,
+        api_token_id,
+        source_id,
+        workout.sport,
+        workout.sub_sport,
+        workout.start_time,
+        workout.end_time,
+        workout.elapsed_time,
+        workout.timer_time,
+        workout.distance,
+        workout.calories,
+        workout.avg_heart_rate,
+        workout.max_heart_rate,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let workout_id = match record {
+        Some(record) => record.id,
+        None => return Ok(false),
+    };
+
+    insert_laps(tx, workout_id, &workout.laps).await?;
+    insert_records(tx, workout_id, &workout.records).await?;
+
+    Ok(true)
+}
+
+/// Attaches the records of a route to the workout of the user it overlaps the most,
+/// returns `None` if it doesn't overlap any workout.
+///
+/// The route fills the positions missing from the records of the workout at the same dates,
+/// and adds the records of the other dates. Returns the number of records added or filled.
+pub async fn attach_route(
+    tx: &mut db::Transaction,
+    user_id: i64,
+    route: &Workout,
+) -> Result<Option<u64>> {
+    let record = sqlx::query!(
+        r#"
+        SELECT id
+        FROM workout
+        WHERE user_id = $1 AND start_time < $3 AND end_time > $2
+        ORDER BY LEAST(end_time, $3) - GREATEST(start_time, $2) DESC, id
+        LIMIT 1"#,
+        user_id,
+        route.start_time,
+        route.end_time,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    match record {
+        Some(record) => Ok(Some(insert_records(tx, record.id, &route.records).await?)),
+        None => Ok(None),
+    }
+}
+
+/// The route of a workout, its records with a position.
+#[derive(Debug, PartialEq)]
+pub struct Route {
+    pub workout_id: i64,
+    pub user_id: i64,
+    pub sport: String,
+    pub start_time: OffsetDateTime,
+    pub end_time: OffsetDateTime,
+    pub points: Vec<RoutePoint>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RoutePoint {
+    pub date: OffsetDateTime,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+    pub speed: Option<f64>,
+    pub heart_rate: Option<f64>,
+}
+
+/// Returns the route of a workout, `None` if the workout doesn't exist.
+///
+/// The points are sorted by date, a workout without positions has an empty route.
+pub async fn get_route<'e, E>(executor: E, workout_id: i64) -> Result<Option<Route>>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+          w.id, w.user_id, w.sport, w.start_time, w.end_time,
+          r.date AS "date?", r.latitude AS "latitude?", r.longitude AS "longitude?",
+          r.altitude AS "altitude?", r.speed AS "speed?", r.heart_rate AS "heart_rate?"
+        FROM workout w
+        LEFT JOIN workout_record r
+          ON r.workout_id = w.id AND r.latitude IS NOT NULL AND r.longitude IS NOT NULL
+        WHERE w.id = $1
+        ORDER BY r.date"#,
+        workout_id,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    let first = match rows.first() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+
+    let points = rows
+        .iter()
+        .filter_map(|row| {
+            Some(RoutePoint {
+                date: row.date?,
+                latitude: row.latitude?,
+                longitude: row.longitude?,
+                altitude: row.altitude,
+                speed: row.speed,
+                heart_rate: row.heart_rate,
+            })
+        })
+        .collect();
+
+    Ok(Some(Route {
+        workout_id: first.id,
+        user_id: first.user_id,
+        sport: first.sport.clone(),
+        start_time: first.start_time,
+        end_time: first.end_time,
+        points,
+    }))
+}
+
+impl Route {
+    /// Returns the route as a GeoJSON feature with a line string geometry.
+    ///
+    /// The coordinates are `[longitude, latitude, altitude]`, without the altitude if it's unknown.
+    /// The times, speeds and heart rates of the points are in the properties, in the same order.
+    pub fn to_geojson(&self) -> serde_json::Value {
+        let coordinates: Vec<_> = self
+            .points
+            .iter()
+            .map(|point| match point.altitude {
+                Some(altitude) => vec![point.longitude, point.latitude, altitude],
+                None => vec![point.longitude, point.latitude],
+            })
+            .collect();
+        let times: Vec<_> = self
+            .points
+            .iter()
+            .map(|point| point.date.format(&Rfc3339).ok())
+            .collect();
+        let speeds: Vec<_> = self.points.iter().map(|point| point.speed).collect();
+        let heart_rates: Vec<_> = self.points.iter().map(|point| point.heart_rate).collect();
+
+        serde_json::json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": coordinates,
+            },
+            "properties": {
+                "workout_id": self.workout_id,
+                "sport": self.sport,
+                "start_time": self.start_time.format(&Rfc3339).ok(),
+                "end_time": self.end_time.format(&Rfc3339).ok(),
+                "times": times,
+                "speeds": speeds,
+                "heart_rates": heart_rates,
+            },
+        })
+    }
+}
+
+async fn insert_laps(tx: &mut db::Transaction, workout_id: i64, laps: &[Lap]) -> Result<()> {
+    if laps.is_empty() {
+        return Ok(());
+    }
+
+    let lap_indexes: Vec<_> = (0..laps.len() as i32).collect();
+    let start_times: Vec<_> = laps.iter().map(|lap| lap.start_time).collect();
+    let end_times: Vec<_> = laps.iter().map(|lap| lap.end_time).collect();
+    let elapsed_times: Vec<_> = laps.iter().map(|lap| lap.elapsed_time).collect();
+    let timer_times: Vec<_> = laps.iter().map(|lap| lap.timer_time).collect();
+    let distances: Vec<_> = laps.iter().map(|lap| lap.distance).collect();
+    let calories: Vec<_> = laps.iter().map(|lap| lap.calories).collect();
+    let avg_heart_rates: Vec<_> = laps.iter().map(|lap| lap.avg_heart_rate).collect();
+    let max_heart_rates: Vec<_> = laps.iter().map(|lap| lap.max_heart_rate).collect();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO workout_lap(
+          workout_id, lap_index, start_time, end_time, elapsed_time, timer_time,
+          distance, calories, avg_heart_rate, max_heart_rate
+        )
+        SELECT $1, * FROM UNNEST(
+          $2::integer[], $3::timestamptz[], $4::timestamptz[], $5::float8[], $6::float8[],
+          $7::float8[], $8::float8[], $9::float8[], $10::float8[]
+        )"#,
+        workout_id,
+        &lap_indexes,
+        &start_times,
+        &end_times,
+        &elapsed_times,
+        &timer_times as _,
+        &distances as _,
+        &calories as _,
+        &avg_heart_rates as _,
+        &max_heart_rates as _,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Inserts the records of a workout, returns the number of records inserted or updated.
+///
+/// A record already stored at the same date is only updated if it has no position,
+/// its missing values are then taken from the new record.
+async fn insert_records(
+    tx: &mut db::Transaction,
+    workout_id: i64,
+    records: &[Record],
+) -> Result<u64> {
+    // Only one record is kept per date
+    let mut records: Vec<_> = records.iter().collect();
+    records.sort_by_key(|record| record.date);
+    records.dedup_by_key(|record| record.date);
+
+    if records.is_empty() {
+        return Ok(0);
+    }
+
+    let dates: Vec<_> = records.iter().map(|record| record.date).collect();
+    let heart_rates: Vec<_> = records.iter().map(|record| record.heart_rate).collect();
+    let latitudes: Vec<_> = records.iter().map(|record| record.latitude).collect();
+    let longitudes: Vec<_> = records.iter().map(|record| record.longitude).collect();
+    let altitudes: Vec<_> = records.iter().map(|record| record.altitude).collect();
+    let distances: Vec<_> = records.iter().map(|record| record.distance).collect();
+    let speeds: Vec<_> = records.iter().map(|record| record.speed).collect();
+    let cadences: Vec<_> = records.iter().map(|record| record.cadence).collect();
+    let powers: Vec<_> = records.iter().map(|record| record.power).collect();
+
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO workout_record(
+          workout_id, date, heart_rate, latitude, longitude, altitude,
+          distance, speed, cadence, power
+        )
+        SELECT $1, * FROM UNNEST(
+          $2::timestamptz[], $3::float8[], $4::float8[], $5::float8[], $6::float8[],
+          $7::float8[], $8::float8[], $9::float8[], $10::float8[]
+        )
+        ON CONFLICT (workout_id, date) DO UPDATE
+        SET
+          heart_rate = COALESCE(workout_record.heart_rate, EXCLUDED.heart_rate),
+          latitude = EXCLUDED.latitude,
+          longitude = EXCLUDED.longitude,
+          altitude = COALESCE(workout_record.altitude, EXCLUDED.altitude),
+          distance = COALESCE(workout_record.distance, EXCLUDED.distance),
+          speed = COALESCE(workout_record.speed, EXCLUDED.speed),
+          cadence = COALESCE(workout_record.cadence, EXCLUDED.cadence),
+          power = COALESCE(workout_record.power, EXCLUDED.power)
+        WHERE
+          (workout_record.latitude IS NULL OR workout_record.longitude IS NULL)
+          AND EXCLUDED.latitude IS NOT NULL AND EXCLUDED.longitude IS NOT NULL"#,
+        workout_id,
+        &dates,
+        &heart_rates as _,
+        &latitudes as _,
+        &longitudes as _,
+        &altitudes as _,
+        &distances as _,
+        &speeds as _,
+        &cadences as _,
+        &powers as _,
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration;
+    use crate::user;
+    use secrecy::ExposeSecret;
+    use time::macros::datetime;
+
+    async fn get_db() -> db::Db {
+        let config = configuration::get_configuration(None).unwrap();
+
+        db::Db::build(config.database.connection_string().expose_secret())
+            .await
+            .unwrap()
+    }
+
+    fn test_workout() -> Workout {
+        let start_time = datetime!(2023-03-25 09:00 UTC);
+        let record = |seconds: i64, heart_rate: f64| Record {
+            date: start_time + time::Duration::seconds(seconds),
+            heart_rate: Some(heart_rate),
+            latitude: Some(48.85),
+            longitude: Some(2.35),
+            altitude: None,
+            distance: Some(seconds as f64 * 3.0),
+            speed: Some(3.0),
+            cadence: None,
+            power: None,
+        };
+
+        Workout {
+            sport: "running".to_owned(),
+            sub_sport: None,
+            start_time,
+            end_time: start_time + time::Duration::minutes(30),
+            elapsed_time: 1800.0,
+            timer_time: Some(1750.0),
+            distance: Some(5400.0),
+            calories: Some(400.0),
+            avg_heart_rate: Some(150.0),
+            max_heart_rate: Some(172.0),
+            laps: vec![Lap {
+                start_time,
+                end_time: start_time + time::Duration::minutes(30),
+                elapsed_time: 1800.0,
+                timer_time: None,
+                distance: Some(5400.0),
+                calories: None,
+                avg_heart_rate: None,
+                max_heart_rate: None,
+            }],
+            // The record sampled twice in the same second is only stored once
+            records: vec![record(0, 120.0), record(1, 121.0), record(1, 122.0)],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_workout() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let user_id = user::get_or_create_user(&mut tx, "test-user")
+            .await
+            .unwrap();
+        let source_id = sqlx::query!("SELECT id FROM source WHERE name = ''")
+            .fetch_one(&mut tx)
+            .await
+            .unwrap()
+            .id;
+
+        let workout = test_workout();
+        assert!(insert_workout(&mut tx, user_id, None, source_id, &workout)
+            .await
+            .unwrap());
+        assert!(!insert_workout(&mut tx, user_id, None, source_id, &workout)
+            .await
+            .unwrap());
+
+        let record = sqlx::query!(
+            r#"
+            SELECT
+              (SELECT COUNT(*) FROM workout_lap l WHERE l.workout_id = w.id) AS "nb_laps!",
+              (SELECT COUNT(*) FROM workout_record r WHERE r.workout_id = w.id) AS "nb_records!"
+            FROM workout w
+            WHERE w.user_id = $1 AND w.start_time = $2"#,
+            user_id,
+            workout.start_time,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+        assert_eq!((1, 2), (record.nb_laps, record.nb_records));
+
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_attach_route() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let user_id = user::get_or_create_user(&mut tx, "test-user")
+            .await
+            .unwrap();
+        let source_id = sqlx::query!("SELECT id FROM source WHERE name = ''")
+            .fetch_one(&mut tx)
+            .await
+            .unwrap()
+            .id;
+
+        // A workout recorded without a position, like on a watch without GPS
+        let mut workout = test_workout();
+        for record in &mut workout.records {
+            record.latitude = None;
+            record.longitude = None;
+        }
+        insert_workout(&mut tx, user_id, None, source_id, &workout)
+            .await
+            .unwrap();
+
+        // The route fills the position of the first record and adds a record
+        let start_time = workout.start_time + time::Duration::minutes(5);
+        let point = |date, latitude| Record {
+            date,
+            heart_rate: None,
+            latitude: Some(latitude),
+            longitude: Some(2.35),
+            altitude: None,
+            distance: None,
+            speed: None,
+            cadence: None,
+            power: None,
+        };
+        let route = Workout {
+            start_time,
+            end_time: start_time + time::Duration::minutes(30),
+            laps: Vec::new(),
+            records: vec![
+                point(workout.start_time, 48.85),
+                point(workout.start_time + time::Duration::seconds(2), 48.86),
+            ],
+            ..test_workout()
+        };
+        assert_eq!(
+            Some(2),
+            attach_route(&mut tx, user_id, &route).await.unwrap()
+        );
+        assert_eq!(
+            Some(0),
+            attach_route(&mut tx, user_id, &route).await.unwrap()
+        );
+
+        let records = sqlx::query!(
+            r#"
+            SELECT r.latitude, r.heart_rate
+            FROM workout_record r
+            JOIN workout w ON w.id = r.workout_id
+            WHERE w.user_id = $1 AND w.start_time = $2
+            ORDER BY r.date"#,
+            user_id,
+            workout.start_time,
+        )
+        .fetch_all(&mut tx)
+        .await
+        .unwrap();
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|record| (record.latitude, record.heart_rate))
+            .collect();
+        assert_eq!(
+            vec![
+                (Some(48.85), Some(120.0)),
+                (None, Some(121.0)),
+                (Some(48.86), None)
+            ],
+            records
+        );
+
+        // A route after the workout doesn't overlap it
+        let route = Workout {
+            start_time: workout.end_time,
+            end_time: workout.end_time + time::Duration::minutes(30),
+            ..route
+        };
+        assert_eq!(None, attach_route(&mut tx, user_id, &route).await.unwrap());
+
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_route() {
+        let db = get_db().await;
+        let mut tx = db.pool.begin().await.unwrap();
+
+        let user_id = user::get_or_create_user(&mut tx, "test-user")
+            .await
+            .unwrap();
+        let source_id = sqlx::query!("SELECT id FROM source WHERE name = ''")
+            .fetch_one(&mut tx)
+            .await
+            .unwrap()
+            .id;
+
+        // The records without a position aren't part of the route
+        let mut workout = test_workout();
+        workout.records[0].latitude = None;
+        workout.records.push(Record {
+            date: workout.start_time + time::Duration::seconds(2),
+            altitude: Some(35.0),
+            ..workout.records[1].clone()
+        });
+        insert_workout(&mut tx, user_id, None, source_id, &workout)
+            .await
+            .unwrap();
+
+        let workout_id = sqlx::query!(
+            "SELECT id FROM workout WHERE user_id = $1 AND start_time = $2",
+            user_id,
+            workout.start_time,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .unwrap()
+        .id;
+
+        let route = get_route(&mut tx, workout_id).await.unwrap().unwrap();
+        assert_eq!(user_id, route.user_id);
+        assert_eq!(2, route.points.len());
+
+        let geojson = route.to_geojson();
+        assert_eq!(
+            serde_json::json!([[2.35, 48.85], [2.35, 48.85, 35.0]]),
+            geojson["geometry"]["coordinates"]
+        );
+        assert_eq!(
+            serde_json::json!(["2023-03-25T09:00:01Z", "2023-03-25T09:00:02Z"]),
+            geojson["properties"]["times"]
+        );
+        assert_eq!(
+            serde_json::json!([121.0, 121.0]),
+            geojson["properties"]["heart_rates"]
+        );
+
+        assert_eq!(None, get_route(&mut tx, -1).await.unwrap());
+
+        tx.rollback().await.unwrap();
+    }
+}